@@ -0,0 +1,69 @@
+-- Named permissions grouped into roles
+-- Every user has the role matching users.role and may be assigned more.
+-- Stream grants share a single stream with a user who lacks the permission
+-- through their roles.
+CREATE TABLE IF NOT EXISTS permissions (
+    name VARCHAR(100) PRIMARY KEY,
+    description TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS roles (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name VARCHAR(100) UNIQUE NOT NULL,
+    description TEXT,
+    is_system BOOLEAN NOT NULL DEFAULT FALSE, -- built-in roles cannot be deleted
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role_id UUID NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    permission VARCHAR(100) NOT NULL REFERENCES permissions(name) ON DELETE CASCADE,
+    PRIMARY KEY (role_id, permission)
+);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role_id UUID NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, role_id)
+);
+
+CREATE TABLE IF NOT EXISTS stream_grants (
+    stream_id UUID NOT NULL REFERENCES video_streams(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    permission VARCHAR(100) NOT NULL REFERENCES permissions(name) ON DELETE CASCADE,
+    granted_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (stream_id, user_id, permission)
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_roles_role_id ON user_roles(role_id);
+CREATE INDEX IF NOT EXISTS idx_stream_grants_user_id ON stream_grants(user_id);
+
+INSERT INTO permissions (name, description) VALUES
+    ('streams:read', 'View streams and their segments'),
+    ('streams:write', 'Create streams and change or delete the ones you own'),
+    ('streams:share', 'Share streams you own with other users'),
+    ('streams:manage', 'Change, delete or share any stream'),
+    ('results:read', 'View inference results and analytics events'),
+    ('alerts:read', 'View alerts'),
+    ('alerts:ack', 'Acknowledge alerts'),
+    ('models:manage', 'Manage inference models'),
+    ('users:manage', 'Manage users, their roles and sessions'),
+    ('roles:manage', 'Create and edit roles')
+ON CONFLICT (name) DO NOTHING;
+
+-- Built-in roles matching the values of users.role
+INSERT INTO roles (name, description, is_system) VALUES
+    ('admin', 'Full access', TRUE),
+    ('user', 'Manage your own streams and work with their results', TRUE),
+    ('viewer', 'Read-only access', TRUE)
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT r.id, p.name
+FROM roles r
+JOIN permissions p ON r.name = 'admin'
+    OR (r.name = 'user' AND p.name IN ('streams:read', 'streams:write', 'streams:share', 'results:read', 'alerts:read', 'alerts:ack'))
+    OR (r.name = 'viewer' AND p.name IN ('streams:read', 'results:read', 'alerts:read'))
+ON CONFLICT DO NOTHING;
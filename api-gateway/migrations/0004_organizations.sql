@@ -0,0 +1,65 @@
+-- Organizations (clubs) and their members
+-- Streams belong to one organization, and everything recorded for a stream
+-- (segments, inference results, analytics events, alerts) belongs to the
+-- stream's organization. A member's permissions inside an organization come
+-- from their membership role.
+CREATE TABLE IF NOT EXISTS organizations (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name VARCHAR(255) NOT NULL,
+    slug VARCHAR(100) UNIQUE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS organization_members (
+    organization_id UUID NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role_id UUID NOT NULL REFERENCES roles(id),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (organization_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_organization_members_user_id ON organization_members(user_id);
+
+INSERT INTO permissions (name, description) VALUES
+    ('org:manage', 'Add and remove organization members and change their roles')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO roles (name, description, is_system) VALUES
+    ('owner', 'Manage an organization and everything in it', TRUE)
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT r.id, p.name
+FROM roles r
+JOIN permissions p ON p.name IN (
+    'streams:read', 'streams:write', 'streams:share', 'streams:manage',
+    'results:read', 'alerts:read', 'alerts:ack', 'org:manage'
+)
+WHERE r.name = 'owner'
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE video_streams ADD COLUMN IF NOT EXISTS organization_id UUID REFERENCES organizations(id) ON DELETE CASCADE;
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS organization_id UUID REFERENCES organizations(id) ON DELETE CASCADE;
+
+-- Everything that existed before organizations moves into a default one,
+-- with admins as its owners
+INSERT INTO organizations (name, slug) VALUES ('Default', 'default')
+ON CONFLICT (slug) DO NOTHING;
+
+INSERT INTO organization_members (organization_id, user_id, role_id)
+SELECT o.id, u.id, r.id
+FROM users u
+JOIN organizations o ON o.slug = 'default'
+JOIN roles r ON r.name = CASE LOWER(u.role) WHEN 'admin' THEN 'owner' ELSE LOWER(u.role) END
+ON CONFLICT DO NOTHING;
+
+UPDATE video_streams SET organization_id = (SELECT id FROM organizations WHERE slug = 'default')
+WHERE organization_id IS NULL;
+
+UPDATE api_keys SET organization_id = (SELECT id FROM organizations WHERE slug = 'default')
+WHERE organization_id IS NULL;
+
+ALTER TABLE video_streams ALTER COLUMN organization_id SET NOT NULL;
+ALTER TABLE api_keys ALTER COLUMN organization_id SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_video_streams_organization_id ON video_streams(organization_id);
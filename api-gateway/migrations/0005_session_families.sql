@@ -0,0 +1,13 @@
+-- Refresh tokens become opaque random strings stored as a keyed SHA-256 digest,
+-- so a refresh is a single indexed lookup. Each login starts a session family;
+-- refreshing rotates the token within it, and presenting an already rotated
+-- token again revokes the whole family.
+-- Existing sessions hold bcrypt hashes that can no longer be looked up, so
+-- their users have to sign in again.
+DELETE FROM user_sessions;
+
+ALTER TABLE user_sessions ADD COLUMN IF NOT EXISTS family_id UUID NOT NULL;
+ALTER TABLE user_sessions ADD COLUMN IF NOT EXISTS rotated_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE user_sessions ADD COLUMN IF NOT EXISTS revoked_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX IF NOT EXISTS idx_user_sessions_family_id ON user_sessions(family_id);
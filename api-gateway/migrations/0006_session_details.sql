@@ -0,0 +1,9 @@
+-- Where each session is used from, for listing signed-in devices, and the
+-- access token issued with each refresh token so revoking a session can
+-- denylist it
+ALTER TABLE user_sessions ADD COLUMN IF NOT EXISTS user_agent TEXT;
+ALTER TABLE user_sessions ADD COLUMN IF NOT EXISTS ip_address VARCHAR(45);
+ALTER TABLE user_sessions ADD COLUMN IF NOT EXISTS access_jti UUID;
+ALTER TABLE user_sessions ADD COLUMN IF NOT EXISTS access_expires_at TIMESTAMP WITH TIME ZONE;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_user_sessions_access_jti ON user_sessions(access_jti);
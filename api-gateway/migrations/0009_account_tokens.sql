@@ -0,0 +1,22 @@
+-- Accounts created before email verification existed are trusted as they are
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified_at TIMESTAMP WITH TIME ZONE;
+UPDATE users SET email_verified_at = created_at WHERE email_verified_at IS NULL;
+
+-- Single-use tokens sent by email for verifying the address, resetting the
+-- password and accepting an invitation. Only a keyed digest is stored.
+CREATE TABLE IF NOT EXISTS account_tokens (
+    token_hash VARCHAR(255) PRIMARY KEY,
+    purpose VARCHAR(50) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    -- Unset for invitations, whose user does not exist yet
+    user_id UUID REFERENCES users(id) ON DELETE CASCADE,
+    -- Role an invited user is created with
+    role VARCHAR(50),
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_account_tokens_purpose_email ON account_tokens(purpose, email);
+CREATE INDEX IF NOT EXISTS idx_account_tokens_expires_at ON account_tokens(expires_at);
@@ -0,0 +1,5 @@
+-- Cursor pagination lists rows of a stream newest first, ties broken by id
+CREATE INDEX IF NOT EXISTS idx_inference_results_stream_time ON inference_results(stream_id, timestamp DESC, id DESC);
+CREATE INDEX IF NOT EXISTS idx_video_segments_stream_time ON video_segments(stream_id, timestamp DESC, id DESC);
+CREATE INDEX IF NOT EXISTS idx_analytics_events_stream_time ON analytics_events(stream_id, timestamp DESC, id DESC);
+CREATE INDEX IF NOT EXISTS idx_alerts_stream_triggered ON alerts(stream_id, triggered_at DESC, id DESC);
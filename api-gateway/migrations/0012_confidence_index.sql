@@ -0,0 +1,2 @@
+-- inferenceResults can also be ordered by confidence
+CREATE INDEX IF NOT EXISTS idx_inference_results_stream_confidence ON inference_results(stream_id, confidence DESC, id DESC);
@@ -0,0 +1,4 @@
+-- TOTP secrets are kept encrypted (AES-256-GCM, nonce first). The gateway
+-- encrypts plaintext secrets of earlier enrollments on startup.
+ALTER TABLE user_totp ADD COLUMN IF NOT EXISTS secret_ciphertext BYTEA;
+ALTER TABLE user_totp ALTER COLUMN secret DROP NOT NULL;
@@ -0,0 +1,14 @@
+-- Alerts are raised by other services writing to this table directly. New
+-- alerts and status changes are announced on the `alert_changed` channel so
+-- every gateway replica can publish them to its live subscribers.
+CREATE OR REPLACE FUNCTION notify_alert_changed() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('alert_changed', NEW.id::TEXT);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS alert_changed ON alerts;
+CREATE TRIGGER alert_changed
+    AFTER INSERT OR UPDATE OF status ON alerts
+    FOR EACH ROW EXECUTE FUNCTION notify_alert_changed();
@@ -0,0 +1,4 @@
+-- Invited users join the inviter's organization with the membership role
+-- chosen when inviting
+ALTER TABLE account_tokens ADD COLUMN IF NOT EXISTS organization_id UUID REFERENCES organizations(id) ON DELETE CASCADE;
+ALTER TABLE account_tokens ADD COLUMN IF NOT EXISTS organization_role_id UUID REFERENCES roles(id) ON DELETE SET NULL;
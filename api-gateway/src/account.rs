@@ -0,0 +1,426 @@
+use axum::{extract::State, response::Json};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::{FromRow, PgExecutor};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    auth,
+    error::{AppError, Result},
+    models::{Invitation, User, UserRole},
+    services::mailer::Email,
+    AppState,
+};
+
+/// What an account token lets its holder do. The purpose is part of the
+/// token's digest, so a token issued for one purpose is useless for another.
+#[derive(Debug, Clone, Copy)]
+enum TokenPurpose {
+    EmailVerification,
+    PasswordReset,
+    Invitation,
+}
+
+impl TokenPurpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            TokenPurpose::EmailVerification => "email_verification",
+            TokenPurpose::PasswordReset => "password_reset",
+            TokenPurpose::Invitation => "invitation",
+        }
+    }
+
+    fn lifetime(self) -> Duration {
+        match self {
+            TokenPurpose::EmailVerification => Duration::hours(48),
+            TokenPurpose::PasswordReset => Duration::hours(1),
+            TokenPurpose::Invitation => Duration::days(7),
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct AccountToken {
+    user_id: Option<Uuid>,
+    email: String,
+    role: Option<UserRole>,
+    organization_id: Option<Uuid>,
+    organization_role_id: Option<Uuid>,
+}
+
+/// The organization an invited user joins, and their membership role in it
+#[derive(Debug, Clone, Copy)]
+pub struct InvitedMembership {
+    pub organization_id: Uuid,
+    pub role_id: Uuid,
+}
+
+/// Tokens are stored as an HMAC keyed with the JWT secret, i.e. signed by
+/// the gateway: a leaked table cannot be turned back into usable links.
+fn hash_token(state: &AppState, purpose: TokenPurpose, token: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(state.config.jwt_secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(purpose.as_str().as_bytes());
+    mac.update(b":");
+    mac.update(token.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Issues a single-use token for `email`, superseding any unused token it
+/// was sent earlier for the same purpose.
+async fn issue_token(
+    state: &AppState,
+    purpose: TokenPurpose,
+    email: &str,
+    user_id: Option<Uuid>,
+    role: Option<UserRole>,
+    membership: Option<InvitedMembership>,
+    created_by: Option<Uuid>,
+) -> Result<(String, DateTime<Utc>)> {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+    let expires_at = Utc::now() + purpose.lifetime();
+
+    let mut tx = state.db.pool().begin().await?;
+
+    sqlx::query("DELETE FROM account_tokens WHERE expires_at <= NOW()")
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "UPDATE account_tokens SET used_at = NOW() WHERE purpose = $1 AND email = $2 AND used_at IS NULL"
+    )
+    .bind(purpose.as_str())
+    .bind(email)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO account_tokens
+            (token_hash, purpose, email, user_id, role, organization_id, organization_role_id, created_by, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#,
+    )
+    .bind(hash_token(state, purpose, &token))
+    .bind(purpose.as_str())
+    .bind(email)
+    .bind(user_id)
+    .bind(role)
+    .bind(membership.map(|membership| membership.organization_id))
+    .bind(membership.map(|membership| membership.role_id))
+    .bind(created_by)
+    .bind(expires_at)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok((token, expires_at))
+}
+
+/// Marks a token used and returns what it was issued for. Run it in the
+/// transaction that acts on the token, so a failure leaves it usable.
+async fn consume_token(
+    executor: impl PgExecutor<'_>,
+    state: &AppState,
+    purpose: TokenPurpose,
+    token: &str,
+) -> Result<AccountToken> {
+    sqlx::query_as::<_, AccountToken>(
+        r#"
+        UPDATE account_tokens SET used_at = NOW()
+        WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING user_id, email, role, organization_id, organization_role_id
+        "#,
+    )
+    .bind(hash_token(state, purpose, token))
+    .bind(purpose.as_str())
+    .fetch_optional(executor)
+    .await?
+    .ok_or_else(|| AppError::BadRequest("Invalid or expired token".to_string()))
+}
+
+fn link(state: &AppState, path: &str, token: &str) -> String {
+    format!("{}/{}?token={}", state.config.mail.app_url.trim_end_matches('/'), path, token)
+}
+
+pub async fn send_verification_email(state: &AppState, user: &User) -> Result<()> {
+    let (token, _) = issue_token(state, TokenPurpose::EmailVerification, &user.email, Some(user.id), None, None, None).await?;
+
+    state.mailer.send(&Email {
+        to: user.email.clone(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Confirm that this is your email address by opening the link below within 48 hours:\n\n{}\n\n\
+             If you did not create an account, you can ignore this email.",
+            link(state, "verify-email", &token),
+        ),
+    }).await
+}
+
+async fn send_password_reset_email(state: &AppState, email: &str) -> Result<()> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+        .bind(email)
+        .fetch_optional(state.db.pool())
+        .await?;
+
+    let Some(user) = user else {
+        return Ok(());
+    };
+
+    let (token, _) = issue_token(state, TokenPurpose::PasswordReset, &user.email, Some(user.id), None, None, None).await?;
+
+    state.mailer.send(&Email {
+        to: user.email,
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Choose a new password by opening the link below within an hour:\n\n{}\n\n\
+             If you did not ask to reset your password, you can ignore this email.",
+            link(state, "reset-password", &token),
+        ),
+    }).await
+}
+
+/// Invites someone to create an account with `role`, joining `membership`'s
+/// organization when given. Accepting the invitation also verifies their
+/// email address.
+pub async fn invite_user(
+    state: &AppState,
+    inviter: &User,
+    email: &str,
+    role: UserRole,
+    membership: Option<InvitedMembership>,
+) -> Result<Invitation> {
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users WHERE email = $1)")
+        .bind(email)
+        .fetch_one(state.db.pool())
+        .await?;
+
+    if exists {
+        return Err(AppError::Conflict("User already exists".to_string()));
+    }
+
+    let (token, expires_at) = issue_token(state, TokenPurpose::Invitation, email, None, Some(role), membership, Some(inviter.id)).await?;
+
+    state.mailer.send(&Email {
+        to: email.to_string(),
+        subject: "You have been invited to Video Analytics".to_string(),
+        body: format!(
+            "{} invited you to create an account. Accept the invitation within 7 days:\n\n{}",
+            inviter.email,
+            link(state, "accept-invitation", &token),
+        ),
+    }).await?;
+
+    tracing::info!("{} invited {} as {:?}", inviter.email, email, role);
+
+    Ok(Invitation {
+        email: email.to_string(),
+        role,
+        organization_id: membership.map(|membership| membership.organization_id),
+        organization_role_id: membership.map(|membership| membership.role_id),
+        expires_at,
+    })
+}
+
+// Same answer whether or not the email belongs to an account
+const EMAIL_SENT_MESSAGE: &str = "If the address belongs to an account, an email is on its way";
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct EmailInput {
+    #[validate(email)]
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenInput {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct SetPasswordInput {
+    pub token: String,
+    #[validate(length(min = 8))]
+    pub password: String,
+}
+
+pub async fn verify_email(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    Json(input): Json<TokenInput>,
+) -> Result<Json<serde_json::Value>> {
+    let mut tx = state.db.pool().begin().await?;
+
+    let token = consume_token(&mut *tx, &state, TokenPurpose::EmailVerification, &input.token).await?;
+
+    // The email must not have changed since the link was sent
+    let result = sqlx::query(
+        "UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW() WHERE id = $1 AND email = $2"
+    )
+    .bind(token.user_id)
+    .bind(&token.email)
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::BadRequest("Invalid or expired token".to_string()));
+    }
+
+    tx.commit().await?;
+
+    tracing::info!("Email verified: {}", token.email);
+
+    Ok(Json(json!({
+        "message": "Email address verified"
+    })))
+}
+
+pub async fn resend_verification_email(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    Json(input): Json<EmailInput>,
+) -> Result<Json<serde_json::Value>> {
+    input.validate().map_err(|e| AppError::Validation(e.to_string()))?;
+
+    // Sent in the background so response times do not reveal which emails exist
+    tokio::spawn(async move {
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1 AND email_verified_at IS NULL")
+            .bind(&input.email)
+            .fetch_optional(state.db.pool())
+            .await;
+
+        let result = match user {
+            Ok(Some(user)) => send_verification_email(&state, &user).await,
+            Ok(None) => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+
+        if let Err(e) = result {
+            tracing::warn!("Failed to resend verification email: {}", e);
+        }
+    });
+
+    Ok(Json(json!({
+        "message": EMAIL_SENT_MESSAGE
+    })))
+}
+
+pub async fn request_password_reset(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    Json(input): Json<EmailInput>,
+) -> Result<Json<serde_json::Value>> {
+    input.validate().map_err(|e| AppError::Validation(e.to_string()))?;
+
+    // Sent in the background so response times do not reveal which emails exist
+    tokio::spawn(async move {
+        if let Err(e) = send_password_reset_email(&state, &input.email).await {
+            tracing::warn!("Failed to send password reset email: {}", e);
+        }
+    });
+
+    Ok(Json(json!({
+        "message": EMAIL_SENT_MESSAGE
+    })))
+}
+
+/// Sets a new password and signs the user out everywhere. Receiving the
+/// link proves the mailbox, so the email counts as verified too.
+pub async fn reset_password(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    Json(input): Json<SetPasswordInput>,
+) -> Result<Json<serde_json::Value>> {
+    input.validate().map_err(|e| AppError::Validation(e.to_string()))?;
+
+    let password_hash = state.password_hasher.hash(&input.password).await?;
+
+    let mut tx = state.db.pool().begin().await?;
+
+    let token = consume_token(&mut *tx, &state, TokenPurpose::PasswordReset, &input.token).await?;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        r#"
+        UPDATE users SET password_hash = $3, email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW()
+        WHERE id = $1 AND email = $2
+        RETURNING id
+        "#,
+    )
+    .bind(token.user_id)
+    .bind(&token.email)
+    .bind(&password_hash)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::BadRequest("Invalid or expired token".to_string()))?;
+
+    tx.commit().await?;
+
+    let revoked = auth::revoke_sessions(&state, user_id, None).await?;
+    tracing::info!("Password reset for {}, {} sessions revoked", token.email, revoked);
+
+    Ok(Json(json!({
+        "message": "Password updated, please log in again"
+    })))
+}
+
+pub async fn accept_invitation(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    Json(input): Json<SetPasswordInput>,
+) -> Result<Json<serde_json::Value>> {
+    input.validate().map_err(|e| AppError::Validation(e.to_string()))?;
+
+    let password_hash = state.password_hasher.hash(&input.password).await?;
+
+    let mut tx = state.db.pool().begin().await?;
+
+    let token = consume_token(&mut *tx, &state, TokenPurpose::Invitation, &input.token).await?;
+
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        INSERT INTO users (id, email, password_hash, role, email_verified_at, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, NOW(), NOW(), NOW())
+        ON CONFLICT (email) DO NOTHING
+        RETURNING *
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(&token.email)
+    .bind(&password_hash)
+    .bind(token.role.unwrap_or(UserRole::User))
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::Conflict("User already exists".to_string()))?;
+
+    // A membership role deleted since the invitation falls back to read-only access
+    if let Some(organization_id) = token.organization_id {
+        sqlx::query(
+            r#"
+            INSERT INTO organization_members (organization_id, user_id, role_id)
+            SELECT $1, $2, COALESCE($3, (SELECT id FROM roles WHERE name = 'viewer'))
+            ON CONFLICT (organization_id, user_id) DO NOTHING
+            "#,
+        )
+        .bind(organization_id)
+        .bind(user.id)
+        .bind(token.organization_role_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    tracing::info!("Invitation accepted: {}", user.email);
+
+    Ok(Json(json!({
+        "message": "Account created",
+        "user": {
+            "id": user.id,
+            "email": user.email,
+            "role": user.role,
+            "created_at": user.created_at
+        }
+    })))
+}
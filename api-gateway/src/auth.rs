@@ -1,18 +1,25 @@
 use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
+    extract::{ConnectInfo, State},
+    http::{header::USER_AGENT, HeaderMap, StatusCode},
     response::Json,
 };
-use bcrypt::{hash, verify, DEFAULT_COST};
-use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
 use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::PgExecutor;
+use std::{collections::HashSet, net::SocketAddr};
 use uuid::Uuid;
 use validator::Validate;
 
 use crate::{
+    account,
     error::{AppError, Result},
-    models::{AuthResponse, Claims, CreateUserInput, LoginInput, User, UserRole, UserSession},
+    login_throttle,
+    middleware::rate_limit::client_ip,
+    models::{ApiKey, AuthResponse, Claims, CreateUserInput, LoginInput, LoginResponse, User, UserRole, UserSession},
+    two_factor,
     AppState,
 };
 
@@ -36,12 +43,12 @@ pub async fn register(
     }
 
     // Hash password
-    let password_hash = hash(&input.password, state.config.auth.bcrypt_cost)
-        .map_err(|e| AppError::Internal(format!("Failed to hash password: {}", e)))?;
+    let password_hash = state.password_hasher.hash(&input.password).await?;
 
-    // Create user
+    // Create user. Self-registration always gets the default role; admins
+    // grant more with `setUserRole` or by invitation.
     let user_id = Uuid::new_v4();
-    let role = input.role.unwrap_or(UserRole::User);
+    let role = UserRole::User;
 
     let user = sqlx::query_as::<_, User>(
         r#"
@@ -59,6 +66,11 @@ pub async fn register(
 
     tracing::info!("User registered: {}", user.email);
 
+    // The account exists either way; a lost email can be sent again
+    if let Err(e) = account::send_verification_email(&state, &user).await {
+        tracing::warn!("Failed to send verification email to {}: {}", user.email, e);
+    }
+
     Ok(Json(json!({
         "message": "User registered successfully",
         "user": {
@@ -72,129 +84,267 @@ pub async fn register(
 
 pub async fn login(
     State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(input): Json<LoginInput>,
-) -> Result<Json<AuthResponse>> {
+) -> Result<Json<LoginResponse>> {
     // Validate input
     input.validate().map_err(|e| AppError::Validation(e.to_string()))?;
 
+    let client = SessionClient::new(&state, peer, &headers);
+    login_throttle::check(&state, &input.email, &client.ip_address).await?;
+
     // Find user
     let user = sqlx::query_as::<_, User>(
         "SELECT * FROM users WHERE email = $1"
     )
     .bind(&input.email)
     .fetch_optional(state.db.pool())
-    .await?
-    .ok_or_else(|| AppError::Authentication("Invalid credentials".to_string()))?;
+    .await?;
 
-    // Verify password
-    let is_valid = verify(&input.password, &user.password_hash)
-        .map_err(|e| AppError::Internal(format!("Password verification failed: {}", e)))?;
+    // Verify password; unknown emails take as long as wrong passwords
+    let verification = match &user {
+        Some(user) => state.password_hasher.verify(&input.password, &user.password_hash).await?,
+        None => state.password_hasher.verify_dummy(&input.password).await?,
+    };
 
-    if !is_valid {
-        return Err(AppError::Authentication("Invalid credentials".to_string()));
-    }
+    let user = match user {
+        Some(user) if verification.valid => user,
+        _ => {
+            login_throttle::record_failure(&state, &input.email, &client.ip_address).await?;
+            return Err(AppError::Authentication("Invalid credentials".to_string()));
+        }
+    };
 
-    // Generate tokens
-    let (access_token, expires_at) = generate_access_token(&user, &state.config.jwt_secret)?;
-    let refresh_token = generate_refresh_token(&user, &state)?;
+    login_throttle::record_success(&state, &input.email).await?;
 
-    // Store refresh token in database
-    let session_id = Uuid::new_v4();
-    let refresh_token_hash = hash(&refresh_token, DEFAULT_COST)
-        .map_err(|e| AppError::Internal(format!("Failed to hash refresh token: {}", e)))?;
+    if verification.needs_rehash {
+        upgrade_password_hash(&state, &user, &input.password).await;
+    }
 
-    sqlx::query(
-        r#"
-        INSERT INTO user_sessions (id, user_id, token_hash, expires_at, created_at)
-        VALUES ($1, $2, $3, $4, NOW())
-        "#,
-    )
-    .bind(session_id)
-    .bind(user.id)
-    .bind(&refresh_token_hash)
-    .bind(Utc::now() + Duration::days(state.config.auth.refresh_token_expiry_days))
-    .execute(state.db.pool())
-    .await?;
+    if state.config.auth.require_email_verification && user.email_verified_at.is_none() {
+        return Err(AppError::Authentication("Email address has not been verified".to_string()));
+    }
+
+    if let Some(challenge) = two_factor::challenge(&state, &user).await? {
+        return Ok(Json(LoginResponse::TwoFactorRequired { two_factor: challenge }));
+    }
 
     tracing::info!("User logged in: {}", user.email);
 
-    Ok(Json(AuthResponse {
-        access_token,
-        refresh_token,
-        user,
-        expires_at,
-    }))
+    // Every login starts a new session family
+    let response = issue_tokens(state.db.pool(), &state, user, Uuid::new_v4(), &client).await?;
+
+    Ok(Json(LoginResponse::Authenticated(response)))
+}
+
+/// Rehashes a password with the current settings after a successful login.
+/// Failing to do so is not worth failing the login over.
+async fn upgrade_password_hash(state: &AppState, user: &User, password: &str) {
+    let result = async {
+        let password_hash = state.password_hasher.hash(password).await?;
+
+        // Skipped if the password changed in the meantime
+        sqlx::query("UPDATE users SET password_hash = $3, updated_at = NOW() WHERE id = $1 AND password_hash = $2")
+            .bind(user.id)
+            .bind(&user.password_hash)
+            .bind(&password_hash)
+            .execute(state.db.pool())
+            .await?;
+
+        Ok::<_, AppError>(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => tracing::info!("Upgraded password hash of {}", user.email),
+        Err(e) => tracing::warn!("Failed to upgrade password hash of {}: {}", user.email, e),
+    }
 }
 
 pub async fn refresh_token(
     State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
 ) -> Result<Json<AuthResponse>> {
     // Extract refresh token from Authorization header
-    let auth_header = headers
-        .get("authorization")
-        .ok_or_else(|| AppError::Authentication("Missing authorization header".to_string()))?
-        .to_str()
-        .map_err(|_| AppError::Authentication("Invalid authorization header".to_string()))?;
+    let refresh_token = bearer_token(&headers)?;
 
-    let refresh_token = auth_header
-        .strip_prefix("Bearer ")
-        .ok_or_else(|| AppError::Authentication("Invalid authorization format".to_string()))?;
+    let mut tx = state.db.pool().begin().await?;
 
-    // Find valid session
-    let sessions = sqlx::query_as::<_, UserSession>(
-        "SELECT * FROM user_sessions WHERE expires_at > NOW()"
+    // Locking the row makes concurrent refreshes with the same token take turns
+    let session = sqlx::query_as::<_, UserSession>(
+        "SELECT * FROM user_sessions WHERE token_hash = $1 FOR UPDATE"
     )
-    .fetch_all(state.db.pool())
-    .await?;
+    .bind(hash_refresh_token(refresh_token, &state))
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::Authentication("Invalid refresh token".to_string()))?;
 
-    let mut valid_session = None;
-    for session in sessions {
-        if verify(refresh_token, &session.token_hash).unwrap_or(false) {
-            valid_session = Some(session);
-            break;
-        }
+    // A rotated token should never be seen again. Either the client or an
+    // attacker holds a stolen copy, so neither gets to keep the session.
+    if session.rotated_at.is_some() {
+        // Release the row lock; the revocation takes it again
+        tx.rollback().await?;
+        revoke_sessions(&state, session.user_id, Some(session.family_id)).await?;
+
+        tracing::warn!("Refresh token reused, revoked session family {} of user {}", session.family_id, session.user_id);
+        return Err(AppError::Authentication("Invalid refresh token".to_string()));
     }
 
-    let session = valid_session
-        .ok_or_else(|| AppError::Authentication("Invalid refresh token".to_string()))?;
+    if session.revoked_at.is_some() || session.expires_at <= Utc::now() {
+        return Err(AppError::Authentication("Invalid refresh token".to_string()));
+    }
 
     // Get user
     let user = sqlx::query_as::<_, User>(
         "SELECT * FROM users WHERE id = $1"
     )
     .bind(session.user_id)
-    .fetch_one(state.db.pool())
+    .fetch_one(&mut *tx)
     .await?;
 
-    // Generate new access token
-    let (access_token, expires_at) = generate_access_token(&user, &state.config.jwt_secret)?;
-    let new_refresh_token = generate_refresh_token(&user, &state)?;
+    // Rotate: the presented token is spent and a new one joins the family
+    sqlx::query("UPDATE user_sessions SET rotated_at = NOW() WHERE id = $1")
+        .bind(session.id)
+        .execute(&mut *tx)
+        .await?;
+
+    let client = SessionClient::new(&state, peer, &headers);
+    let response = issue_tokens(&mut *tx, &state, user, session.family_id, &client).await?;
+    tx.commit().await?;
+
+    Ok(Json(response))
+}
+
+/// Ends the session the access token belongs to. The token itself stops
+/// working immediately, as does the session's refresh token.
+pub async fn logout(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>> {
+    let (user, claims) = get_user_from_token(bearer_token(&headers)?, &state).await?;
+
+    revoke_sessions(&state, user.id, Some(claims.sid)).await?;
+
+    tracing::info!("User logged out: {}", user.email);
 
-    // Update session with new refresh token
-    let new_refresh_token_hash = hash(&new_refresh_token, DEFAULT_COST)
-        .map_err(|e| AppError::Internal(format!("Failed to hash refresh token: {}", e)))?;
+    Ok(Json(json!({
+        "message": "Logged out successfully"
+    })))
+}
+
+/// Public keys for verifying access tokens, for services that accept them.
+/// Empty while tokens are signed with the shared HS256 secret.
+pub async fn jwks(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+) -> Json<serde_json::Value> {
+    Json(state.jwt_keys.jwks())
+}
+
+fn bearer_token(headers: &HeaderMap) -> Result<&str> {
+    let auth_header = headers
+        .get("authorization")
+        .ok_or_else(|| AppError::Authentication("Missing authorization header".to_string()))?
+        .to_str()
+        .map_err(|_| AppError::Authentication("Invalid authorization header".to_string()))?;
+
+    auth_header
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| AppError::Authentication("Invalid authorization format".to_string()))
+}
+
+/// The device a session is used from, as shown by `mySessions`
+pub(crate) struct SessionClient {
+    user_agent: Option<String>,
+    pub(crate) ip_address: String,
+}
+
+impl SessionClient {
+    pub(crate) fn new(state: &AppState, peer: SocketAddr, headers: &HeaderMap) -> Self {
+        Self {
+            user_agent: headers
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.chars().take(512).collect()),
+            ip_address: client_ip(peer.ip(), headers, &state.config.rate_limit.trusted_proxies),
+        }
+    }
+}
+
+/// Issues an access and refresh token pair in session family `family_id` and
+/// records them, so the access token can be revoked along with its session.
+pub(crate) async fn issue_tokens(
+    executor: impl PgExecutor<'_>,
+    state: &AppState,
+    user: User,
+    family_id: Uuid,
+    client: &SessionClient,
+) -> Result<AuthResponse> {
+    let access_jti = Uuid::new_v4();
+    let (access_token, expires_at) = generate_access_token(&user, state, access_jti, family_id)?;
+    let refresh_token = generate_refresh_token();
 
     sqlx::query(
-        "UPDATE user_sessions SET token_hash = $1, expires_at = $2 WHERE id = $3"
+        r#"
+        INSERT INTO user_sessions (
+            id, user_id, family_id, token_hash, expires_at, user_agent, ip_address,
+            access_jti, access_expires_at, created_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, NOW())
+        "#,
     )
-    .bind(&new_refresh_token_hash)
+    .bind(Uuid::new_v4())
+    .bind(user.id)
+    .bind(family_id)
+    .bind(hash_refresh_token(&refresh_token, state))
     .bind(Utc::now() + Duration::days(state.config.auth.refresh_token_expiry_days))
-    .bind(session.id)
-    .execute(state.db.pool())
+    .bind(&client.user_agent)
+    .bind(&client.ip_address)
+    .bind(access_jti)
+    .bind(expires_at)
+    .execute(executor)
     .await?;
 
-    Ok(Json(AuthResponse {
+    Ok(AuthResponse {
         access_token,
-        refresh_token: new_refresh_token,
+        refresh_token,
         user,
         expires_at,
-    }))
+        recovery_codes: None,
+    })
+}
+
+/// Revokes one session family of a user, or all of them, and denylists the
+/// access tokens issued for them that have not expired yet. Returns how many
+/// sessions were ended.
+pub async fn revoke_sessions(state: &AppState, user_id: Uuid, family_id: Option<Uuid>) -> Result<usize> {
+    let revoked: Vec<(Uuid, Option<Uuid>, Option<DateTime<Utc>>)> = sqlx::query_as(
+        r#"
+        UPDATE user_sessions SET revoked_at = NOW()
+        WHERE user_id = $1 AND ($2::UUID IS NULL OR family_id = $2) AND revoked_at IS NULL
+        RETURNING family_id, access_jti, access_expires_at
+        "#,
+    )
+    .bind(user_id)
+    .bind(family_id)
+    .fetch_all(state.db.pool())
+    .await?;
+
+    let mut families = HashSet::new();
+    for (family_id, access_jti, access_expires_at) in revoked {
+        families.insert(family_id);
+        if let (Some(jti), Some(expires_at)) = (access_jti, access_expires_at) {
+            state.denylist.deny(jti, expires_at).await;
+        }
+    }
+
+    Ok(families.len())
 }
 
-fn generate_access_token(user: &User, secret: &str) -> Result<(String, chrono::DateTime<Utc>)> {
+fn generate_access_token(user: &User, state: &AppState, jti: Uuid, sid: Uuid) -> Result<(String, DateTime<Utc>)> {
     let now = Utc::now();
-    let expires_at = now + Duration::hours(24); // 24 hours
+    let expires_at = now + Duration::hours(state.config.auth.jwt_expiry_hours);
 
     let claims = Claims {
         sub: user.id.to_string(),
@@ -202,55 +352,59 @@ fn generate_access_token(user: &User, secret: &str) -> Result<(String, chrono::D
         role: user.role,
         exp: expires_at.timestamp(),
         iat: now.timestamp(),
+        jti,
+        sid,
     };
 
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )?;
+    let token = state.jwt_keys.sign(&claims)?;
 
     Ok((token, expires_at))
 }
 
-fn generate_refresh_token(user: &User, state: &AppState) -> Result<String> {
-    let now = Utc::now();
-    let expires_at = now + Duration::days(state.config.auth.refresh_token_expiry_days);
+/// Refresh tokens are opaque: unlike a JWT they cannot be presented as an
+/// access token, and they only mean something to the `user_sessions` table.
+fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
 
-    let claims = Claims {
-        sub: user.id.to_string(),
-        email: user.email.clone(),
-        role: user.role,
-        exp: expires_at.timestamp(),
-        iat: now.timestamp(),
-    };
+/// Keyed digest refresh tokens are stored and looked up under. Tokens are
+/// random, so a fast hash is enough; the key keeps a leaked table from being
+/// checked against guessed tokens offline.
+fn hash_refresh_token(token: &str, state: &AppState) -> String {
+    let key = state.config.auth.refresh_token_secret.as_deref().unwrap_or(&state.config.jwt_secret);
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(token.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
 
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(state.config.jwt_secret.as_ref()),
-    )?;
+/// Whether an access token was revoked before it expired. A denylist hit in
+/// Redis answers without touching the database, but only the sessions table
+/// can tell that a token is still valid: Redis misses revocations made while
+/// it was unreachable.
+pub async fn is_revoked(state: &AppState, jti: Uuid) -> Result<bool> {
+    if state.denylist.contains(jti).await {
+        return Ok(true);
+    }
 
-    Ok(token)
+    let revoked: Option<bool> = sqlx::query_scalar(
+        "SELECT revoked_at IS NOT NULL FROM user_sessions WHERE access_jti = $1"
+    )
+    .bind(jti)
+    .fetch_optional(state.db.pool())
+    .await?;
+
+    Ok(revoked.unwrap_or(true))
 }
 
-pub fn verify_token(token: &str, secret: &str) -> Result<Claims> {
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::default(),
-    )?;
+pub async fn get_user_from_token(token: &str, state: &AppState) -> Result<(User, Claims)> {
+    let claims = state.jwt_keys.verify(token)?;
 
-    Ok(token_data.claims)
-}
+    if is_revoked(state, claims.jti).await? {
+        return Err(AppError::Authentication("Token has been revoked".to_string()));
+    }
 
-pub async fn get_user_from_token(
-    token: &str,
-    secret: &str,
-    db: &crate::database::Database,
-) -> Result<User> {
-    let claims = verify_token(token, secret)?;
-    
     let user_id = Uuid::parse_str(&claims.sub)
         .map_err(|_| AppError::Authentication("Invalid user ID in token".to_string()))?;
 
@@ -258,9 +412,118 @@ pub async fn get_user_from_token(
         "SELECT * FROM users WHERE id = $1"
     )
     .bind(user_id)
-    .fetch_optional(db.pool())
+    .fetch_optional(state.db.pool())
     .await?
     .ok_or_else(|| AppError::Authentication("User not found".to_string()))?;
 
-    Ok(user)
-} 
\ No newline at end of file
+    Ok((user, claims))
+}
+
+// Makes leaked keys easy to recognise in logs and secret scanners
+const API_KEY_PREFIX: &str = "vae_";
+
+pub fn generate_api_key() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", API_KEY_PREFIX, hex::encode(bytes))
+}
+
+/// API keys are high-entropy random strings, so a fast unsalted digest is
+/// enough to store them and lets lookups use the `key_hash` index.
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+pub async fn get_user_from_api_key(key: &str, state: &AppState) -> Result<(User, ApiKey)> {
+    let key_hash = hash_api_key(key);
+
+    // Looking the key up also records that it was used
+    let api_key = sqlx::query_as::<_, ApiKey>(
+        r#"
+        UPDATE api_keys SET last_used_at = NOW()
+        WHERE key_hash = $1 AND is_active = TRUE AND (expires_at IS NULL OR expires_at > NOW())
+        RETURNING *
+        "#,
+    )
+    .bind(&key_hash)
+    .fetch_optional(state.db.pool())
+    .await?
+    .ok_or_else(|| AppError::Authentication("Invalid API key".to_string()))?;
+
+    let user = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE id = $1"
+    )
+    .bind(api_key.user_id)
+    .fetch_optional(state.db.pool())
+    .await?
+    .ok_or_else(|| AppError::Authentication("User not found".to_string()))?;
+
+    // Later requests with this key are rate limited on the key itself
+    state.verified_api_keys.insert(key_hash);
+
+    Ok((user, api_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use reqwest::StatusCode;
+    use sqlx::PgPool;
+
+    async fn refresh(base_url: &str, refresh_token: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/auth/refresh", base_url))
+            .bearer_auth(refresh_token)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn family_jtis(state: &AppState, family_id: Uuid) -> Vec<Uuid> {
+        sqlx::query_scalar("SELECT access_jti FROM user_sessions WHERE family_id = $1")
+            .bind(family_id)
+            .fetch_all(state.db.pool())
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn reusing_a_rotated_refresh_token_revokes_its_family(pool: PgPool) {
+        let (base_url, listener) = test_support::listen().await;
+        let state = test_support::state(pool).await;
+        test_support::serve(state.clone(), listener).await;
+
+        let user = sqlx::query_as::<_, User>(
+            "INSERT INTO users (email, password_hash, role) VALUES ('refresh@example.com', '', $1) RETURNING *"
+        )
+        .bind(UserRole::User)
+        .fetch_one(state.db.pool())
+        .await
+        .unwrap();
+        let client = SessionClient::new(&state, "127.0.0.1:1".parse().unwrap(), &HeaderMap::new());
+
+        let family_id = Uuid::new_v4();
+        let first = issue_tokens(state.db.pool(), &state, user.clone(), family_id, &client).await.unwrap();
+        let other = issue_tokens(state.db.pool(), &state, user, Uuid::new_v4(), &client).await.unwrap();
+
+        let response = refresh(&base_url, &first.refresh_token).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let second: AuthResponse = response.json().await.unwrap();
+
+        assert_eq!(refresh(&base_url, &first.refresh_token).await.status(), StatusCode::UNAUTHORIZED);
+
+        // The legitimate holder of the newer token is signed out as well
+        assert_eq!(refresh(&base_url, &second.refresh_token).await.status(), StatusCode::UNAUTHORIZED);
+
+        let jtis = family_jtis(&state, family_id).await;
+        assert_eq!(jtis.len(), 2);
+        for jti in jtis {
+            assert!(is_revoked(&state, jti).await.unwrap());
+        }
+        assert!(get_user_from_token(&second.access_token, &state).await.is_err());
+
+        // Other sign-ins of the same user are untouched
+        assert!(get_user_from_token(&other.access_token, &state).await.is_ok());
+    }
+}
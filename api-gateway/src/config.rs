@@ -1,5 +1,9 @@
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::{collections::HashMap, env};
+
+use crate::models::UserRole;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -7,23 +11,273 @@ pub struct Config {
     pub database_url: String,
     pub redis_url: String,
     pub kafka_brokers: String,
+    pub kafka: KafkaConfig,
     pub jwt_secret: String,
     pub cors_origins: Vec<String>,
+    pub migrate_dry_run: bool,
     pub rate_limit: RateLimitConfig,
     pub auth: AuthConfig,
+    pub oidc: OidcConfig,
+    pub mail: MailConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KafkaConfig {
+    pub consumer_enabled: bool,
+    pub group_id: String,
+    pub inference_results_topic: String,
+    pub analytics_events_topic: String,
+    pub dead_letter_topic: String,
+    pub stream_commands_topic: String,
+    pub outbox_relay_enabled: bool,
+    pub batch_size: usize,
+    /// Replay messages from a JSON-lines file instead of a broker (local dev and tests)
+    pub source_file: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RateLimitConfig {
     pub requests_per_minute: u32,
     pub burst_size: u32,
+    /// Identity the default limit is keyed on
+    pub key: RateLimitKey,
+    /// Proxies whose X-Forwarded-For / X-Real-IP headers are trusted
+    pub trusted_proxies: Vec<IpNet>,
+    /// Routes that are never rate limited
+    pub exempt_routes: Vec<String>,
+    /// Additional, usually tighter, limits for specific routes or GraphQL fields
+    pub policies: Vec<RateLimitPolicy>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// Authenticated user id, falling back to API key and then client IP
+    User,
+    /// API key, falling back to user id and then client IP
+    ApiKey,
+    /// The caller's role, so every user with that role shares one bucket
+    Role,
+    /// Client IP
+    Ip,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitPolicy {
+    pub name: String,
+    /// Request path, or a prefix ending in `*`
+    #[serde(default)]
+    pub route: Option<String>,
+    /// GraphQL root fields, e.g. `inferenceResults`
+    #[serde(default)]
+    pub graphql_fields: Vec<String>,
+    pub key: RateLimitKey,
+    pub requests_per_minute: u32,
+    pub burst_size: u32,
+}
+
+fn default_rate_limit_policies() -> Vec<RateLimitPolicy> {
+    vec![
+        RateLimitPolicy {
+            name: "auth-login".to_string(),
+            route: Some("/auth/login".to_string()),
+            graphql_fields: Vec::new(),
+            key: RateLimitKey::Ip,
+            requests_per_minute: 10,
+            burst_size: 5,
+        },
+        // Each attempt guesses a six-digit code for a challenge that passed the password check
+        RateLimitPolicy {
+            name: "auth-2fa-verify".to_string(),
+            route: Some("/auth/2fa/verify".to_string()),
+            graphql_fields: Vec::new(),
+            key: RateLimitKey::Ip,
+            requests_per_minute: 6,
+            burst_size: 3,
+        },
+        RateLimitPolicy {
+            name: "auth-register".to_string(),
+            route: Some("/auth/register".to_string()),
+            graphql_fields: Vec::new(),
+            key: RateLimitKey::Ip,
+            requests_per_minute: 5,
+            burst_size: 2,
+        },
+        // Each request sends an email
+        RateLimitPolicy {
+            name: "auth-password-reset".to_string(),
+            route: Some("/auth/password-reset".to_string()),
+            graphql_fields: Vec::new(),
+            key: RateLimitKey::Ip,
+            requests_per_minute: 5,
+            burst_size: 2,
+        },
+        RateLimitPolicy {
+            name: "auth-verify-email-resend".to_string(),
+            route: Some("/auth/verify-email/resend".to_string()),
+            graphql_fields: Vec::new(),
+            key: RateLimitKey::Ip,
+            requests_per_minute: 5,
+            burst_size: 2,
+        },
+        RateLimitPolicy {
+            name: "analytics-queries".to_string(),
+            route: None,
+            graphql_fields: vec![
+                "inferenceResults".to_string(),
+                "videoSegments".to_string(),
+                "alerts".to_string(),
+                "analyticsEvents".to_string(),
+                "inferenceStats".to_string(),
+            ],
+            key: RateLimitKey::User,
+            requests_per_minute: 30,
+            burst_size: 10,
+        },
+    ]
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthConfig {
     pub jwt_expiry_hours: i64,
+    pub jwt_algorithm: JwtAlgorithm,
+    /// `kid` of the signing key; derived from the public key when unset
+    pub jwt_key_id: Option<String>,
+    /// PEM files of the signing key pair, required for RS256 and EdDSA
+    pub jwt_private_key_path: Option<String>,
+    pub jwt_public_key_path: Option<String>,
+    /// Keys that signed tokens before the current one
+    pub jwt_previous_keys: Vec<PreviousJwtKey>,
+    /// How long a previous key keeps verifying tokens after it was retired;
+    /// defaults to the token lifetime so every token it signed can expire
+    pub jwt_rotation_grace_hours: Option<i64>,
     pub refresh_token_expiry_days: i64,
+    /// Key for the digests refresh tokens are stored under; defaults to the JWT secret
+    pub refresh_token_secret: Option<String>,
+    /// Algorithm new password hashes use; others are upgraded on login
+    pub password_algorithm: PasswordAlgorithm,
     pub bcrypt_cost: u32,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    /// Failed logins per account before it is locked out
+    pub lockout_threshold: u32,
+    /// Failed logins per client IP before it is locked out
+    pub lockout_ip_threshold: u32,
+    /// The first lockout; it doubles with every further failure
+    pub lockout_base_seconds: i64,
+    pub lockout_max_seconds: i64,
+    /// Issuer shown next to the account in authenticator apps
+    pub totp_issuer: String,
+    /// Key TOTP secrets are encrypted with; defaults to the JWT secret
+    pub totp_encryption_key: Option<String>,
+    /// Refuse password logins until the email address has been verified
+    pub require_email_verification: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    pub from: String,
+    pub smtp_url: Option<String>,
+    /// Where the file transport writes emails to
+    pub directory: String,
+    /// Base URL of the web app; links in emails point there
+    pub app_url: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MailTransport {
+    Smtp,
+    /// Writes emails to files, for local development and tests
+    File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordAlgorithm {
+    Argon2id,
+    Bcrypt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JwtAlgorithm {
+    /// Shared secret from `JWT_SECRET`; only the gateway can verify tokens
+    HS256,
+    RS256,
+    EdDSA,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviousJwtKey {
+    pub kid: String,
+    pub algorithm: JwtAlgorithm,
+    pub public_key_path: String,
+    pub retired_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcConfig {
+    pub providers: Vec<OidcProviderConfig>,
+    /// Serves a mock identity provider under `/mock-idp`. Never enable in production.
+    pub mock_idp: Option<MockIdpConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcProviderConfig {
+    /// Used in the login and callback routes, e.g. `/auth/oidc/google/login`
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// ID token claim listing the user's groups or app roles
+    #[serde(default = "default_oidc_groups_claim")]
+    pub groups_claim: String,
+    /// Group to role; the most privileged match wins
+    #[serde(default)]
+    pub role_mapping: HashMap<String, UserRole>,
+    #[serde(default = "default_oidc_role")]
+    pub default_role: UserRole,
+    /// Whether emails from this provider are verified even without an
+    /// `email_verified` claim (e.g. Azure AD). Verified emails link to
+    /// existing accounts.
+    #[serde(default)]
+    pub trust_email: bool,
+    /// Slug of the organization new users join
+    #[serde(default)]
+    pub organization: Option<String>,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "email".to_string(), "profile".to_string()]
+}
+
+fn default_oidc_groups_claim() -> String {
+    "groups".to_string()
+}
+
+fn default_oidc_role() -> UserRole {
+    UserRole::Viewer
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MockIdpConfig {
+    /// Must match the `issuer` of the provider pointing at the mock
+    pub issuer: String,
+    /// Users the mock signs in, picked by `login_hint`; the first one otherwise
+    pub users: Vec<MockIdpUser>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MockIdpUser {
+    pub email: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
 }
 
 impl Config {
@@ -48,6 +302,30 @@ impl Config {
             kafka_brokers: env::var("KAFKA_BROKERS")
                 .unwrap_or_else(|_| "localhost:9092".to_string()),
             
+            kafka: KafkaConfig {
+                consumer_enabled: env::var("KAFKA_CONSUMER_ENABLED")
+                    .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                    .unwrap_or(true),
+                group_id: env::var("KAFKA_GROUP_ID")
+                    .unwrap_or_else(|_| "api-gateway".to_string()),
+                inference_results_topic: env::var("KAFKA_INFERENCE_RESULTS_TOPIC")
+                    .unwrap_or_else(|_| "inference-results".to_string()),
+                analytics_events_topic: env::var("KAFKA_ANALYTICS_EVENTS_TOPIC")
+                    .unwrap_or_else(|_| "analytics-events".to_string()),
+                dead_letter_topic: env::var("KAFKA_DEAD_LETTER_TOPIC")
+                    .unwrap_or_else(|_| "api-gateway-dead-letter".to_string()),
+                stream_commands_topic: env::var("KAFKA_STREAM_COMMANDS_TOPIC")
+                    .unwrap_or_else(|_| "stream-commands".to_string()),
+                outbox_relay_enabled: env::var("KAFKA_OUTBOX_RELAY_ENABLED")
+                    .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                    .unwrap_or(true),
+                batch_size: env::var("KAFKA_BATCH_SIZE")
+                    .unwrap_or_else(|_| "500".to_string())
+                    .parse()
+                    .unwrap_or(500),
+                source_file: env::var("KAFKA_SOURCE_FILE").ok(),
+            },
+            
             jwt_secret: env::var("JWT_SECRET")
                 .unwrap_or_else(|_| "your-super-secure-jwt-secret-key-change-in-production".to_string()),
             
@@ -57,6 +335,10 @@ impl Config {
                 .map(|s| s.trim().to_string())
                 .collect(),
             
+            migrate_dry_run: env::var("MIGRATE_DRY_RUN")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            
             rate_limit: RateLimitConfig {
                 requests_per_minute: env::var("RATE_LIMIT_RPM")
                     .unwrap_or_else(|_| "60".to_string())
@@ -66,6 +348,29 @@ impl Config {
                     .unwrap_or_else(|_| "10".to_string())
                     .parse()
                     .unwrap_or(10),
+                key: match env::var("RATE_LIMIT_KEY") {
+                    Ok(key) => serde_json::from_value(serde_json::Value::String(key))
+                        .map_err(|e| format!("Invalid RATE_LIMIT_KEY: {}", e))?,
+                    Err(_) => RateLimitKey::User,
+                },
+                trusted_proxies: env::var("RATE_LIMIT_TRUSTED_PROXIES")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse::<IpNet>().map_err(|e| format!("Invalid trusted proxy '{}': {}", s, e)))
+                    .collect::<Result<_, _>>()?,
+                exempt_routes: env::var("RATE_LIMIT_EXEMPT_ROUTES")
+                    .unwrap_or_else(|_| "/health".to_string())
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                policies: match env::var("RATE_LIMIT_POLICIES") {
+                    Ok(policies) => serde_json::from_str(&policies)
+                        .map_err(|e| format!("Invalid RATE_LIMIT_POLICIES: {}", e))?,
+                    Err(_) => default_rate_limit_policies(),
+                },
             },
             
             auth: AuthConfig {
@@ -73,14 +378,97 @@ impl Config {
                     .unwrap_or_else(|_| "24".to_string())
                     .parse()
                     .unwrap_or(24),
+                jwt_algorithm: match env::var("JWT_ALGORITHM") {
+                    Ok(algorithm) => serde_json::from_value(serde_json::Value::String(algorithm))
+                        .map_err(|e| format!("Invalid JWT_ALGORITHM: {}", e))?,
+                    Err(_) => JwtAlgorithm::HS256,
+                },
+                jwt_key_id: env::var("JWT_KEY_ID").ok(),
+                jwt_private_key_path: env::var("JWT_PRIVATE_KEY_PATH").ok(),
+                jwt_public_key_path: env::var("JWT_PUBLIC_KEY_PATH").ok(),
+                jwt_previous_keys: match env::var("JWT_PREVIOUS_KEYS") {
+                    Ok(keys) => serde_json::from_str(&keys)
+                        .map_err(|e| format!("Invalid JWT_PREVIOUS_KEYS: {}", e))?,
+                    Err(_) => Vec::new(),
+                },
+                jwt_rotation_grace_hours: env::var("JWT_ROTATION_GRACE_HOURS")
+                    .ok()
+                    .and_then(|hours| hours.parse().ok()),
                 refresh_token_expiry_days: env::var("REFRESH_TOKEN_EXPIRY_DAYS")
                     .unwrap_or_else(|_| "30".to_string())
                     .parse()
                     .unwrap_or(30),
+                refresh_token_secret: env::var("REFRESH_TOKEN_SECRET").ok(),
                 bcrypt_cost: env::var("BCRYPT_COST")
                     .unwrap_or_else(|_| "12".to_string())
                     .parse()
                     .unwrap_or(12),
+                password_algorithm: match env::var("PASSWORD_HASH_ALGORITHM") {
+                    Ok(algorithm) => serde_json::from_value(serde_json::Value::String(algorithm))
+                        .map_err(|e| format!("Invalid PASSWORD_HASH_ALGORITHM: {}", e))?,
+                    Err(_) => PasswordAlgorithm::Argon2id,
+                },
+                // OWASP's recommended minimum for Argon2id
+                argon2_memory_kib: env::var("ARGON2_MEMORY_KIB")
+                    .unwrap_or_else(|_| "19456".to_string())
+                    .parse()
+                    .unwrap_or(19456),
+                argon2_iterations: env::var("ARGON2_ITERATIONS")
+                    .unwrap_or_else(|_| "2".to_string())
+                    .parse()
+                    .unwrap_or(2),
+                argon2_parallelism: env::var("ARGON2_PARALLELISM")
+                    .unwrap_or_else(|_| "1".to_string())
+                    .parse()
+                    .unwrap_or(1),
+                lockout_threshold: env::var("LOGIN_LOCKOUT_THRESHOLD")
+                    .unwrap_or_else(|_| "5".to_string())
+                    .parse()
+                    .unwrap_or(5),
+                lockout_ip_threshold: env::var("LOGIN_LOCKOUT_IP_THRESHOLD")
+                    .unwrap_or_else(|_| "20".to_string())
+                    .parse()
+                    .unwrap_or(20),
+                lockout_base_seconds: env::var("LOGIN_LOCKOUT_BASE_SECONDS")
+                    .unwrap_or_else(|_| "30".to_string())
+                    .parse()
+                    .unwrap_or(30),
+                lockout_max_seconds: env::var("LOGIN_LOCKOUT_MAX_SECONDS")
+                    .unwrap_or_else(|_| "3600".to_string())
+                    .parse()
+                    .unwrap_or(3600),
+                totp_issuer: env::var("TOTP_ISSUER")
+                    .unwrap_or_else(|_| "Video Analytics".to_string()),
+                totp_encryption_key: env::var("TOTP_ENCRYPTION_KEY").ok(),
+                require_email_verification: env::var("REQUIRE_EMAIL_VERIFICATION")
+                    .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                    .unwrap_or(false),
+            },
+
+            oidc: OidcConfig {
+                providers: match env::var("OIDC_PROVIDERS") {
+                    Ok(providers) => serde_json::from_str(&providers)
+                        .map_err(|e| format!("Invalid OIDC_PROVIDERS: {}", e))?,
+                    Err(_) => Vec::new(),
+                },
+                mock_idp: match env::var("OIDC_MOCK_IDP") {
+                    Ok(mock_idp) => Some(serde_json::from_str(&mock_idp)
+                        .map_err(|e| format!("Invalid OIDC_MOCK_IDP: {}", e))?),
+                    Err(_) => None,
+                },
+            },
+
+            mail: MailConfig {
+                transport: match env::var("MAIL_TRANSPORT") {
+                    Ok(transport) => serde_json::from_value(serde_json::Value::String(transport))
+                        .map_err(|e| format!("Invalid MAIL_TRANSPORT: {}", e))?,
+                    Err(_) => MailTransport::File,
+                },
+                from: env::var("MAIL_FROM")
+                    .unwrap_or_else(|_| "Video Analytics <no-reply@localhost>".to_string()),
+                smtp_url: env::var("SMTP_URL").ok(),
+                directory: env::var("MAIL_DIR").unwrap_or_else(|_| "./mail".to_string()),
+                app_url: env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_string()),
             },
         };
 
@@ -111,6 +499,50 @@ impl Config {
             return Err("Kafka brokers cannot be empty".into());
         }
 
+        if self.rate_limit.requests_per_minute == 0 || self.rate_limit.burst_size == 0 {
+            return Err("Rate limit and burst size must be greater than 0".into());
+        }
+
+        for policy in &self.rate_limit.policies {
+            if policy.requests_per_minute == 0 || policy.burst_size == 0 {
+                return Err(format!("Rate limit policy '{}' must allow at least one request", policy.name).into());
+            }
+        }
+
+        if self.auth.jwt_expiry_hours <= 0 {
+            return Err("JWT expiry must be greater than 0".into());
+        }
+
+        if self.auth.lockout_threshold == 0 || self.auth.lockout_ip_threshold == 0 {
+            return Err("Login lockout thresholds must be greater than 0".into());
+        }
+
+        if self.auth.lockout_base_seconds <= 0 || self.auth.lockout_max_seconds < self.auth.lockout_base_seconds {
+            return Err("Login lockout durations must be positive, the maximum at least the base".into());
+        }
+
+        if self.auth.jwt_algorithm != JwtAlgorithm::HS256
+            && (self.auth.jwt_private_key_path.is_none() || self.auth.jwt_public_key_path.is_none())
+        {
+            return Err("JWT_PRIVATE_KEY_PATH and JWT_PUBLIC_KEY_PATH are required for asymmetric signing".into());
+        }
+
+        if self.auth.jwt_previous_keys.iter().any(|key| key.algorithm == JwtAlgorithm::HS256) {
+            return Err("Previous JWT keys must be public keys".into());
+        }
+
+        if self.oidc.mock_idp.as_ref().is_some_and(|mock_idp| mock_idp.users.is_empty()) {
+            return Err("The mock identity provider needs at least one user".into());
+        }
+
+        if self.mail.transport == MailTransport::Smtp && self.mail.smtp_url.is_none() {
+            return Err("SMTP_URL is required when MAIL_TRANSPORT is smtp".into());
+        }
+
+        if self.kafka.batch_size == 0 {
+            return Err("Kafka batch size must be greater than 0".into());
+        }
+
         Ok(())
     }
 } 
\ No newline at end of file
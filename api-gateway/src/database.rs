@@ -1,6 +1,15 @@
-use sqlx::{postgres::PgPoolOptions, PgPool, Row};
+use sqlx::{
+    migrate::{MigrateError, Migration, Migrator},
+    postgres::PgPoolOptions,
+    PgPool, Row,
+};
+use std::collections::HashSet;
 use std::time::Duration;
 
+// Migrations are embedded at compile time so every binary carries the exact
+// schema it was built against.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
 #[derive(Clone, Debug)]
 pub struct Database {
     pool: PgPool,
@@ -17,17 +26,80 @@ impl Database {
         Ok(Database { pool })
     }
 
+    /// Wraps a pool that is already migrated, like the one `#[sqlx::test]` provides
+    #[cfg(test)]
+    pub fn from_pool(pool: PgPool) -> Self {
+        Database { pool }
+    }
+
     pub async fn migrate(&self) -> Result<(), sqlx::Error> {
-        // In a real implementation, you'd use sqlx-cli migrations
-        // For now, we'll just verify the connection works
-        let _result = sqlx::query("SELECT 1")
-            .fetch_one(&self.pool)
-            .await?;
-        
-        tracing::info!("Database migration check completed");
+        let pending = self.pending_migrations().await?;
+
+        for migration in &pending {
+            tracing::info!(
+                "Applying migration {}: {}",
+                migration.version,
+                migration.description
+            );
+        }
+
+        MIGRATOR.run(&self.pool).await?;
+
+        tracing::info!("Database schema is up to date ({} migrations applied)", pending.len());
         Ok(())
     }
 
+    /// Returns the embedded migrations that have not been applied yet, in the
+    /// order they would run. Fails if the database has been migrated past what
+    /// this binary knows about.
+    pub async fn pending_migrations(&self) -> Result<Vec<&'static Migration>, sqlx::Error> {
+        let applied = self.applied_migrations().await?;
+        let known: HashSet<i64> = MIGRATOR.iter().map(|m| m.version).collect();
+
+        // Refuse to run against a schema that is ahead of the binary
+        if let Some(version) = applied.iter().find(|v| !known.contains(v)) {
+            return Err(MigrateError::VersionMissing(*version).into());
+        }
+
+        let pending = MIGRATOR
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration())
+            .filter(|m| !applied.contains(&m.version))
+            .collect();
+
+        Ok(pending)
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<i64>, sqlx::Error> {
+        let table_exists: bool =
+            sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+                .fetch_one(&self.pool)
+                .await?;
+
+        if !table_exists {
+            return Ok(Vec::new());
+        }
+
+        let rows = sqlx::query("SELECT version, success FROM _sqlx_migrations ORDER BY version")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut versions = Vec::with_capacity(rows.len());
+        for row in rows {
+            let version: i64 = row.try_get("version")?;
+            let success: bool = row.try_get("success")?;
+
+            // A failed migration leaves the schema in an unknown state
+            if !success {
+                return Err(MigrateError::Dirty(version).into());
+            }
+
+            versions.push(version);
+        }
+
+        Ok(versions)
+    }
+
     pub fn pool(&self) -> &PgPool {
         &self.pool
     }
@@ -38,4 +110,4 @@ impl Database {
             .await?;
         Ok(())
     }
-} 
\ No newline at end of file
+}
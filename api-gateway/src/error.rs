@@ -1,3 +1,4 @@
+use async_graphql::ErrorExtensions;
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
@@ -50,116 +51,115 @@ pub enum AppError {
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
 
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] rdkafka::error::KafkaError),
+
     #[error("Configuration error: {0}")]
     Config(String),
 }
 
+impl AppError {
+    /// Stable code reported to clients, both in REST error bodies and in the
+    /// `code` extension of GraphQL errors
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Database(_) => "DATABASE_ERROR",
+            AppError::Authentication(_) => "AUTHENTICATION_ERROR",
+            AppError::Authorization(_) => "AUTHORIZATION_ERROR",
+            AppError::Validation(_) => "VALIDATION_ERROR",
+            AppError::NotFound(_) => "NOT_FOUND",
+            AppError::Conflict(_) => "CONFLICT",
+            AppError::RateLimited => "RATE_LIMITED",
+            AppError::BadRequest(_) => "BAD_REQUEST",
+            AppError::ServiceUnavailable(_) => "SERVICE_UNAVAILABLE",
+            AppError::Internal(_) => "INTERNAL_ERROR",
+            AppError::Jwt(_) => "INVALID_TOKEN",
+            AppError::Redis(_) => "CACHE_ERROR",
+            AppError::HttpClient(_) => "EXTERNAL_SERVICE_ERROR",
+            AppError::Serialization(_) => "DATA_PROCESSING_ERROR",
+            AppError::Kafka(_) => "MESSAGE_BROKER_ERROR",
+            AppError::Config(_) => "CONFIG_ERROR",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::Authentication(_) | AppError::Jwt(_) => StatusCode::UNAUTHORIZED,
+            AppError::Authorization(_) => StatusCode::FORBIDDEN,
+            AppError::Validation(_) | AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            AppError::HttpClient(_) => StatusCode::BAD_GATEWAY,
+            AppError::ServiceUnavailable(_) | AppError::Kafka(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(_)
+            | AppError::Internal(_)
+            | AppError::Redis(_)
+            | AppError::Serialization(_)
+            | AppError::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// What clients are told. The text of database, cache, broker and other
+    /// internal errors may reveal the schema or the infrastructure, so it is
+    /// only logged.
+    pub fn client_message(&self) -> String {
+        match self {
+            AppError::Authentication(msg)
+            | AppError::Authorization(msg)
+            | AppError::Validation(msg)
+            | AppError::NotFound(msg)
+            | AppError::Conflict(msg)
+            | AppError::BadRequest(msg)
+            | AppError::ServiceUnavailable(msg) => msg.clone(),
+            AppError::RateLimited => "Rate limit exceeded".to_string(),
+            AppError::Database(_) => "Database error occurred".to_string(),
+            AppError::Internal(_) => "Internal server error".to_string(),
+            AppError::Jwt(_) => "Invalid token".to_string(),
+            AppError::Redis(_) => "Cache error occurred".to_string(),
+            AppError::HttpClient(_) => "External service error".to_string(),
+            AppError::Serialization(_) => "Data processing error".to_string(),
+            AppError::Kafka(_) => "Message broker error".to_string(),
+            AppError::Config(_) => "Configuration error".to_string(),
+        }
+    }
+
+    fn log(&self) {
+        match self {
+            AppError::NotFound(_) => tracing::info!("{}", self),
+            AppError::Authentication(_)
+            | AppError::Authorization(_)
+            | AppError::Validation(_)
+            | AppError::Conflict(_)
+            | AppError::RateLimited
+            | AppError::BadRequest(_)
+            | AppError::Jwt(_) => tracing::warn!("{}", self),
+            _ => tracing::error!("{}", self),
+        }
+    }
+}
+
+/// GraphQL errors carry the same code and message as REST responses
+impl ErrorExtensions for AppError {
+    fn extend(&self) -> async_graphql::Error {
+        self.log();
+        async_graphql::Error::new(self.client_message()).extend_with(|_, e| e.set("code", self.code()))
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, error_message, error_code) = match self {
-            AppError::Database(_) => {
-                tracing::error!("Database error: {}", self);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Database error occurred".to_string(),
-                    "DATABASE_ERROR",
-                )
-            }
-            AppError::Authentication(msg) => {
-                tracing::warn!("Authentication error: {}", msg);
-                (StatusCode::UNAUTHORIZED, msg, "AUTHENTICATION_ERROR")
-            }
-            AppError::Authorization(msg) => {
-                tracing::warn!("Authorization error: {}", msg);
-                (StatusCode::FORBIDDEN, msg, "AUTHORIZATION_ERROR")
-            }
-            AppError::Validation(msg) => {
-                tracing::warn!("Validation error: {}", msg);
-                (StatusCode::BAD_REQUEST, msg, "VALIDATION_ERROR")
-            }
-            AppError::NotFound(msg) => {
-                tracing::info!("Not found: {}", msg);
-                (StatusCode::NOT_FOUND, msg, "NOT_FOUND")
-            }
-            AppError::Conflict(msg) => {
-                tracing::warn!("Conflict: {}", msg);
-                (StatusCode::CONFLICT, msg, "CONFLICT")
-            }
-            AppError::RateLimited => {
-                tracing::warn!("Rate limit exceeded");
-                (
-                    StatusCode::TOO_MANY_REQUESTS,
-                    "Rate limit exceeded".to_string(),
-                    "RATE_LIMITED",
-                )
-            }
-            AppError::BadRequest(msg) => {
-                tracing::warn!("Bad request: {}", msg);
-                (StatusCode::BAD_REQUEST, msg, "BAD_REQUEST")
-            }
-            AppError::ServiceUnavailable(msg) => {
-                tracing::error!("Service unavailable: {}", msg);
-                (StatusCode::SERVICE_UNAVAILABLE, msg, "SERVICE_UNAVAILABLE")
-            }
-            AppError::Internal(msg) => {
-                tracing::error!("Internal error: {}", msg);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Internal server error".to_string(),
-                    "INTERNAL_ERROR",
-                )
-            }
-            AppError::Jwt(_) => {
-                tracing::warn!("JWT error: {}", self);
-                (
-                    StatusCode::UNAUTHORIZED,
-                    "Invalid token".to_string(),
-                    "INVALID_TOKEN",
-                )
-            }
-            AppError::Redis(_) => {
-                tracing::error!("Redis error: {}", self);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Cache error occurred".to_string(),
-                    "CACHE_ERROR",
-                )
-            }
-            AppError::HttpClient(_) => {
-                tracing::error!("HTTP client error: {}", self);
-                (
-                    StatusCode::BAD_GATEWAY,
-                    "External service error".to_string(),
-                    "EXTERNAL_SERVICE_ERROR",
-                )
-            }
-            AppError::Serialization(_) => {
-                tracing::error!("Serialization error: {}", self);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Data processing error".to_string(),
-                    "DATA_PROCESSING_ERROR",
-                )
-            }
-            AppError::Config(msg) => {
-                tracing::error!("Configuration error: {}", msg);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Configuration error".to_string(),
-                    "CONFIG_ERROR",
-                )
-            }
-        };
+        self.log();
 
         let body = Json(json!({
             "error": {
-                "code": error_code,
-                "message": error_message,
+                "code": self.code(),
+                "message": self.client_message(),
                 "timestamp": chrono::Utc::now().to_rfc3339()
             }
         }));
 
-        (status, body).into_response()
+        (self.status(), body).into_response()
     }
 }
 
@@ -1,65 +1,308 @@
 use async_graphql::{
-    http::{playground_source, GraphQLPlaygroundConfig},
-    Context, EmptySubscription, Object, Schema, SimpleObject,
+    dataloader::{DataLoader, Loader},
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextRequest, NextSubscribe},
+    futures_util::stream::BoxStream,
+    http::{playground_source, GraphQLPlaygroundConfig, ALL_WEBSOCKET_PROTOCOLS},
+    ComplexObject, Context, Data, ErrorExtensions, Object, Pos, SimpleObject, Subscription,
 };
+use async_graphql_axum::{GraphQLProtocol, GraphQLWebSocket};
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::{Html, IntoResponse},
+    extract::{State, WebSocketUpgrade},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Response},
     Json,
 };
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
 use serde_json::Value;
+use sqlx::{Postgres, QueryBuilder};
+use std::sync::{atomic::Ordering, Arc};
+use tokio_stream::wrappers::BroadcastStream;
 use uuid::Uuid;
+use validator::Validate;
 
 use crate::{
+    account::{self, InvitedMembership},
+    auth,
     error::{AppError, Result},
-    middleware::auth::{require_auth_context, AuthContext},
+    loaders::{self, LatestInferenceLoader, ModelLoader, OpenAlertsLoader, SegmentsLoader, StreamLoader, UserLoader},
+    middleware::auth::{authenticate_optional, require_auth_context, AuthContext, ORGANIZATION_HEADER},
     models::{
-        Alert, AlertStatus, AnalyticsEvent, CreateVideoStreamInput, EventSeverity,
-        InferenceModel, InferenceResult, PaginatedResponse, PaginationInfo, PaginationInput,
-        StreamStatus, StreamSourceType, UpdateVideoStreamInput, User, UserRole, VideoSegment,
-        VideoStream,
+        Alert, AlertStatus, AnalyticsEvent, ApiKey, CreateApiKeyInput, CreateVideoStreamInput,
+        CreateOrganizationInput, CreateRoleInput, CreatedApiKey, EventSeverity,
+        InferenceModel, InferenceResult, InferenceResultFilter, InferenceResultOrder, InferenceStats, Invitation, InviteUserInput, Organization, OrganizationMember, PaginatedResponse, PaginationInfo, PaginationInput, Role, SessionInfo,
+        StatsBucket, StatsGroup, StreamGrant, StreamStatus, StreamSourceType, TotpEnrollment, UpdateVideoStreamInput, User, UserRole, VideoSegment,
+        VideoStream, WebSocketSessionInfo, WebSocketSubscriptionInfo,
     },
+    pagination::{self, CursorValue, Keyset, KeysetConnection, PageArgs, SortKey},
+    permissions::{self, Access, PermissionGuard, StreamAccess},
+    query::{self, Filter, Update},
+    services::{
+        events::LiveEvent,
+        kafka_producer::{enqueue_stream_command, StreamCommand},
+    },
+    two_factor,
     AppState,
 };
 
-pub type Schema = async_graphql::Schema<Query, Mutation, EmptySubscription>;
+pub type Schema = async_graphql::Schema<Query, Mutation, Subscription>;
+
+/// Streams a resolver may read data from. Every query over stream data filters
+/// on this list, so tenant isolation and stream grants are applied in one
+/// place. With a stream id only that stream is returned, provided the caller
+/// may read it.
+async fn readable_streams(ctx: &Context<'_>, stream_id: Option<Uuid>, permission: &str) -> Result<Vec<Uuid>> {
+    let state = ctx.data::<AppState>()
+        .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+    let auth_context = ctx.data::<AuthContext>()
+        .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+    let Some(stream_id) = stream_id else {
+        return permissions::visible_stream_ids(&state.db, auth_context, permission).await;
+    };
+
+    auth_context.require_key_permission(permission)?;
+
+    // Streams the caller cannot see are reported as missing so their ids do not leak
+    if !permissions::has_stream_permission(&state.db, auth_context, stream_id, permission).await? {
+        return Err(AppError::NotFound("Video stream not found".to_string()));
+    }
+
+    Ok(vec![stream_id])
+}
+
+/// Checks a subscription's stream argument as `readable_streams` does and
+/// returns the check each of its events goes through. A subscription outlives
+/// the moment it started, so what the caller may see is decided per event.
+async fn subscription_access(ctx: &Context<'_>, stream_id: Option<Uuid>, permission: &'static str) -> Result<Arc<StreamAccess>> {
+    let state = ctx.data::<AppState>()
+        .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+    let auth_context = ctx.data::<AuthContext>()
+        .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+    if stream_id.is_some() {
+        readable_streams(ctx, stream_id, permission).await?;
+    }
+
+    Ok(Arc::new(StreamAccess::new(state.db.clone(), auth_context.clone(), permission)?))
+}
+
+// Roles with their permissions; callers append a WHERE clause and the GROUP BY
+const ROLE_QUERY: &str = r#"
+    SELECT r.id, r.name, r.description, r.is_system, r.requires_two_factor, r.created_at,
+           COALESCE(ARRAY_AGG(rp.permission ORDER BY rp.permission) FILTER (WHERE rp.permission IS NOT NULL), '{}')::TEXT[] AS permissions
+    FROM roles r
+    LEFT JOIN role_permissions rp ON rp.role_id = r.id
+"#;
+
+// Members with their email and role name; callers add the WHERE clause
+const MEMBER_QUERY: &str = r#"
+    SELECT m.organization_id, m.user_id, u.email, m.role_id, r.name AS role, m.created_at
+    FROM organization_members m
+    JOIN users u ON u.id = m.user_id
+    JOIN roles r ON r.id = m.role_id
+"#;
+
+async fn fetch_member(state: &AppState, organization_id: Uuid, user_id: Uuid) -> Result<OrganizationMember> {
+    sqlx::query_as::<_, OrganizationMember>(&format!(
+        "{} WHERE m.organization_id = $1 AND m.user_id = $2",
+        MEMBER_QUERY
+    ))
+    .bind(organization_id)
+    .bind(user_id)
+    .fetch_optional(state.db.pool())
+    .await?
+    .ok_or_else(|| AppError::NotFound("Organization member not found".to_string()))
+}
+
+/// Reads a stream and locks it for the rest of the transaction
+async fn lock_stream(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, id: Uuid) -> Result<VideoStream> {
+    sqlx::query_as::<_, VideoStream>("SELECT * FROM video_streams WHERE id = $1 FOR UPDATE")
+        .bind(id)
+        .fetch_optional(&mut **tx)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Video stream not found".to_string()))
+}
+
+async fn fetch_role(state: &AppState, id: Uuid) -> Result<Role> {
+    sqlx::query_as::<_, Role>(&format!("{} WHERE r.id = $1 GROUP BY r.id", ROLE_QUERY))
+        .bind(id)
+        .fetch_optional(state.db.pool())
+        .await?
+        .ok_or_else(|| AppError::NotFound("Role not found".to_string()))
+}
+
+async fn replace_role_permissions(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    role_id: Uuid,
+    permissions: &[String],
+) -> Result<()> {
+    sqlx::query("DELETE FROM role_permissions WHERE role_id = $1")
+        .bind(role_id)
+        .execute(&mut **tx)
+        .await?;
+
+    sqlx::query("INSERT INTO role_permissions (role_id, permission) SELECT $1, UNNEST($2::TEXT[])")
+        .bind(role_id)
+        .bind(permissions)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+// Limits the time buckets an `inferenceStats` range may span. Splitting by
+// class or model returns up to this many rows per group.
+const MAX_STATS_BUCKETS: i64 = 10_000;
+
+/// Compiles the `inferenceResults` filter, always scoped to `stream_ids`
+fn inference_result_filter(stream_ids: Vec<Uuid>, input: InferenceResultFilter) -> Result<Filter> {
+    let filter = Filter::new()
+        .any("stream_id", stream_ids)
+        .gte_opt("timestamp", input.from)
+        .lt_opt("timestamp", input.to)
+        .any_opt("detected_class", input.detected_classes)
+        .gte_opt("confidence", input.min_confidence)
+        .lte_opt("confidence", input.max_confidence)
+        .eq_opt("model_id", input.model_id)
+        .gte_opt("frame_number", input.min_frame_number)
+        .lte_opt("frame_number", input.max_frame_number);
+
+    let Some(region) = input.region else {
+        return Ok(filter);
+    };
+
+    if region.width < 0.0 || region.height < 0.0 {
+        return Err(AppError::Validation("Region width and height cannot be negative".to_string()));
+    }
+
+    // Two rectangles intersect when they overlap on both axes
+    Ok(filter.condition(move |query| {
+        query.push("bounding_box IS NOT NULL")
+            .push(" AND (bounding_box->>'x')::FLOAT8 < ").push_bind(region.x + region.width)
+            .push(" AND (bounding_box->>'x')::FLOAT8 + (bounding_box->>'width')::FLOAT8 > ").push_bind(region.x)
+            .push(" AND (bounding_box->>'y')::FLOAT8 < ").push_bind(region.y + region.height)
+            .push(" AND (bounding_box->>'y')::FLOAT8 + (bounding_box->>'height')::FLOAT8 > ").push_bind(region.y);
+    }))
+}
+
+/// Loads one row through the request's `DataLoader<L>`, batched with the
+/// loads of sibling fields
+async fn load<L>(ctx: &Context<'_>, id: Uuid) -> async_graphql::Result<Option<L::Value>>
+where
+    L: Loader<Uuid, Error = async_graphql::Error>,
+{
+    ctx.data::<DataLoader<L>>()?.load_one(id).await
+}
+
+async fn load_opt<L>(ctx: &Context<'_>, id: Option<Uuid>) -> async_graphql::Result<Option<L::Value>>
+where
+    L: Loader<Uuid, Error = async_graphql::Error>,
+{
+    match id {
+        Some(id) => load::<L>(ctx, id).await,
+        None => Ok(None),
+    }
+}
+
+#[ComplexObject]
+impl VideoStream {
+    /// Latest segments, newest first; `videoSegments` pages through the rest
+    #[graphql(guard = "Access::Authenticated")]
+    async fn segments(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<VideoSegment>> {
+        Ok(load::<SegmentsLoader>(ctx, self.id).await?.unwrap_or_default())
+    }
+
+    #[graphql(guard = "Access::Authenticated")]
+    async fn latest_inference(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<InferenceResult>> {
+        load::<LatestInferenceLoader>(ctx, self.id).await
+    }
+
+    #[graphql(guard = "Access::Authenticated")]
+    async fn open_alerts(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Alert>> {
+        Ok(load::<OpenAlertsLoader>(ctx, self.id).await?.unwrap_or_default())
+    }
+
+    #[graphql(name = "createdBy", guard = "Access::Authenticated")]
+    async fn creator(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<User>> {
+        load_opt::<UserLoader>(ctx, self.created_by).await
+    }
+}
+
+#[ComplexObject]
+impl Alert {
+    /// Null when the caller may read the alert but not the stream itself
+    #[graphql(guard = "Access::Authenticated")]
+    async fn stream(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<VideoStream>> {
+        load::<StreamLoader>(ctx, self.stream_id).await
+    }
+
+    #[graphql(name = "acknowledgedBy", guard = "Access::Authenticated")]
+    async fn acknowledger(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<User>> {
+        load_opt::<UserLoader>(ctx, self.acknowledged_by).await
+    }
+}
+
+#[ComplexObject]
+impl InferenceResult {
+    #[graphql(guard = "Access::Authenticated")]
+    async fn model(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<InferenceModel>> {
+        load_opt::<ModelLoader>(ctx, self.model_id).await
+    }
+
+    /// Null when the caller may read the result but not the stream itself
+    #[graphql(guard = "Access::Authenticated")]
+    async fn stream(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<VideoStream>> {
+        load::<StreamLoader>(ctx, self.stream_id).await
+    }
+}
 
 pub struct Query;
 
 #[Object]
 impl Query {
+    #[graphql(guard = "Access::Authenticated")]
     async fn me(&self, ctx: &Context<'_>) -> Result<User> {
         let auth_context = ctx.data::<AuthContext>()
             .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
         Ok(auth_context.user.clone())
     }
 
+    #[graphql(guard = "Access::Authenticated")]
     async fn video_streams(
         &self,
         ctx: &Context<'_>,
+        status: Option<StreamStatus>,
         pagination: Option<PaginationInput>,
     ) -> Result<PaginatedResponse<VideoStream>> {
         let state = ctx.data::<AppState>()
             .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
 
+        let stream_ids = readable_streams(ctx, None, permissions::STREAMS_READ).await?;
+
         let page = pagination.as_ref().and_then(|p| p.page).unwrap_or(1).max(1);
         let per_page = pagination.as_ref().and_then(|p| p.per_page).unwrap_or(20).min(100).max(1);
         let offset = (page - 1) * per_page;
 
+        let filter = Filter::new()
+            .any("id", stream_ids)
+            .eq_opt("status", status);
+
         // Get total count
-        let total_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM video_streams")
+        let total_count: i64 = query::count("video_streams", &filter)
+            .build_query_scalar()
             .fetch_one(state.db.pool())
             .await?;
 
         // Get streams
-        let streams = sqlx::query_as::<_, VideoStream>(
-            "SELECT * FROM video_streams ORDER BY created_at DESC LIMIT $1 OFFSET $2"
-        )
-        .bind(per_page)
-        .bind(offset)
-        .fetch_all(state.db.pool())
-        .await?;
+        let streams = query::select("video_streams", &filter)
+            .push(" ORDER BY created_at DESC LIMIT ").push_bind(per_page)
+            .push(" OFFSET ").push_bind(offset)
+            .build_query_as::<VideoStream>()
+            .fetch_all(state.db.pool())
+            .await?;
 
         let total_pages = (total_count as f64 / per_page as f64).ceil() as i32;
 
@@ -76,10 +319,16 @@ impl Query {
         })
     }
 
+    #[graphql(guard = "Access::Authenticated")]
     async fn video_stream(&self, ctx: &Context<'_>, id: Uuid) -> Result<Option<VideoStream>> {
         let state = ctx.data::<AppState>()
             .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
 
+        match readable_streams(ctx, Some(id), permissions::STREAMS_READ).await {
+            Err(AppError::NotFound(_)) => return Ok(None),
+            result => result?,
+        };
+
         let stream = sqlx::query_as::<_, VideoStream>(
             "SELECT * FROM video_streams WHERE id = $1"
         )
@@ -90,187 +339,375 @@ impl Query {
         Ok(stream)
     }
 
+    /// Segments of a stream, newest first. Page forward with `first`/`after`
+    /// and backward with `last`/`before`.
+    #[graphql(guard = "Access::Authenticated")]
     async fn video_segments(
         &self,
         ctx: &Context<'_>,
         stream_id: Uuid,
-        pagination: Option<PaginationInput>,
-    ) -> Result<PaginatedResponse<VideoSegment>> {
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<KeysetConnection<VideoSegment>> {
         let state = ctx.data::<AppState>()
             .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
 
-        let page = pagination.as_ref().and_then(|p| p.page).unwrap_or(1).max(1);
-        let per_page = pagination.as_ref().and_then(|p| p.per_page).unwrap_or(20).min(100).max(1);
-        let offset = (page - 1) * per_page;
-
-        // Get total count
-        let total_count: i64 = sqlx::query_scalar(
-            "SELECT COUNT(*) FROM video_segments WHERE stream_id = $1"
-        )
-        .bind(stream_id)
-        .fetch_one(state.db.pool())
-        .await?;
-
-        // Get segments
-        let segments = sqlx::query_as::<_, VideoSegment>(
-            "SELECT * FROM video_segments WHERE stream_id = $1 ORDER BY timestamp DESC LIMIT $2 OFFSET $3"
-        )
-        .bind(stream_id)
-        .bind(per_page)
-        .bind(offset)
-        .fetch_all(state.db.pool())
-        .await?;
+        readable_streams(ctx, Some(stream_id), permissions::STREAMS_READ).await?;
 
-        let total_pages = (total_count as f64 / per_page as f64).ceil() as i32;
+        let keyset = Keyset {
+            table: "video_segments",
+            sort: SortKey { column: "timestamp", descending: true, value: |segment: &VideoSegment| CursorValue::Time(segment.timestamp) },
+            default_size: 20,
+            max_size: 100,
+        };
+        let filter = Filter::new().eq("stream_id", stream_id);
 
-        Ok(PaginatedResponse {
-            items: segments,
-            pagination: PaginationInfo {
-                current_page: page,
-                per_page,
-                total_pages,
-                total_count,
-                has_next_page: page < total_pages,
-                has_prev_page: page > 1,
-            },
-        })
+        pagination::keyset_connection(ctx, state.db.pool(), keyset, &filter, PageArgs { after, before, first, last }).await
     }
 
+    /// Inference results, newest first unless another `orderBy` is given
+    #[graphql(guard = "Access::Authenticated")]
+    #[allow(clippy::too_many_arguments)]
     async fn inference_results(
         &self,
         ctx: &Context<'_>,
         stream_id: Option<Uuid>,
-        pagination: Option<PaginationInput>,
-    ) -> Result<PaginatedResponse<InferenceResult>> {
+        filter: Option<InferenceResultFilter>,
+        order_by: Option<InferenceResultOrder>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<KeysetConnection<InferenceResult>> {
         let state = ctx.data::<AppState>()
             .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
 
-        let page = pagination.as_ref().and_then(|p| p.page).unwrap_or(1).max(1);
-        let per_page = pagination.as_ref().and_then(|p| p.per_page).unwrap_or(50).min(200).max(1);
-        let offset = (page - 1) * per_page;
+        let stream_ids = readable_streams(ctx, stream_id, permissions::RESULTS_READ).await?;
+        let filter = inference_result_filter(stream_ids, filter.unwrap_or_default())?;
 
-        let (count_query, results_query, bind_stream_id) = if let Some(stream_id) = stream_id {
-            (
-                "SELECT COUNT(*) FROM inference_results WHERE stream_id = $1",
-                "SELECT * FROM inference_results WHERE stream_id = $1 ORDER BY timestamp DESC LIMIT $2 OFFSET $3",
-                Some(stream_id),
-            )
-        } else {
-            (
-                "SELECT COUNT(*) FROM inference_results",
-                "SELECT * FROM inference_results ORDER BY timestamp DESC LIMIT $1 OFFSET $2",
-                None,
-            )
+        let order = order_by.unwrap_or_default();
+        let sort = match order {
+            InferenceResultOrder::NewestFirst | InferenceResultOrder::OldestFirst => SortKey {
+                column: "timestamp",
+                descending: order == InferenceResultOrder::NewestFirst,
+                value: |result: &InferenceResult| CursorValue::Time(result.timestamp),
+            },
+            InferenceResultOrder::HighestConfidence | InferenceResultOrder::LowestConfidence => SortKey {
+                column: "confidence",
+                descending: order == InferenceResultOrder::HighestConfidence,
+                value: |result: &InferenceResult| CursorValue::Number(result.confidence as f64),
+            },
         };
+        let keyset = Keyset { table: "inference_results", sort, default_size: 50, max_size: 200 };
 
-        // Get total count
-        let total_count: i64 = if let Some(stream_id) = bind_stream_id {
-            sqlx::query_scalar(count_query)
-                .bind(stream_id)
-                .fetch_one(state.db.pool())
-                .await?
-        } else {
-            sqlx::query_scalar(count_query)
-                .fetch_one(state.db.pool())
-                .await?
-        };
+        pagination::keyset_connection(ctx, state.db.pool(), keyset, &filter, PageArgs { after, before, first, last }).await
+    }
 
-        // Get results
-        let results = if let Some(stream_id) = bind_stream_id {
-            sqlx::query_as::<_, InferenceResult>(results_query)
-                .bind(stream_id)
-                .bind(per_page)
-                .bind(offset)
-                .fetch_all(state.db.pool())
-                .await?
-        } else {
-            sqlx::query_as::<_, InferenceResult>(results_query)
-                .bind(per_page)
-                .bind(offset)
-                .fetch_all(state.db.pool())
-                .await?
-        };
+    /// Analytics events, newest first
+    #[graphql(guard = "Access::Authenticated")]
+    #[allow(clippy::too_many_arguments)]
+    async fn analytics_events(
+        &self,
+        ctx: &Context<'_>,
+        stream_id: Option<Uuid>,
+        event_type: Option<String>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<KeysetConnection<AnalyticsEvent>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
 
-        let total_pages = (total_count as f64 / per_page as f64).ceil() as i32;
+        let stream_ids = readable_streams(ctx, stream_id, permissions::RESULTS_READ).await?;
 
-        Ok(PaginatedResponse {
-            items: results,
-            pagination: PaginationInfo {
-                current_page: page,
-                per_page,
-                total_pages,
-                total_count,
-                has_next_page: page < total_pages,
-                has_prev_page: page > 1,
-            },
-        })
+        let keyset = Keyset {
+            table: "analytics_events",
+            sort: SortKey { column: "timestamp", descending: true, value: |event: &AnalyticsEvent| CursorValue::Time(event.timestamp) },
+            default_size: 50,
+            max_size: 200,
+        };
+        let filter = Filter::new()
+            .any("stream_id", stream_ids)
+            .eq_opt("event_type", event_type);
+
+        pagination::keyset_connection(ctx, state.db.pool(), keyset, &filter, PageArgs { after, before, first, last }).await
     }
 
+    /// Alerts, most recently triggered first
+    #[graphql(guard = "Access::Authenticated")]
+    #[allow(clippy::too_many_arguments)]
     async fn alerts(
         &self,
         ctx: &Context<'_>,
         stream_id: Option<Uuid>,
         status: Option<AlertStatus>,
-        pagination: Option<PaginationInput>,
-    ) -> Result<PaginatedResponse<Alert>> {
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<KeysetConnection<Alert>> {
         let state = ctx.data::<AppState>()
             .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
 
-        let page = pagination.as_ref().and_then(|p| p.page).unwrap_or(1).max(1);
-        let per_page = pagination.as_ref().and_then(|p| p.per_page).unwrap_or(20).min(100).max(1);
-        let offset = (page - 1) * per_page;
+        let stream_ids = readable_streams(ctx, stream_id, permissions::ALERTS_READ).await?;
 
-        // Build dynamic query based on filters
-        let mut where_conditions = Vec::new();
-        let mut bind_values: Vec<&dyn sqlx::Encode<sqlx::Postgres>> = Vec::new();
-        let mut param_count = 0;
+        let keyset = Keyset {
+            table: "alerts",
+            sort: SortKey { column: "triggered_at", descending: true, value: |alert: &Alert| CursorValue::Time(alert.triggered_at) },
+            default_size: 20,
+            max_size: 100,
+        };
+        let filter = Filter::new()
+            .any("stream_id", stream_ids)
+            .eq_opt("status", status);
+
+        pagination::keyset_connection(ctx, state.db.pool(), keyset, &filter, PageArgs { after, before, first, last }).await
+    }
+
+    /// Detection counts and confidence per time bucket over `[from, to)`,
+    /// optionally split by class and/or model. Empty buckets are left out.
+    #[graphql(guard = "Access::Authenticated")]
+    async fn inference_stats(
+        &self,
+        ctx: &Context<'_>,
+        stream_id: Option<Uuid>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        bucket: StatsBucket,
+        #[graphql(default)] group_by: Vec<StatsGroup>,
+    ) -> Result<Vec<InferenceStats>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
 
-        if let Some(stream_id) = stream_id {
-            param_count += 1;
-            where_conditions.push(format!("stream_id = ${}", param_count));
+        if from >= to {
+            return Err(AppError::Validation("from must be before to".to_string()));
+        }
+        if (to - from).num_seconds() / bucket.seconds() > MAX_STATS_BUCKETS {
+            return Err(AppError::Validation(format!(
+                "Too many buckets, use a wider bucket or a shorter range (at most {})",
+                MAX_STATS_BUCKETS
+            )));
         }
 
-        if let Some(status) = status {
-            param_count += 1;
-            where_conditions.push(format!("status = ${}", param_count));
+        let stream_ids = readable_streams(ctx, stream_id, permissions::RESULTS_READ).await?;
+        let filter = Filter::new()
+            .any("stream_id", stream_ids)
+            .gte("timestamp", from)
+            .lt("timestamp", to);
+
+        let by_class = group_by.contains(&StatsGroup::Class);
+        let by_model = group_by.contains(&StatsGroup::Model);
+
+        // Bound parameters cannot be repeated in GROUP BY, hence the ordinals
+        let mut stats = QueryBuilder::<Postgres>::new("SELECT time_bucket(make_interval(secs => ");
+        stats.push_bind(bucket.seconds() as f64)
+            .push("), timestamp) AS bucket, ")
+            .push(if by_class { "detected_class" } else { "NULL::VARCHAR AS detected_class" })
+            .push(", ")
+            .push(if by_model { "model_id" } else { "NULL::UUID AS model_id" })
+            .push(
+                ", COUNT(*) AS detections, AVG(confidence)::FLOAT8 AS mean_confidence, \
+                 MAX(confidence)::FLOAT8 AS max_confidence, COUNT(DISTINCT detected_class) AS distinct_classes \
+                 FROM inference_results",
+            );
+        filter.push_where(&mut stats);
+        stats.push(" GROUP BY 1");
+        if by_class {
+            stats.push(", 2");
         }
+        if by_model {
+            stats.push(", 3");
+        }
+        stats.push(" ORDER BY 1, 2, 3");
 
-        let where_clause = if where_conditions.is_empty() {
-            String::new()
-        } else {
-            format!("WHERE {}", where_conditions.join(" AND "))
-        };
+        let stats = stats.build_query_as::<InferenceStats>()
+            .fetch_all(state.db.pool())
+            .await?;
 
-        // Get total count (simplified for this example)
-        let total_count: i64 = sqlx::query_scalar(&format!(
-            "SELECT COUNT(*) FROM alerts {}", where_clause
-        ))
-        .fetch_one(state.db.pool())
+        Ok(stats)
+    }
+
+    /// Connected WebSocket sessions
+    #[graphql(guard = "Access::Admin")]
+    async fn websocket_sessions(&self, ctx: &Context<'_>) -> Result<Vec<WebSocketSessionInfo>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let sessions = state.ws_sessions.list().await;
+
+        let user_ids: Vec<Uuid> = sessions.iter().map(|(_, session)| session.user_id).collect();
+        let users = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ANY($1)")
+            .bind(&user_ids)
+            .fetch_all(state.db.pool())
+            .await?;
+
+        let mut infos = Vec::with_capacity(sessions.len());
+        for (id, session) in sessions {
+            let subscriptions = session
+                .subscriptions
+                .read()
+                .await
+                .iter()
+                .map(|subscription| WebSocketSubscriptionInfo {
+                    stream_id: subscription.stream_id,
+                    event_types: subscription.event_types.clone(),
+                })
+                .collect();
+
+            infos.push(WebSocketSessionInfo {
+                id,
+                user: users.iter().find(|user| user.id == session.user_id).cloned(),
+                subscriptions,
+                connected_at: session.connected_at,
+                dropped_messages: session.dropped_messages.load(Ordering::Relaxed) as i64,
+            });
+        }
+
+        Ok(infos)
+    }
+
+    /// API keys belonging to the current user
+    #[graphql(guard = "Access::Authenticated")]
+    async fn api_keys(&self, ctx: &Context<'_>) -> Result<Vec<ApiKey>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        let api_keys = sqlx::query_as::<_, ApiKey>(
+            "SELECT * FROM api_keys WHERE user_id = $1 ORDER BY created_at DESC"
+        )
+        .bind(auth_context.user.id)
+        .fetch_all(state.db.pool())
+        .await?;
+
+        Ok(api_keys)
+    }
+
+    /// Devices the current user is signed in on
+    #[graphql(guard = "Access::Authenticated")]
+    async fn my_sessions(&self, ctx: &Context<'_>) -> Result<Vec<SessionInfo>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        // Each family's newest token describes the session; its first one when it began
+        let sessions = sqlx::query_as::<_, SessionInfo>(
+            r#"
+            SELECT s.family_id AS id, s.user_agent, s.ip_address, s.family_id = $2 AS current,
+                   f.started_at AS created_at, s.created_at AS last_used_at, s.expires_at
+            FROM user_sessions s
+            JOIN (
+                SELECT family_id, MIN(created_at) AS started_at
+                FROM user_sessions WHERE user_id = $1 GROUP BY family_id
+            ) f ON f.family_id = s.family_id
+            WHERE s.user_id = $1 AND s.rotated_at IS NULL AND s.revoked_at IS NULL AND s.expires_at > NOW()
+            ORDER BY s.created_at DESC
+            "#,
+        )
+        .bind(auth_context.user.id)
+        .bind(auth_context.claims.as_ref().map(|claims| claims.sid))
+        .fetch_all(state.db.pool())
+        .await?;
+
+        Ok(sessions)
+    }
+
+    /// Permissions the current user holds in the active organization
+    #[graphql(guard = "Access::Authenticated")]
+    async fn my_permissions(&self, ctx: &Context<'_>) -> Result<Vec<String>> {
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        let mut permissions: Vec<String> = auth_context.permissions.iter().cloned().collect();
+        permissions.sort();
+        Ok(permissions)
+    }
+
+    /// Organizations the current user belongs to
+    #[graphql(guard = "Access::Authenticated")]
+    async fn organizations(&self, ctx: &Context<'_>) -> Result<Vec<Organization>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        let organizations = sqlx::query_as::<_, Organization>(
+            r#"
+            SELECT o.* FROM organizations o
+            JOIN organization_members m ON m.organization_id = o.id
+            WHERE m.user_id = $1
+            ORDER BY m.created_at
+            "#,
+        )
+        .bind(auth_context.user.id)
+        .fetch_all(state.db.pool())
         .await?;
 
-        // Get alerts (simplified query)
-        let alerts = sqlx::query_as::<_, Alert>(&format!(
-            "SELECT * FROM alerts {} ORDER BY triggered_at DESC LIMIT {} OFFSET {}",
-            where_clause, per_page, offset
+        Ok(organizations)
+    }
+
+    /// Members of the active organization
+    #[graphql(guard = "PermissionGuard::new(permissions::ORG_MANAGE)")]
+    async fn organization_members(&self, ctx: &Context<'_>) -> Result<Vec<OrganizationMember>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        let members = sqlx::query_as::<_, OrganizationMember>(&format!(
+            "{} WHERE m.organization_id = $1 ORDER BY m.created_at",
+            MEMBER_QUERY
         ))
+        .bind(auth_context.organization_id)
         .fetch_all(state.db.pool())
         .await?;
 
-        let total_pages = (total_count as f64 / per_page as f64).ceil() as i32;
+        Ok(members)
+    }
 
-        Ok(PaginatedResponse {
-            items: alerts,
-            pagination: PaginationInfo {
-                current_page: page,
-                per_page,
-                total_pages,
-                total_count,
-                has_next_page: page < total_pages,
-                has_prev_page: page > 1,
-            },
-        })
+    #[graphql(guard = "PermissionGuard::new(permissions::ROLES_MANAGE)")]
+    async fn roles(&self, ctx: &Context<'_>) -> Result<Vec<Role>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let roles = sqlx::query_as::<_, Role>(&format!("{} GROUP BY r.id ORDER BY r.name", ROLE_QUERY))
+            .fetch_all(state.db.pool())
+            .await?;
+
+        Ok(roles)
     }
 
+    /// Users a stream has been shared with
+    #[graphql(guard = "Access::Authenticated")]
+    async fn stream_grants(&self, ctx: &Context<'_>, stream_id: Uuid) -> Result<Vec<StreamGrant>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        permissions::require_stream_permission(&state.db, auth_context, stream_id, permissions::STREAMS_SHARE).await?;
+
+        let grants = sqlx::query_as::<_, StreamGrant>(
+            "SELECT * FROM stream_grants WHERE stream_id = $1 ORDER BY created_at, permission"
+        )
+        .bind(stream_id)
+        .fetch_all(state.db.pool())
+        .await?;
+
+        Ok(grants)
+    }
+
+    #[graphql(guard = "Access::Public")]
     async fn inference_models(&self, ctx: &Context<'_>) -> Result<Vec<InferenceModel>> {
         let state = ctx.data::<AppState>()
             .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
@@ -289,6 +726,7 @@ pub struct Mutation;
 
 #[Object]
 impl Mutation {
+    #[graphql(guard = "PermissionGuard::new(permissions::STREAMS_WRITE)")]
     async fn create_video_stream(
         &self,
         ctx: &Context<'_>,
@@ -300,30 +738,41 @@ impl Mutation {
         let auth_context = ctx.data::<AuthContext>()
             .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
 
+        let organization_id = auth_context.organization_id
+            .ok_or_else(|| AppError::Validation("No active organization".to_string()))?;
+
         let stream_id = Uuid::new_v4();
 
+        // The stream and its lifecycle command are committed together
+        let mut tx = state.db.pool().begin().await?;
+
         let stream = sqlx::query_as::<_, VideoStream>(
             r#"
-            INSERT INTO video_streams (id, name, description, source_url, source_type, status, created_by, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, NOW(), NOW())
+            INSERT INTO video_streams (id, organization_id, name, description, source_url, source_type, status, created_by, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NOW(), NOW())
             RETURNING *
             "#,
         )
         .bind(stream_id)
+        .bind(organization_id)
         .bind(&input.name)
         .bind(&input.description)
         .bind(&input.source_url)
         .bind(input.source_type)
         .bind(StreamStatus::Inactive)
         .bind(auth_context.user.id)
-        .fetch_one(state.db.pool())
+        .fetch_one(&mut *tx)
         .await?;
 
+        enqueue_stream_command(&mut tx, state, &StreamCommand::StreamCreated { stream: stream.clone() }).await?;
+        tx.commit().await?;
+
         tracing::info!("Video stream created: {} by {}", stream.name, auth_context.user.email);
 
         Ok(stream)
     }
 
+    #[graphql(guard = "Access::Authenticated")]
     async fn update_video_stream(
         &self,
         ctx: &Context<'_>,
@@ -336,56 +785,41 @@ impl Mutation {
         let auth_context = ctx.data::<AuthContext>()
             .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
 
-        // Check if stream exists and user has permission
-        let existing_stream = sqlx::query_as::<_, VideoStream>(
-            "SELECT * FROM video_streams WHERE id = $1"
-        )
-        .bind(id)
-        .fetch_optional(state.db.pool())
-        .await?
-        .ok_or_else(|| AppError::NotFound("Video stream not found".to_string()))?;
+        permissions::require_stream_permission(&state.db, auth_context, id, permissions::STREAMS_WRITE).await?;
 
-        // Check permission (owner or admin)
-        if existing_stream.created_by != Some(auth_context.user.id) 
-            && auth_context.user.role != UserRole::Admin {
-            return Err(AppError::Authorization("Permission denied".to_string()));
-        }
+        input.validate().map_err(|e| AppError::Validation(e.to_string()))?;
 
-        // Build update query dynamically
-        let mut set_clauses = vec!["updated_at = NOW()"];
-        let mut bind_count = 1;
+        let update = Update::new("video_streams")
+            .set_opt("name", input.name)
+            .set_opt("description", input.description)
+            .set_opt("source_url", input.source_url)
+            .set_opt("status", input.status)
+            .set_sql("updated_at", "NOW()");
 
-        if input.name.is_some() {
-            bind_count += 1;
-            set_clauses.push(&format!("name = ${}", bind_count));
-        }
-        if input.description.is_some() {
-            bind_count += 1;
-            set_clauses.push(&format!("description = ${}", bind_count));
-        }
-        if input.source_url.is_some() {
-            bind_count += 1;
-            set_clauses.push(&format!("source_url = ${}", bind_count));
-        }
-        if input.status.is_some() {
-            bind_count += 1;
-            set_clauses.push(&format!("status = ${}", bind_count));
-        }
+        let mut tx = state.db.pool().begin().await?;
 
-        let query = format!(
-            "UPDATE video_streams SET {} WHERE id = $1 RETURNING *",
-            set_clauses.join(", ")
-        );
+        // Locked until commit, so concurrent updates queue the commands for
+        // every status change in the order they happened
+        let existing_stream = lock_stream(&mut tx, id).await?;
 
-        // This is a simplified version - in a real implementation you'd use a query builder
-        let stream = sqlx::query_as::<_, VideoStream>(&query)
-            .bind(id)
-            .fetch_one(state.db.pool())
+        let stream = update.build(&Filter::new().eq("id", id))?
+            .build_query_as::<VideoStream>()
+            .fetch_one(&mut *tx)
             .await?;
 
-        Ok(stream)
-    }
+        if let Some(command) = StreamCommand::for_status_change(&existing_stream, &stream) {
+            enqueue_stream_command(&mut tx, state, &command).await?;
+        }
+        tx.commit().await?;
+
+        if stream.status != existing_stream.status {
+            state.events.publish(LiveEvent::StreamStatusChanged(stream.clone()));
+        }
 
+        Ok(stream)
+    }
+
+    #[graphql(guard = "Access::Authenticated")]
     async fn delete_video_stream(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool> {
         let state = ctx.data::<AppState>()
             .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
@@ -393,29 +827,25 @@ impl Mutation {
         let auth_context = ctx.data::<AuthContext>()
             .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
 
-        // Check if stream exists and user has permission
-        let existing_stream = sqlx::query_as::<_, VideoStream>(
-            "SELECT * FROM video_streams WHERE id = $1"
-        )
-        .bind(id)
-        .fetch_optional(state.db.pool())
-        .await?
-        .ok_or_else(|| AppError::NotFound("Video stream not found".to_string()))?;
+        permissions::require_stream_permission(&state.db, auth_context, id, permissions::STREAMS_WRITE).await?;
 
-        // Check permission (owner or admin)
-        if existing_stream.created_by != Some(auth_context.user.id) 
-            && auth_context.user.role != UserRole::Admin {
-            return Err(AppError::Authorization("Permission denied".to_string()));
-        }
+        let mut tx = state.db.pool().begin().await?;
+
+        // A concurrent update commits before the deletion is queued, or not at all
+        lock_stream(&mut tx, id).await?;
 
-        let result = sqlx::query("DELETE FROM video_streams WHERE id = $1")
+        sqlx::query("DELETE FROM video_streams WHERE id = $1")
             .bind(id)
-            .execute(state.db.pool())
+            .execute(&mut *tx)
             .await?;
 
-        Ok(result.rows_affected() > 0)
+        enqueue_stream_command(&mut tx, state, &StreamCommand::StreamDeleted { stream_id: id }).await?;
+        tx.commit().await?;
+
+        Ok(true)
     }
 
+    #[graphql(guard = "Access::Authenticated")]
     async fn acknowledge_alert(&self, ctx: &Context<'_>, id: Uuid) -> Result<Alert> {
         let state = ctx.data::<AppState>()
             .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
@@ -423,6 +853,14 @@ impl Mutation {
         let auth_context = ctx.data::<AuthContext>()
             .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
 
+        let stream_id: Uuid = sqlx::query_scalar("SELECT stream_id FROM alerts WHERE id = $1")
+            .bind(id)
+            .fetch_optional(state.db.pool())
+            .await?
+            .ok_or_else(|| AppError::NotFound("Alert not found".to_string()))?;
+
+        permissions::require_stream_permission(&state.db, auth_context, stream_id, permissions::ALERTS_ACK).await?;
+
         let alert = sqlx::query_as::<_, Alert>(
             r#"
             UPDATE alerts 
@@ -438,26 +876,911 @@ impl Mutation {
         .await?
         .ok_or_else(|| AppError::NotFound("Alert not found".to_string()))?;
 
+        // The alert_changed trigger publishes the acknowledgement to subscribers on every replica
         Ok(alert)
     }
+
+    /// Issues a new API key. The plaintext key is only returned by this call.
+    #[graphql(guard = "Access::Authenticated")]
+    async fn create_api_key(&self, ctx: &Context<'_>, input: CreateApiKeyInput) -> Result<CreatedApiKey> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+        input.validate().map_err(|e| AppError::Validation(e.to_string()))?;
+
+        permissions::ensure_known(&input.permissions)?;
+
+        if input.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+            return Err(AppError::Validation("Expiry must be in the future".to_string()));
+        }
+
+        // Keys act in the organization they were created in
+        let organization_id = auth_context.organization_id
+            .ok_or_else(|| AppError::Validation("No active organization".to_string()))?;
+
+        let key = auth::generate_api_key();
+
+        let api_key = sqlx::query_as::<_, ApiKey>(
+            r#"
+            INSERT INTO api_keys (id, user_id, organization_id, name, key_hash, permissions, is_active, expires_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, TRUE, $7, NOW())
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(auth_context.user.id)
+        .bind(organization_id)
+        .bind(&input.name)
+        .bind(auth::hash_api_key(&key))
+        .bind(serde_json::json!(input.permissions))
+        .bind(input.expires_at)
+        .fetch_one(state.db.pool())
+        .await?;
+
+        tracing::info!("API key '{}' created by {}", api_key.name, auth_context.user.email);
+
+        Ok(CreatedApiKey { api_key, key })
+    }
+
+    /// Replaces the secret of an active key, keeping its name and permissions.
+    /// The previous secret stops working immediately.
+    #[graphql(guard = "Access::Authenticated")]
+    async fn rotate_api_key(&self, ctx: &Context<'_>, id: Uuid) -> Result<CreatedApiKey> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+
+        let key = auth::generate_api_key();
+
+        let api_key = sqlx::query_as::<_, ApiKey>(
+            r#"
+            UPDATE api_keys SET key_hash = $1, last_used_at = NULL
+            WHERE id = $2 AND user_id = $3 AND is_active = TRUE
+            RETURNING *
+            "#,
+        )
+        .bind(auth::hash_api_key(&key))
+        .bind(id)
+        .bind(auth_context.user.id)
+        .fetch_optional(state.db.pool())
+        .await?
+        .ok_or_else(|| AppError::NotFound("API key not found".to_string()))?;
+
+        tracing::info!("API key '{}' rotated by {}", api_key.name, auth_context.user.email);
+
+        Ok(CreatedApiKey { api_key, key })
+    }
+
+    #[graphql(guard = "Access::Authenticated")]
+    async fn revoke_api_key(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+
+        // User managers may revoke any key, e.g. one found in a leaked config
+        let result = sqlx::query(
+            "UPDATE api_keys SET is_active = FALSE WHERE id = $1 AND (user_id = $2 OR $3) AND is_active = TRUE"
+        )
+        .bind(id)
+        .bind(auth_context.user.id)
+        .bind(auth_context.permissions.contains(permissions::USERS_MANAGE))
+        .execute(state.db.pool())
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    #[graphql(guard = "PermissionGuard::new(permissions::ROLES_MANAGE)")]
+    async fn create_role(&self, ctx: &Context<'_>, input: CreateRoleInput) -> Result<Role> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        input.validate().map_err(|e| AppError::Validation(e.to_string()))?;
+        permissions::ensure_known(&input.permissions)?;
+
+        let mut tx = state.db.pool().begin().await?;
+
+        let role_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO roles (name, description) VALUES ($1, $2) ON CONFLICT (name) DO NOTHING RETURNING id"
+        )
+        .bind(&input.name)
+        .bind(&input.description)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| AppError::Conflict("Role already exists".to_string()))?;
+
+        replace_role_permissions(&mut tx, role_id, &input.permissions).await?;
+        tx.commit().await?;
+
+        fetch_role(state, role_id).await
+    }
+
+    #[graphql(guard = "PermissionGuard::new(permissions::ROLES_MANAGE)")]
+    async fn set_role_permissions(&self, ctx: &Context<'_>, id: Uuid, permissions: Vec<String>) -> Result<Role> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        permissions::ensure_known(&permissions)?;
+
+        let mut tx = state.db.pool().begin().await?;
+
+        // Lock the role so concurrent edits cannot interleave
+        let is_system: bool = sqlx::query_scalar("SELECT is_system FROM roles WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| AppError::NotFound("Role not found".to_string()))?;
+
+        // Keeps at least one role able to manage roles
+        if is_system && !permissions.iter().any(|p| p == permissions::ROLES_MANAGE) {
+            let manages_roles: bool = sqlx::query_scalar(
+                "SELECT EXISTS(SELECT 1 FROM role_permissions WHERE role_id = $1 AND permission = $2)"
+            )
+            .bind(id)
+            .bind(permissions::ROLES_MANAGE)
+            .fetch_one(&mut *tx)
+            .await?;
+
+            if manages_roles {
+                return Err(AppError::Validation("Built-in roles cannot lose the roles:manage permission".to_string()));
+            }
+        }
+
+        replace_role_permissions(&mut tx, id, &permissions).await?;
+        tx.commit().await?;
+
+        fetch_role(state, id).await
+    }
+
+    /// Requires members of a role to sign in with a second factor. Those who
+    /// have not set one up are asked to enroll at their next login.
+    #[graphql(guard = "PermissionGuard::new(permissions::ROLES_MANAGE)")]
+    async fn set_role_requires_two_factor(&self, ctx: &Context<'_>, id: Uuid, required: bool) -> Result<Role> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let result = sqlx::query("UPDATE roles SET requires_two_factor = $2 WHERE id = $1")
+            .bind(id)
+            .bind(required)
+            .execute(state.db.pool())
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::NotFound("Role not found".to_string()));
+        }
+
+        fetch_role(state, id).await
+    }
+
+    #[graphql(guard = "PermissionGuard::new(permissions::ROLES_MANAGE)")]
+    async fn delete_role(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let role = fetch_role(state, id).await?;
+        if role.is_system {
+            return Err(AppError::Validation("Built-in roles cannot be deleted".to_string()));
+        }
+
+        let result = sqlx::query("DELETE FROM roles WHERE id = $1 AND NOT is_system")
+            .bind(id)
+            .execute(state.db.pool())
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Changes a user's built-in role. Registration only ever grants `User`.
+    #[graphql(guard = "Access::Admin")]
+    async fn set_user_role(&self, ctx: &Context<'_>, user_id: Uuid, role: UserRole) -> Result<User> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+
+        // Keeps an admin from locking themselves, and possibly everyone, out
+        if user_id == auth_context.user.id {
+            return Err(AppError::Validation("You cannot change your own role".to_string()));
+        }
+
+        let user = sqlx::query_as::<_, User>(
+            "UPDATE users SET role = $2, updated_at = NOW() WHERE id = $1 RETURNING *"
+        )
+        .bind(user_id)
+        .bind(role)
+        .fetch_optional(state.db.pool())
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+
+        tracing::warn!("{} changed the role of {} to {:?}", auth_context.user.email, user.email, role);
+
+        Ok(user)
+    }
+
+    /// Emails someone an invitation to create an account with the given role
+    #[graphql(guard = "PermissionGuard::new(permissions::USERS_MANAGE)")]
+    async fn invite_user(&self, ctx: &Context<'_>, input: InviteUserInput) -> Result<Invitation> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        input.validate().map_err(|e| AppError::Validation(e.to_string()))?;
+
+        // Only admins can bring in more admins
+        if input.role == UserRole::Admin && auth_context.user.role != UserRole::Admin {
+            return Err(AppError::Authorization("Only admins can invite admins".to_string()));
+        }
+
+        // The invitee joins the inviter's active organization. Picking their
+        // membership role takes the right to manage members.
+        let membership = match auth_context.organization_id {
+            Some(organization_id) => {
+                let role_id: Option<Uuid> = match input.organization_role_id {
+                    Some(role_id) => {
+                        auth_context.require_permission(permissions::ORG_MANAGE)?;
+                        sqlx::query_scalar("SELECT id FROM roles WHERE id = $1")
+                            .bind(role_id)
+                            .fetch_optional(state.db.pool())
+                            .await?
+                    }
+                    None => sqlx::query_scalar("SELECT id FROM roles WHERE name = CASE $1 WHEN 'admin' THEN 'owner' ELSE $1 END")
+                        .bind(format!("{:?}", input.role).to_lowercase())
+                        .fetch_optional(state.db.pool())
+                        .await?,
+                };
+
+                let role_id = role_id.ok_or_else(|| AppError::NotFound("Role not found".to_string()))?;
+                Some(InvitedMembership { organization_id, role_id })
+            }
+            None => None,
+        };
+
+        account::invite_user(state, &auth_context.user, &input.email, input.role, membership).await
+    }
+
+    /// Gives a user a role for its platform permissions, which apply in every
+    /// organization. Its other permissions have no effect here; members get
+    /// those through `setMemberRole`. Only roles whose every permission the
+    /// caller holds can be passed on.
+    #[graphql(guard = "PermissionGuard::new(permissions::USERS_MANAGE)")]
+    async fn assign_role(&self, ctx: &Context<'_>, user_id: Uuid, role_id: Uuid) -> Result<bool> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        if user_id == auth_context.user.id && auth_context.user.role != UserRole::Admin {
+            return Err(AppError::Authorization("Only admins can assign roles to themselves".to_string()));
+        }
+
+        let role = fetch_role(state, role_id).await?;
+        if !role.permissions.iter().any(|permission| permissions::PLATFORM.contains(&permission.as_str())) {
+            return Err(AppError::Validation(format!(
+                "Role '{}' grants no platform permission; assign it with setMemberRole",
+                role.name
+            )));
+        }
+
+        for permission in &role.permissions {
+            auth_context.require_permission(permission)?;
+        }
+
+        let result = sqlx::query(
+            r#"
+            INSERT INTO user_roles (user_id, role_id)
+            SELECT u.id, r.id FROM users u, roles r WHERE u.id = $1 AND r.id = $2
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user_id)
+        .bind(role_id)
+        .execute(state.db.pool())
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    #[graphql(guard = "PermissionGuard::new(permissions::USERS_MANAGE)")]
+    async fn unassign_role(&self, ctx: &Context<'_>, user_id: Uuid, role_id: Uuid) -> Result<bool> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let result = sqlx::query("DELETE FROM user_roles WHERE user_id = $1 AND role_id = $2")
+            .bind(user_id)
+            .bind(role_id)
+            .execute(state.db.pool())
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Signs the current user out of one session
+    #[graphql(guard = "Access::Authenticated")]
+    async fn revoke_session(&self, ctx: &Context<'_>, id: Uuid) -> Result<bool> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+
+        Ok(auth::revoke_sessions(state, auth_context.user.id, Some(id)).await? > 0)
+    }
+
+    /// Signs the current user out everywhere, including this session.
+    /// Returns how many sessions were ended.
+    #[graphql(guard = "Access::Authenticated")]
+    async fn revoke_all_sessions(&self, ctx: &Context<'_>) -> Result<i32> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+
+        let revoked = auth::revoke_sessions(state, auth_context.user.id, None).await?;
+        tracing::info!("{} revoked all of their {} sessions", auth_context.user.email, revoked);
+
+        Ok(revoked as i32)
+    }
+
+    /// Starts TOTP enrollment; it takes effect once confirmed with a code
+    #[graphql(guard = "Access::Authenticated")]
+    async fn enroll_totp(&self, ctx: &Context<'_>) -> Result<TotpEnrollment> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+
+        two_factor::enroll(state, &auth_context.user).await
+    }
+
+    /// Turns on two-factor authentication and returns the recovery codes,
+    /// which are not shown again
+    #[graphql(guard = "Access::Authenticated")]
+    async fn confirm_totp(&self, ctx: &Context<'_>, code: String) -> Result<Vec<String>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+
+        let recovery_codes = two_factor::confirm(state, auth_context.user.id, &code).await?;
+        tracing::info!("{} enabled two-factor authentication", auth_context.user.email);
+
+        Ok(recovery_codes)
+    }
+
+    #[graphql(guard = "Access::Authenticated")]
+    async fn disable_totp(&self, ctx: &Context<'_>, code: String) -> Result<bool> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+
+        if !two_factor::verify_code(state, auth_context.user.id, &code).await? {
+            return Err(AppError::Authentication("Invalid authentication code".to_string()));
+        }
+
+        two_factor::disable(state, &auth_context.user).await?;
+        tracing::info!("{} disabled two-factor authentication", auth_context.user.email);
+
+        Ok(true)
+    }
+
+    /// Replaces the recovery codes, invalidating the old ones
+    #[graphql(guard = "Access::Authenticated")]
+    async fn regenerate_recovery_codes(&self, ctx: &Context<'_>, code: String) -> Result<Vec<String>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+
+        if !two_factor::verify_code(state, auth_context.user.id, &code).await? {
+            return Err(AppError::Authentication("Invalid authentication code".to_string()));
+        }
+
+        let mut tx = state.db.pool().begin().await?;
+        let recovery_codes = two_factor::replace_recovery_codes(&mut tx, auth_context.user.id).await?;
+        tx.commit().await?;
+
+        Ok(recovery_codes)
+    }
+
+    /// Ends every session of another user, e.g. after their account was compromised
+    #[graphql(guard = "PermissionGuard::new(permissions::USERS_MANAGE)")]
+    async fn revoke_user_sessions(&self, ctx: &Context<'_>, user_id: Uuid) -> Result<i32> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        let revoked = auth::revoke_sessions(state, user_id, None).await?;
+        tracing::warn!("{} revoked all {} sessions of user {}", auth_context.user.email, revoked, user_id);
+
+        Ok(revoked as i32)
+    }
+
+    /// Creates an organization with the current user as its owner
+    #[graphql(guard = "Access::Authenticated")]
+    async fn create_organization(&self, ctx: &Context<'_>, input: CreateOrganizationInput) -> Result<Organization> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        auth_context.require_user_session()?;
+        input.validate().map_err(|e| AppError::Validation(e.to_string()))?;
+
+        let mut tx = state.db.pool().begin().await?;
+
+        let organization = sqlx::query_as::<_, Organization>(
+            "INSERT INTO organizations (name, slug) VALUES ($1, $2) ON CONFLICT (slug) DO NOTHING RETURNING *"
+        )
+        .bind(&input.name)
+        .bind(&input.slug)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| AppError::Conflict("Organization slug already taken".to_string()))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO organization_members (organization_id, user_id, role_id)
+            SELECT $1, $2, id FROM roles WHERE name = 'owner'
+            "#,
+        )
+        .bind(organization.id)
+        .bind(auth_context.user.id)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        tracing::info!("Organization '{}' created by {}", organization.slug, auth_context.user.email);
+
+        Ok(organization)
+    }
+
+    /// Adds an existing user to the active organization
+    #[graphql(guard = "PermissionGuard::new(permissions::ORG_MANAGE)")]
+    async fn add_organization_member(
+        &self,
+        ctx: &Context<'_>,
+        email: String,
+        role_id: Uuid,
+    ) -> Result<OrganizationMember> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        let organization_id = auth_context.organization_id
+            .ok_or_else(|| AppError::Validation("No active organization".to_string()))?;
+
+        let user_id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO organization_members (organization_id, user_id, role_id)
+            SELECT $1, u.id, r.id FROM users u, roles r WHERE u.email = $2 AND r.id = $3
+            ON CONFLICT (organization_id, user_id) DO NOTHING
+            RETURNING user_id
+            "#,
+        )
+        .bind(organization_id)
+        .bind(&email)
+        .bind(role_id)
+        .fetch_optional(state.db.pool())
+        .await?
+        .ok_or_else(|| AppError::NotFound("User or role not found, or already a member".to_string()))?;
+
+        fetch_member(state, organization_id, user_id).await
+    }
+
+    #[graphql(guard = "PermissionGuard::new(permissions::ORG_MANAGE)")]
+    async fn set_member_role(&self, ctx: &Context<'_>, user_id: Uuid, role_id: Uuid) -> Result<OrganizationMember> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        let organization_id = auth_context.organization_id
+            .ok_or_else(|| AppError::Validation("No active organization".to_string()))?;
+
+        // Managers cannot demote themselves and leave the organization without one
+        if user_id == auth_context.user.id {
+            return Err(AppError::Validation("You cannot change your own role".to_string()));
+        }
+
+        let result = sqlx::query(
+            r#"
+            UPDATE organization_members SET role_id = r.id
+            FROM roles r
+            WHERE organization_id = $1 AND user_id = $2 AND r.id = $3
+            "#,
+        )
+        .bind(organization_id)
+        .bind(user_id)
+        .bind(role_id)
+        .execute(state.db.pool())
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::NotFound("Organization member or role not found".to_string()));
+        }
+
+        fetch_member(state, organization_id, user_id).await
+    }
+
+    /// Removes a member from the active organization along with their grants
+    /// on its streams
+    #[graphql(guard = "PermissionGuard::new(permissions::ORG_MANAGE)")]
+    async fn remove_organization_member(&self, ctx: &Context<'_>, user_id: Uuid) -> Result<bool> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        let organization_id = auth_context.organization_id
+            .ok_or_else(|| AppError::Validation("No active organization".to_string()))?;
+
+        if user_id == auth_context.user.id {
+            return Err(AppError::Validation("You cannot remove yourself".to_string()));
+        }
+
+        let mut tx = state.db.pool().begin().await?;
+
+        sqlx::query(
+            "DELETE FROM stream_grants WHERE user_id = $1 AND stream_id IN (SELECT id FROM video_streams WHERE organization_id = $2)"
+        )
+        .bind(user_id)
+        .bind(organization_id)
+        .execute(&mut *tx)
+        .await?;
+
+        // Their keys for this organization stop working with the membership
+        sqlx::query("UPDATE api_keys SET is_active = FALSE WHERE user_id = $1 AND organization_id = $2")
+            .bind(user_id)
+            .bind(organization_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let result = sqlx::query("DELETE FROM organization_members WHERE organization_id = $1 AND user_id = $2")
+            .bind(organization_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Shares one stream with another member of its organization. Only
+    /// permissions the caller holds on the stream can be passed on.
+    #[graphql(guard = "Access::Authenticated")]
+    async fn share_stream(
+        &self,
+        ctx: &Context<'_>,
+        stream_id: Uuid,
+        user_id: Uuid,
+        permissions: Vec<String>,
+    ) -> Result<Vec<StreamGrant>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        permissions::require_stream_permission(&state.db, auth_context, stream_id, permissions::STREAMS_SHARE).await?;
+
+        for permission in &permissions {
+            if !permissions::STREAM_SCOPED.contains(&permission.as_str()) {
+                return Err(AppError::Validation(format!("'{}' cannot be granted on a stream", permission)));
+            }
+            permissions::require_stream_permission(&state.db, auth_context, stream_id, permission).await?;
+        }
+
+        let grants = sqlx::query_as::<_, StreamGrant>(
+            r#"
+            INSERT INTO stream_grants (stream_id, user_id, permission, granted_by)
+            SELECT s.id, m.user_id, p, $4
+            FROM video_streams s
+            JOIN organization_members m ON m.organization_id = s.organization_id AND m.user_id = $2
+            CROSS JOIN UNNEST($3::TEXT[]) AS p
+            WHERE s.id = $1
+            ON CONFLICT (stream_id, user_id, permission) DO NOTHING
+            RETURNING *
+            "#,
+        )
+        .bind(stream_id)
+        .bind(user_id)
+        .bind(&permissions)
+        .bind(auth_context.user.id)
+        .fetch_all(state.db.pool())
+        .await?;
+
+        tracing::info!("Stream {} shared with {} by {}: {:?}", stream_id, user_id, auth_context.user.email, permissions);
+
+        Ok(grants)
+    }
+
+    /// Removes every grant a user has on a stream
+    #[graphql(guard = "Access::Authenticated")]
+    async fn unshare_stream(&self, ctx: &Context<'_>, stream_id: Uuid, user_id: Uuid) -> Result<bool> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let auth_context = ctx.data::<AuthContext>()
+            .map_err(|_| AppError::Authentication("Authentication required".to_string()))?;
+
+        permissions::require_stream_permission(&state.db, auth_context, stream_id, permissions::STREAMS_SHARE).await?;
+
+        let result = sqlx::query("DELETE FROM stream_grants WHERE stream_id = $1 AND user_id = $2")
+            .bind(stream_id)
+            .bind(user_id)
+            .execute(state.db.pool())
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    #[graphql(guard = "Access::Authenticated")]
+    async fn inference_results(
+        &self,
+        ctx: &Context<'_>,
+        stream_id: Option<Uuid>,
+        min_confidence: Option<f32>,
+        classes: Option<Vec<String>>,
+    ) -> Result<impl Stream<Item = InferenceResult>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let access = subscription_access(ctx, stream_id, permissions::RESULTS_READ).await?;
+
+        let events = BroadcastStream::new(state.events.subscribe());
+
+        Ok(events.filter_map(move |event| {
+            let result = match event {
+                Ok(LiveEvent::InferenceResult(result)) => Some(result),
+                _ => None,
+            }
+            .filter(|result| stream_id.map_or(true, |id| id == result.stream_id))
+            .filter(|result| min_confidence.map_or(true, |min| result.confidence >= min))
+            .filter(|result| match (&classes, &result.detected_class) {
+                (None, _) => true,
+                (Some(classes), Some(class)) => classes.contains(class),
+                (Some(_), None) => false,
+            });
+
+            let access = access.clone();
+            async move {
+                let result = result?;
+                access.permits(result.stream_id).await.then_some(result)
+            }
+        }))
+    }
+
+    #[graphql(guard = "Access::Authenticated")]
+    async fn alerts(
+        &self,
+        ctx: &Context<'_>,
+        stream_id: Option<Uuid>,
+        min_severity: Option<EventSeverity>,
+    ) -> Result<impl Stream<Item = Alert>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let access = subscription_access(ctx, stream_id, permissions::ALERTS_READ).await?;
+
+        let events = BroadcastStream::new(state.events.subscribe());
+
+        Ok(events.filter_map(move |event| {
+            let alert = match event {
+                Ok(LiveEvent::Alert(alert)) => Some(alert),
+                _ => None,
+            }
+            .filter(|alert| stream_id.map_or(true, |id| id == alert.stream_id))
+            .filter(|alert| min_severity.map_or(true, |min| alert.severity >= min));
+
+            let access = access.clone();
+            async move {
+                let alert = alert?;
+                access.permits(alert.stream_id).await.then_some(alert)
+            }
+        }))
+    }
+
+    #[graphql(guard = "Access::Authenticated")]
+    async fn stream_status_changed(
+        &self,
+        ctx: &Context<'_>,
+        stream_id: Option<Uuid>,
+    ) -> Result<impl Stream<Item = VideoStream>> {
+        let state = ctx.data::<AppState>()
+            .map_err(|_| AppError::Internal("Failed to get app state".to_string()))?;
+
+        let access = subscription_access(ctx, stream_id, permissions::STREAMS_READ).await?;
+
+        let events = BroadcastStream::new(state.events.subscribe());
+
+        Ok(events.filter_map(move |event| {
+            let stream = match event {
+                Ok(LiveEvent::StreamStatusChanged(stream)) => Some(stream),
+                _ => None,
+            }
+            .filter(|stream| stream_id.map_or(true, |id| id == stream.id));
+
+            let access = access.clone();
+            async move {
+                let stream = stream?;
+                access.permits(stream.id).await.then_some(stream)
+            }
+        }))
+    }
 }
 
 pub async fn create_schema(state: AppState) -> Result<Schema> {
-    let schema = Schema::build(Query, Mutation, EmptySubscription)
+    let schema = Schema::build(Query, Mutation, Subscription)
         .data(state)
+        .extension(ErrorCodes)
         .finish();
 
     Ok(schema)
 }
 
+/// Resolvers return `AppError`, which async-graphql reports through its
+/// `Display` text. This passes those errors through `AppError::extend`
+/// instead, over HTTP and WebSocket alike, so they carry the same `code` and
+/// message as errors raised by guards.
+struct ErrorCodes;
+
+impl ExtensionFactory for ErrorCodes {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(ErrorCodes)
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for ErrorCodes {
+    async fn request(&self, ctx: &ExtensionContext<'_>, next: NextRequest<'_>) -> async_graphql::Response {
+        extend_app_errors(next.run(ctx).await)
+    }
+
+    fn subscribe<'s>(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        stream: BoxStream<'s, async_graphql::Response>,
+        next: NextSubscribe<'_>,
+    ) -> BoxStream<'s, async_graphql::Response> {
+        next.run(ctx, stream).map(extend_app_errors).boxed()
+    }
+}
+
+fn extend_app_errors(mut response: async_graphql::Response) -> async_graphql::Response {
+    for error in &mut response.errors {
+        if let Some(extended) = error.source::<AppError>().map(AppError::extend) {
+            error.message = extended.message;
+            error.extensions = extended.extensions;
+        }
+    }
+    response
+}
+
+/// Resolves the caller from the request headers before executing. Requests
+/// without credentials run anonymously and only reach `Access::Public` fields;
+/// invalid credentials fail the whole request.
 pub async fn graphql_handler(
     State((state, schema)): State<(AppState, Schema)>,
+    headers: HeaderMap,
     req: async_graphql_axum::GraphQLRequest,
 ) -> Result<impl IntoResponse> {
-    let response = schema.execute(req.into_inner()).await;
-    Ok(async_graphql_axum::GraphQLResponse::from(response))
+    let mut request = req.into_inner();
+
+    match authenticate_optional(&headers, &state).await {
+        Ok(Some(auth_context)) => {
+            loaders::register(&mut request.data, &state, &auth_context);
+            request = request.data(auth_context);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            let response = async_graphql::Response::from_errors(vec![e.extend().into_server_error(Pos::default())]);
+            return Ok(async_graphql_axum::GraphQLResponse::from(response));
+        }
+    }
+
+    Ok(async_graphql_axum::GraphQLResponse::from(schema.execute(request).await))
+}
+
+// Credentials a graphql-ws client may send in its `connection_init` payload
+const CONNECTION_INIT_HEADERS: &[&str] = &["authorization", "x-api-key", ORGANIZATION_HEADER];
+
+/// Authenticates a subscription connection from its `connection_init` payload,
+/// falling back to the handshake headers for clients that cannot set a payload.
+async fn connection_data(state: AppState, mut headers: HeaderMap, payload: Value) -> async_graphql::Result<Data> {
+    if let Value::Object(payload) = payload {
+        for (name, value) in payload {
+            let name = name.to_lowercase();
+            if !CONNECTION_INIT_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            if let (Ok(name), Some(Ok(value))) = (HeaderName::try_from(name), value.as_str().map(HeaderValue::from_str)) {
+                headers.insert(name, value);
+            }
+        }
+    }
+
+    let mut data = Data::default();
+    if let Some(auth_context) = authenticate_optional(&headers, &state).await.map_err(|e| e.extend())? {
+        loaders::register(&mut data, &state, &auth_context);
+        data.insert(auth_context);
+    }
+
+    Ok(data)
+}
+
+/// Upgrades graphql-ws / graphql-transport-ws handshakes on `/graphql` and
+/// serves the playground for plain GET requests.
+pub async fn graphql_ws_or_playground(
+    State((state, schema)): State<(AppState, Schema)>,
+    headers: HeaderMap,
+    protocol: Option<GraphQLProtocol>,
+    upgrade: Option<WebSocketUpgrade>,
+) -> Response {
+    match (protocol, upgrade) {
+        (Some(protocol), Some(upgrade)) => upgrade
+            .protocols(ALL_WEBSOCKET_PROTOCOLS)
+            .on_upgrade(move |stream| {
+                GraphQLWebSocket::new(stream, schema, protocol)
+                    .on_connection_init(move |payload| connection_data(state, headers, payload))
+                    .serve()
+            })
+            .into_response(),
+        _ => graphql_playground().await.into_response(),
+    }
 }
 
 pub async fn graphql_playground() -> impl IntoResponse {
-    Html(playground_source(GraphQLPlaygroundConfig::new("/graphql")))
+    Html(playground_source(
+        GraphQLPlaygroundConfig::new("/graphql").subscription_endpoint("/graphql"),
+    ))
 } 
\ No newline at end of file
@@ -0,0 +1,194 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use ed25519_dalek::VerifyingKey;
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rsa::{pkcs8::DecodePublicKey as _, traits::PublicKeyParts, RsaPublicKey};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{Config, JwtAlgorithm},
+    error::{AppError, Result},
+    models::Claims,
+};
+
+struct VerificationKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+    /// Published in the JWKS; shared secrets have none
+    jwk: Option<Value>,
+    /// Retired keys stop verifying tokens after their grace window
+    valid_until: Option<DateTime<Utc>>,
+}
+
+/// Keys access tokens are signed and verified with. With RS256 or EdDSA the
+/// public keys are published at `/.well-known/jwks.json`, so other services
+/// can verify gateway tokens without holding a secret.
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    kid: Option<String>,
+    signing_key: EncodingKey,
+    verification_keys: Vec<VerificationKey>,
+}
+
+impl JwtKeys {
+    pub fn load(config: &Config) -> Result<Self> {
+        let auth = &config.auth;
+
+        let mut keys = match auth.jwt_algorithm {
+            JwtAlgorithm::HS256 => Self {
+                algorithm: Algorithm::HS256,
+                kid: None,
+                signing_key: EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+                verification_keys: vec![VerificationKey {
+                    kid: None,
+                    algorithm: Algorithm::HS256,
+                    key: DecodingKey::from_secret(config.jwt_secret.as_bytes()),
+                    jwk: None,
+                    valid_until: None,
+                }],
+            },
+            algorithm => {
+                let algorithm = to_algorithm(algorithm);
+                let private_pem = read_pem(auth.jwt_private_key_path.as_deref())?;
+                let public_pem = read_pem(auth.jwt_public_key_path.as_deref())?;
+
+                // A stable default, so replicas sharing a key pair agree on its id
+                let kid = auth.jwt_key_id.clone()
+                    .unwrap_or_else(|| hex::encode(&Sha256::digest(&public_pem)[..8]));
+
+                let signing_key = match algorithm {
+                    Algorithm::RS256 => EncodingKey::from_rsa_pem(&private_pem)?,
+                    _ => EncodingKey::from_ed_pem(&private_pem)?,
+                };
+
+                Self {
+                    algorithm,
+                    kid: Some(kid.clone()),
+                    signing_key,
+                    verification_keys: vec![public_key(algorithm, kid, &public_pem, None)?],
+                }
+            }
+        };
+
+        let grace = Duration::hours(auth.jwt_rotation_grace_hours.unwrap_or(auth.jwt_expiry_hours));
+        for previous in &auth.jwt_previous_keys {
+            let valid_until = previous.retired_at + grace;
+            if valid_until <= Utc::now() {
+                continue;
+            }
+
+            let pem = read_pem(Some(&previous.public_key_path))?;
+            keys.verification_keys.push(public_key(
+                to_algorithm(previous.algorithm),
+                previous.kid.clone(),
+                &pem,
+                Some(valid_until),
+            )?);
+        }
+
+        Ok(keys)
+    }
+
+    pub fn sign(&self, claims: &Claims) -> Result<String> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid.clone();
+
+        Ok(encode(&header, claims, &self.signing_key)?)
+    }
+
+    /// Verifies a token against the key named by its `kid`. The key also fixes
+    /// the algorithm, so a token cannot pick a weaker one for itself.
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        let header = decode_header(token)?;
+        let now = Utc::now();
+
+        let key = self.verification_keys
+            .iter()
+            .filter(|key| key.valid_until.map_or(true, |valid_until| now < valid_until))
+            .find(|key| key.kid == header.kid && key.algorithm == header.alg)
+            .ok_or_else(|| AppError::Authentication("Unknown token signing key".to_string()))?;
+
+        Ok(decode::<Claims>(token, &key.key, &Validation::new(key.algorithm))?.claims)
+    }
+
+    /// The JSON Web Key Set of every public key still verifying tokens
+    pub fn jwks(&self) -> Value {
+        let now = Utc::now();
+        let keys: Vec<&Value> = self.verification_keys
+            .iter()
+            .filter(|key| key.valid_until.map_or(true, |valid_until| now < valid_until))
+            .filter_map(|key| key.jwk.as_ref())
+            .collect();
+
+        json!({ "keys": keys })
+    }
+}
+
+fn to_algorithm(algorithm: JwtAlgorithm) -> Algorithm {
+    match algorithm {
+        JwtAlgorithm::HS256 => Algorithm::HS256,
+        JwtAlgorithm::RS256 => Algorithm::RS256,
+        JwtAlgorithm::EdDSA => Algorithm::EdDSA,
+    }
+}
+
+fn read_pem(path: Option<&str>) -> Result<Vec<u8>> {
+    let path = path.ok_or_else(|| AppError::Config("Missing JWT key path".to_string()))?;
+
+    std::fs::read(path).map_err(|e| AppError::Config(format!("Cannot read JWT key '{}': {}", path, e)))
+}
+
+/// Loads an SPKI (`BEGIN PUBLIC KEY`) PEM together with its JWK.
+fn public_key(
+    algorithm: Algorithm,
+    kid: String,
+    pem: &[u8],
+    valid_until: Option<DateTime<Utc>>,
+) -> Result<VerificationKey> {
+    let pem_str = std::str::from_utf8(pem)
+        .map_err(|_| AppError::Config(format!("JWT key '{}' is not a PEM file", kid)))?;
+
+    let (key, jwk) = match algorithm {
+        Algorithm::RS256 => {
+            let public_key = RsaPublicKey::from_public_key_pem(pem_str)
+                .map_err(|e| AppError::Config(format!("Invalid RSA public key '{}': {}", kid, e)))?;
+
+            let jwk = json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": kid,
+                "n": URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+                "e": URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+            });
+
+            (DecodingKey::from_rsa_pem(pem)?, jwk)
+        }
+        Algorithm::EdDSA => {
+            let public_key = VerifyingKey::from_public_key_pem(pem_str)
+                .map_err(|e| AppError::Config(format!("Invalid Ed25519 public key '{}': {}", kid, e)))?;
+
+            let jwk = json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": kid,
+                "x": URL_SAFE_NO_PAD.encode(public_key.as_bytes()),
+            });
+
+            (DecodingKey::from_ed_pem(pem)?, jwk)
+        }
+        other => return Err(AppError::Config(format!("{:?} keys cannot be published", other))),
+    };
+
+    Ok(VerificationKey {
+        kid: Some(kid),
+        algorithm,
+        key,
+        jwk: Some(jwk),
+        valid_until,
+    })
+}
@@ -0,0 +1,206 @@
+//! DataLoaders behind the relations between GraphQL types, e.g.
+//! `VideoStream.openAlerts`. The relations requested while resolving a list
+//! are batched into one query each instead of one per parent. Loaders are
+//! created per request with the caller's `AuthContext` and apply the same
+//! stream permissions as the top-level resolvers.
+
+use async_graphql::{dataloader::{DataLoader, Loader}, Data, ErrorExtensions};
+use sqlx::FromRow;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::{
+    database::Database,
+    error::Result,
+    middleware::auth::AuthContext,
+    models::{Alert, AlertStatus, InferenceModel, InferenceResult, User, VideoSegment, VideoStream},
+    permissions,
+    AppState,
+};
+
+/// Segments returned by `VideoStream.segments`; older ones are paged
+/// through `videoSegments`
+pub const SEGMENTS_PER_STREAM: i64 = 20;
+
+/// What every loader needs to query on the caller's behalf
+#[derive(Clone)]
+struct Scope {
+    db: Database,
+    auth_context: AuthContext,
+}
+
+impl Scope {
+    /// The streams among `stream_ids` the caller holds `permission` on
+    async fn readable(&self, stream_ids: &[Uuid], permission: &str) -> Result<Vec<Uuid>> {
+        let visible: HashSet<Uuid> = permissions::visible_stream_ids(&self.db, &self.auth_context, permission)
+            .await?
+            .into_iter()
+            .collect();
+
+        Ok(stream_ids.iter().copied().filter(|id| visible.contains(id)).collect())
+    }
+}
+
+fn by_id<T>(rows: Vec<T>, id: impl Fn(&T) -> Uuid) -> HashMap<Uuid, T> {
+    rows.into_iter().map(|row| (id(&row), row)).collect()
+}
+
+fn group_by_stream<T>(rows: Vec<T>, stream_id: impl Fn(&T) -> Uuid) -> HashMap<Uuid, Vec<T>> {
+    let mut groups: HashMap<Uuid, Vec<T>> = HashMap::new();
+    for row in rows {
+        groups.entry(stream_id(&row)).or_default().push(row);
+    }
+    groups
+}
+
+/// The newest `limit` rows of `table` for each stream, through the
+/// `(stream_id, timestamp DESC, id DESC)` index
+async fn latest_per_stream<T>(db: &Database, table: &'static str, stream_ids: &[Uuid], limit: i64) -> Result<Vec<T>>
+where
+    T: for<'r> FromRow<'r, sqlx::postgres::PgRow> + Send + Unpin,
+{
+    let rows = sqlx::query_as::<_, T>(&format!(
+        r#"
+        SELECT latest.* FROM UNNEST($1::UUID[]) AS streams(id)
+        CROSS JOIN LATERAL (
+            SELECT * FROM {} WHERE stream_id = streams.id ORDER BY timestamp DESC, id DESC LIMIT $2
+        ) latest
+        "#,
+        table
+    ))
+    .bind(stream_ids)
+    .bind(limit)
+    .fetch_all(db.pool())
+    .await?;
+
+    Ok(rows)
+}
+
+// Loader errors must be cloneable; extending keeps the error code
+macro_rules! impl_loader {
+    ($loader:ty, $value:ty) => {
+        impl Loader<Uuid> for $loader {
+            type Value = $value;
+            type Error = async_graphql::Error;
+
+            async fn load(&self, keys: &[Uuid]) -> std::result::Result<HashMap<Uuid, $value>, Self::Error> {
+                self.fetch(keys).await.map_err(|e| e.extend())
+            }
+        }
+    };
+}
+
+pub struct StreamLoader(Scope);
+
+impl StreamLoader {
+    async fn fetch(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, VideoStream>> {
+        let stream_ids = self.0.readable(keys, permissions::STREAMS_READ).await?;
+
+        let streams = sqlx::query_as::<_, VideoStream>("SELECT * FROM video_streams WHERE id = ANY($1)")
+            .bind(&stream_ids)
+            .fetch_all(self.0.db.pool())
+            .await?;
+
+        Ok(by_id(streams, |stream| stream.id))
+    }
+}
+
+impl_loader!(StreamLoader, VideoStream);
+
+pub struct UserLoader(Database);
+
+impl UserLoader {
+    async fn fetch(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, User>> {
+        let users = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ANY($1)")
+            .bind(keys)
+            .fetch_all(self.0.pool())
+            .await?;
+
+        Ok(by_id(users, |user| user.id))
+    }
+}
+
+impl_loader!(UserLoader, User);
+
+pub struct ModelLoader(Database);
+
+impl ModelLoader {
+    async fn fetch(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, InferenceModel>> {
+        let models = sqlx::query_as::<_, InferenceModel>("SELECT * FROM inference_models WHERE id = ANY($1)")
+            .bind(keys)
+            .fetch_all(self.0.pool())
+            .await?;
+
+        Ok(by_id(models, |model| model.id))
+    }
+}
+
+impl_loader!(ModelLoader, InferenceModel);
+
+/// Newest segments of each stream, keyed by stream id
+pub struct SegmentsLoader(Scope);
+
+impl SegmentsLoader {
+    async fn fetch(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Vec<VideoSegment>>> {
+        let stream_ids = self.0.readable(keys, permissions::STREAMS_READ).await?;
+        let segments: Vec<VideoSegment> =
+            latest_per_stream(&self.0.db, "video_segments", &stream_ids, SEGMENTS_PER_STREAM).await?;
+
+        Ok(group_by_stream(segments, |segment| segment.stream_id))
+    }
+}
+
+impl_loader!(SegmentsLoader, Vec<VideoSegment>);
+
+/// Most recent inference result of each stream, keyed by stream id
+pub struct LatestInferenceLoader(Scope);
+
+impl LatestInferenceLoader {
+    async fn fetch(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, InferenceResult>> {
+        let stream_ids = self.0.readable(keys, permissions::RESULTS_READ).await?;
+        let results: Vec<InferenceResult> =
+            latest_per_stream(&self.0.db, "inference_results", &stream_ids, 1).await?;
+
+        Ok(by_id(results, |result| result.stream_id))
+    }
+}
+
+impl_loader!(LatestInferenceLoader, InferenceResult);
+
+/// Open alerts of each stream, most recently triggered first, keyed by
+/// stream id
+pub struct OpenAlertsLoader(Scope);
+
+impl OpenAlertsLoader {
+    async fn fetch(&self, keys: &[Uuid]) -> Result<HashMap<Uuid, Vec<Alert>>> {
+        let stream_ids = self.0.readable(keys, permissions::ALERTS_READ).await?;
+
+        let alerts = sqlx::query_as::<_, Alert>(
+            "SELECT * FROM alerts WHERE stream_id = ANY($1) AND status = $2 ORDER BY triggered_at DESC, id DESC"
+        )
+        .bind(&stream_ids)
+        .bind(AlertStatus::Open)
+        .fetch_all(self.0.db.pool())
+        .await?;
+
+        Ok(group_by_stream(alerts, |alert| alert.stream_id))
+    }
+}
+
+impl_loader!(OpenAlertsLoader, Vec<Alert>);
+
+/// Adds the loaders for an authenticated caller to a request's data. They
+/// batch without caching, so a subscription never sees stale rows.
+pub fn register(data: &mut Data, state: &AppState, auth_context: &AuthContext) {
+    let scope = Scope {
+        db: state.db.clone(),
+        auth_context: auth_context.clone(),
+    };
+
+    data.insert(DataLoader::new(StreamLoader(scope.clone()), tokio::spawn));
+    data.insert(DataLoader::new(UserLoader(state.db.clone()), tokio::spawn));
+    data.insert(DataLoader::new(ModelLoader(state.db.clone()), tokio::spawn));
+    data.insert(DataLoader::new(SegmentsLoader(scope.clone()), tokio::spawn));
+    data.insert(DataLoader::new(LatestInferenceLoader(scope.clone()), tokio::spawn));
+    data.insert(DataLoader::new(OpenAlertsLoader(scope), tokio::spawn));
+}
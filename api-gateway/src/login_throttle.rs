@@ -0,0 +1,146 @@
+//! Locks out password guessing. Failed logins are counted per account and per
+//! client IP; past a threshold each further failure locks the login for twice
+//! as long as the previous one, up to a maximum.
+//!
+//! Accounts are keyed on the submitted email whether or not it exists, so a
+//! lockout does not reveal which emails are registered. Wrong two-factor codes
+//! are counted per user the same way, across all of their challenges.
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{
+    error::{AppError, Result},
+    AppState,
+};
+
+const SCOPE_ACCOUNT: &str = "account";
+const SCOPE_IP: &str = "ip";
+const SCOPE_TWO_FACTOR: &str = "two_factor";
+
+// Failures are forgotten after a day without any
+const RESET_AFTER_SECONDS: i64 = 24 * 60 * 60;
+
+fn account_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Fails while the account or the client IP is locked out
+pub async fn check(state: &AppState, email: &str, ip: &str) -> Result<()> {
+    let locked_until: Option<DateTime<Utc>> = sqlx::query_scalar(
+        r#"
+        SELECT MAX(locked_until) FROM login_failures
+        WHERE ((scope = $1 AND key = $2) OR (scope = $3 AND key = $4)) AND locked_until > NOW()
+        "#,
+    )
+    .bind(SCOPE_ACCOUNT)
+    .bind(account_key(email))
+    .bind(SCOPE_IP)
+    .bind(ip)
+    .fetch_one(state.db.pool())
+    .await?;
+
+    if let Some(locked_until) = locked_until {
+        tracing::warn!("Login for {} from {} refused, locked until {}", email, ip, locked_until);
+        return Err(AppError::RateLimited);
+    }
+
+    Ok(())
+}
+
+pub async fn record_failure(state: &AppState, email: &str, ip: &str) -> Result<()> {
+    let auth = &state.config.auth;
+
+    record(state, SCOPE_ACCOUNT, &account_key(email), auth.lockout_threshold).await?;
+    record(state, SCOPE_IP, ip, auth.lockout_ip_threshold).await
+}
+
+/// Counts a failure and locks the key once it reaches the threshold
+async fn record(state: &AppState, scope: &str, key: &str, threshold: u32) -> Result<()> {
+    let auth = &state.config.auth;
+
+    let failures: i32 = sqlx::query_scalar(
+        r#"
+        INSERT INTO login_failures (scope, key, failures, last_failed_at) VALUES ($1, $2, 1, NOW())
+        ON CONFLICT (scope, key) DO UPDATE SET
+            failures = CASE
+                WHEN login_failures.last_failed_at < NOW() - make_interval(secs => $3) THEN 1
+                ELSE login_failures.failures + 1
+            END,
+            last_failed_at = NOW()
+        RETURNING failures
+        "#,
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(RESET_AFTER_SECONDS as f64)
+    .fetch_one(state.db.pool())
+    .await?;
+
+    if failures < threshold as i32 {
+        return Ok(());
+    }
+
+    let doublings = (failures - threshold as i32).min(30) as u32;
+    let seconds = auth.lockout_base_seconds
+        .saturating_mul(2i64.saturating_pow(doublings))
+        .min(auth.lockout_max_seconds);
+
+    sqlx::query(
+        "UPDATE login_failures SET locked_until = NOW() + make_interval(secs => $3) WHERE scope = $1 AND key = $2"
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(seconds as f64)
+    .execute(state.db.pool())
+    .await?;
+
+    tracing::warn!("Login locked for {} {} for {}s after {} failures", scope, key, seconds, failures);
+
+    Ok(())
+}
+
+/// Clears the account's failures. Those of the IP only expire, or a valid
+/// login of their own would let an attacker keep guessing at other accounts.
+pub async fn record_success(state: &AppState, email: &str) -> Result<()> {
+    sqlx::query("DELETE FROM login_failures WHERE scope = $1 AND key = $2")
+        .bind(SCOPE_ACCOUNT)
+        .bind(account_key(email))
+        .execute(state.db.pool())
+        .await?;
+
+    Ok(())
+}
+
+/// Fails while the user is locked out of entering two-factor codes
+pub async fn check_two_factor(state: &AppState, user_id: Uuid) -> Result<()> {
+    let locked_until: Option<DateTime<Utc>> = sqlx::query_scalar(
+        "SELECT locked_until FROM login_failures WHERE scope = $1 AND key = $2 AND locked_until > NOW()"
+    )
+    .bind(SCOPE_TWO_FACTOR)
+    .bind(user_id.to_string())
+    .fetch_optional(state.db.pool())
+    .await?
+    .flatten();
+
+    if let Some(locked_until) = locked_until {
+        tracing::warn!("Two-factor code for user {} refused, locked until {}", user_id, locked_until);
+        return Err(AppError::RateLimited);
+    }
+
+    Ok(())
+}
+
+pub async fn record_two_factor_failure(state: &AppState, user_id: Uuid) -> Result<()> {
+    record(state, SCOPE_TWO_FACTOR, &user_id.to_string(), state.config.auth.lockout_threshold).await
+}
+
+pub async fn record_two_factor_success(state: &AppState, user_id: Uuid) -> Result<()> {
+    sqlx::query("DELETE FROM login_failures WHERE scope = $1 AND key = $2")
+        .bind(SCOPE_TWO_FACTOR)
+        .bind(user_id.to_string())
+        .execute(state.db.pool())
+        .await?;
+
+    Ok(())
+}
@@ -1,11 +1,11 @@
 use axum::{
-    extract::State,
-    http::{HeaderValue, Method},
+    extract::{Query, State},
+    http::{HeaderMap, HeaderValue, Method},
     response::{Html, Json},
     routing::{get, post},
     Router,
 };
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 use tower::ServiceBuilder;
 use tower_http::{
     cors::{Any, CorsLayer},
@@ -15,23 +15,50 @@ use tower_http::{
 use tracing_subscriber;
 use serde_json::json;
 
+mod account;
 mod config;
 mod database;
 mod auth;
 mod graphql;
 mod error;
+mod jwt;
+mod loaders;
+mod login_throttle;
 mod middleware;
 mod models;
+mod oidc;
+mod pagination;
+mod password;
+mod permissions;
+mod query;
 mod services;
+#[cfg(test)]
+mod test_support;
+mod two_factor;
 
 use config::Config;
 use database::Database;
 use error::AppError;
+use jwt::JwtKeys;
+use middleware::rate_limit::VerifiedApiKeys;
+use oidc::{mock::MockIdp, Oidc};
+use password::PasswordHasher;
+use services::{denylist::TokenDenylist, events::EventBus, mailer::Mailer, websocket::WebSocketSessions};
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Database,
     pub config: Config,
+    pub events: EventBus,
+    pub ws_sessions: WebSocketSessions,
+    pub denylist: TokenDenylist,
+    pub jwt_keys: Arc<JwtKeys>,
+    /// API keys known to be genuine, so rate limits may key on them
+    pub verified_api_keys: VerifiedApiKeys,
+    pub oidc: Arc<Oidc>,
+    pub mock_idp: Option<Arc<MockIdp>>,
+    pub mailer: Arc<dyn Mailer>,
+    pub password_hasher: Arc<PasswordHasher>,
 }
 
 #[tokio::main]
@@ -51,16 +78,58 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let db = Database::new(&config.database_url).await?;
     tracing::info!("Database connection established");
 
-    // Run migrations
+    // Run migrations, or just report what would run
+    if config.migrate_dry_run {
+        let pending = db.pending_migrations().await?;
+        if pending.is_empty() {
+            println!("Database schema is up to date, no pending migrations");
+        } else {
+            println!("{} pending migration(s):", pending.len());
+            for migration in pending {
+                println!("\n-- {} {}\n{}", migration.version, migration.description, migration.sql.trim());
+            }
+        }
+        return Ok(());
+    }
+
     db.migrate().await?;
     tracing::info!("Database migrations completed");
 
+    // Unknown emails must take as long as the slowest stored hash
+    let mut password_hasher = PasswordHasher::new(&config.auth)?;
+    password_hasher.calibrate_dummy_hash(&db).await?;
+
     // Create application state
     let state = AppState {
         db,
         config: config.clone(),
+        events: EventBus::default(),
+        ws_sessions: WebSocketSessions::default(),
+        denylist: TokenDenylist::connect(config.redis_url.clone()),
+        jwt_keys: Arc::new(JwtKeys::load(&config)?),
+        verified_api_keys: VerifiedApiKeys::default(),
+        oidc: Arc::new(Oidc::new(config.oidc.providers.clone())),
+        mock_idp: config.oidc.mock_idp.clone().map(MockIdp::new).transpose()?.map(Arc::new),
+        mailer: services::mailer::from_config(&config.mail)?,
+        password_hasher: Arc::new(password_hasher),
     };
 
+    // Earlier enrollments stored their TOTP secret in plaintext
+    two_factor::encrypt_plaintext_secrets(&state).await?;
+
+    // Relay alerts raised by other services to live subscribers
+    services::events::spawn_alert_listener(state.clone());
+
+    // Start ingesting inference results and analytics events
+    if config.kafka.consumer_enabled {
+        services::kafka_consumer::spawn(state.clone()).await?;
+    }
+
+    // Publish stream lifecycle commands recorded in the outbox
+    if config.kafka.outbox_relay_enabled {
+        services::kafka_producer::spawn_outbox_relay(state.clone())?;
+    }
+
     // Build our application with routes
     let app = create_app(state).await?;
 
@@ -68,7 +137,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let listener = tokio::net::TcpListener::bind(&format!("0.0.0.0:{}", config.port)).await?;
     tracing::info!("Server listening on port {}", config.port);
 
-    axum::serve(listener, app).await?;
+    // Peer addresses are needed to tell trusted proxies from direct clients
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
@@ -80,6 +150,14 @@ async fn create_app(state: AppState) -> Result<Router, AppError> {
         .allow_headers(Any)
         .allow_origin(Any);
 
+    // Rate limits are shared across replicas through Redis
+    let rate_limit = middleware::rate_limit::RateLimitLayer::new(
+        &state.config,
+        state.jwt_keys.clone(),
+        state.verified_api_keys.clone(),
+    )
+    .await;
+
     // Create GraphQL schema
     let schema = graphql::create_schema(state.clone()).await?;
 
@@ -87,13 +165,36 @@ async fn create_app(state: AppState) -> Result<Router, AppError> {
         // Health check endpoint
         .route("/health", get(health_check))
         
-        // GraphQL endpoint
-        .route("/graphql", post(graphql::graphql_handler).get(graphql::graphql_playground))
+        // GraphQL endpoint (GET serves the playground and graphql-ws subscriptions)
+        .route("/graphql", post(graphql::graphql_handler).get(graphql::graphql_ws_or_playground))
         
         // Authentication endpoints
         .route("/auth/login", post(auth::login))
         .route("/auth/register", post(auth::register))
         .route("/auth/refresh", post(auth::refresh_token))
+        .route("/auth/logout", post(auth::logout))
+        .route("/auth/2fa/enroll", post(two_factor::enroll_with_challenge))
+        .route("/auth/2fa/verify", post(two_factor::verify))
+
+        // Account emails: verification, password reset and invitations
+        .route("/auth/verify-email", post(account::verify_email))
+        .route("/auth/verify-email/resend", post(account::resend_verification_email))
+        .route("/auth/password-reset", post(account::request_password_reset))
+        .route("/auth/password-reset/confirm", post(account::reset_password))
+        .route("/auth/invitations/accept", post(account::accept_invitation))
+
+        // Single sign-on through OpenID Connect providers
+        .route("/auth/oidc/:provider/login", get(oidc::login))
+        .route("/auth/oidc/:provider/callback", get(oidc::callback))
+
+        // Mock identity provider, answering 404 unless enabled
+        .route("/mock-idp/.well-known/openid-configuration", get(oidc::mock::discovery))
+        .route("/mock-idp/authorize", get(oidc::mock::authorize))
+        .route("/mock-idp/token", post(oidc::mock::token))
+        .route("/mock-idp/jwks.json", get(oidc::mock::jwks))
+
+        // Public keys for verifying access tokens
+        .route("/.well-known/jwks.json", get(auth::jwks))
         
         // WebSocket endpoint for real-time updates
         .route("/ws", get(websocket_handler))
@@ -107,7 +208,7 @@ async fn create_app(state: AppState) -> Result<Router, AppError> {
                 .layer(TraceLayer::new_for_http())
                 .layer(CompressionLayer::new())
                 .layer(cors)
-                .layer(middleware::rate_limit::RateLimitLayer::new())
+                .layer(rate_limit)
         );
 
     Ok(app)
@@ -124,6 +225,13 @@ async fn health_check() -> Json<serde_json::Value> {
 async fn websocket_handler(
     ws: axum::extract::WebSocketUpgrade,
     State((state, _)): State<(AppState, graphql::Schema)>,
-) -> impl axum::response::IntoResponse {
-    ws.on_upgrade(move |socket| services::websocket::handle_socket(socket, state))
+    Query(params): Query<services::websocket::WebSocketAuthParams>,
+    headers: HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    // Authenticate before upgrading so unauthenticated clients get a plain 401
+    let identity = services::websocket::authenticate_handshake(&state, &params, &headers).await?;
+
+    Ok(ws
+        .protocols(services::websocket::AUTH_PROTOCOLS)
+        .on_upgrade(move |socket| services::websocket::handle_socket(socket, state, identity)))
 } 
\ No newline at end of file
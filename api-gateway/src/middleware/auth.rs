@@ -4,19 +4,151 @@ use axum::{
     middleware::Next,
     response::Response,
 };
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
+use uuid::Uuid;
 
 use crate::{
-    auth::{get_user_from_token, verify_token},
+    auth::{get_user_from_api_key, get_user_from_token},
     error::AppError,
-    models::{Claims, User, UserRole},
+    models::{ApiKey, Claims, User, UserRole},
+    permissions::{resolve_organization, user_permissions, PLATFORM},
     AppState,
 };
 
 #[derive(Clone)]
 pub struct AuthContext {
     pub user: User,
-    pub claims: Claims,
+    /// Present when the request carried a bearer token
+    pub claims: Option<Claims>,
+    /// Present when the request authenticated with an API key
+    pub api_key: Option<ApiKey>,
+    /// The organization the request acts in; `None` for users who belong to none
+    pub organization_id: Option<Uuid>,
+    /// Permissions the user holds in that organization and across the platform
+    pub permissions: HashSet<String>,
+}
+
+impl AuthContext {
+    /// Requests made with an API key can only use what the key was issued with.
+    pub fn require_key_permission(&self, permission: &str) -> Result<(), AppError> {
+        match &self.api_key {
+            Some(api_key) if !api_key.permits(permission) => Err(AppError::Authorization(format!(
+                "API key lacks the '{}' permission",
+                permission
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn require_permission(&self, permission: &str) -> Result<(), AppError> {
+        self.require_key_permission(permission)?;
+
+        if !self.permissions.contains(permission) {
+            return Err(AppError::Authorization(format!("Missing the '{}' permission", permission)));
+        }
+
+        Ok(())
+    }
+
+    /// Platform administrators. An API key only carries admin access when it
+    /// is scoped to every platform-wide permission.
+    pub fn require_admin(&self) -> Result<(), AppError> {
+        if self.user.role != UserRole::Admin {
+            return Err(AppError::Authorization("Admin access required".to_string()));
+        }
+
+        for permission in PLATFORM {
+            self.require_key_permission(permission)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rejects API keys for operations that must be done by the user in person.
+    pub fn require_user_session(&self) -> Result<(), AppError> {
+        if self.api_key.is_some() {
+            return Err(AppError::Authorization("This operation requires a user session".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Header selecting the organization to act in, for users who belong to several.
+pub const ORGANIZATION_HEADER: &str = "x-organization-id";
+
+/// Completes an `AuthContext` for an authenticated user by resolving the
+/// organization to act in and the permissions held there. API keys are bound
+/// to the organization they were created in.
+pub async fn build_auth_context(
+    state: &AppState,
+    user: User,
+    claims: Option<Claims>,
+    api_key: Option<ApiKey>,
+    requested_organization: Option<Uuid>,
+) -> Result<AuthContext, AppError> {
+    let requested_organization = match &api_key {
+        Some(api_key) if requested_organization.is_some_and(|id| id != api_key.organization_id) => {
+            return Err(AppError::Authorization("API key belongs to another organization".to_string()));
+        }
+        Some(api_key) => Some(api_key.organization_id),
+        None => requested_organization,
+    };
+
+    let organization_id = resolve_organization(&state.db, user.id, requested_organization).await?;
+    let permissions = user_permissions(&state.db, &user, organization_id).await?;
+
+    Ok(AuthContext { user, claims, api_key, organization_id, permissions })
+}
+
+/// Resolves the caller from a bearer token or, failing that, an `X-API-Key` header.
+pub async fn authenticate(headers: &HeaderMap, state: &AppState) -> Result<AuthContext, AppError> {
+    let requested_organization = headers
+        .get(ORGANIZATION_HEADER)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| Uuid::parse_str(value).ok())
+                .ok_or_else(|| AppError::BadRequest("Invalid organization id".to_string()))
+        })
+        .transpose()?;
+
+    if let Some(auth_header) = headers.get("authorization") {
+        let auth_header = auth_header
+            .to_str()
+            .map_err(|_| AppError::Authentication("Invalid authorization header".to_string()))?;
+
+        let token = auth_header
+            .strip_prefix("Bearer ")
+            .ok_or_else(|| AppError::Authentication("Invalid authorization format".to_string()))?;
+
+        // Verify token and get user
+        let (user, claims) = get_user_from_token(token, state).await?;
+
+        return build_auth_context(state, user, Some(claims), None, requested_organization).await;
+    }
+
+    if let Some(key) = headers.get("x-api-key") {
+        let key = key
+            .to_str()
+            .map_err(|_| AppError::Authentication("Invalid API key header".to_string()))?;
+
+        let (user, api_key) = get_user_from_api_key(key, state).await?;
+
+        return build_auth_context(state, user, None, Some(api_key), requested_organization).await;
+    }
+
+    Err(AppError::Authentication("Missing authorization header".to_string()))
+}
+
+/// Like `authenticate`, but a request without credentials resolves to `None`
+/// so public operations stay reachable. Invalid credentials are still rejected.
+pub async fn authenticate_optional(headers: &HeaderMap, state: &AppState) -> Result<Option<AuthContext>, AppError> {
+    if !headers.contains_key("authorization") && !headers.contains_key("x-api-key") {
+        return Ok(None);
+    }
+
+    authenticate(headers, state).await.map(Some)
 }
 
 pub async fn auth_middleware(
@@ -24,24 +156,9 @@ pub async fn auth_middleware(
     mut request: Request,
     next: Next,
 ) -> Result<Response, AppError> {
-    let headers = request.headers();
-    
-    let auth_header = headers
-        .get("authorization")
-        .ok_or_else(|| AppError::Authentication("Missing authorization header".to_string()))?
-        .to_str()
-        .map_err(|_| AppError::Authentication("Invalid authorization header".to_string()))?;
-
-    let token = auth_header
-        .strip_prefix("Bearer ")
-        .ok_or_else(|| AppError::Authentication("Invalid authorization format".to_string()))?;
-
-    // Verify token and get user
-    let user = get_user_from_token(token, &state.config.jwt_secret, &state.db).await?;
-    let claims = verify_token(token, &state.config.jwt_secret)?;
+    let auth_context = authenticate(request.headers(), &state).await?;
 
     // Add auth context to request extensions
-    let auth_context = AuthContext { user, claims };
     request.extensions_mut().insert(auth_context);
 
     Ok(next.run(request).await)
@@ -53,28 +170,12 @@ pub async fn admin_auth_middleware(
     next: Next,
 ) -> Result<Response, AppError> {
     // First run the regular auth middleware logic
-    let headers = request.headers();
-    
-    let auth_header = headers
-        .get("authorization")
-        .ok_or_else(|| AppError::Authentication("Missing authorization header".to_string()))?
-        .to_str()
-        .map_err(|_| AppError::Authentication("Invalid authorization header".to_string()))?;
-
-    let token = auth_header
-        .strip_prefix("Bearer ")
-        .ok_or_else(|| AppError::Authentication("Invalid authorization format".to_string()))?;
-
-    let user = get_user_from_token(token, &state.config.jwt_secret, &state.db).await?;
-    let claims = verify_token(token, &state.config.jwt_secret)?;
+    let auth_context = authenticate(request.headers(), &state).await?;
 
     // Check if user is admin
-    if user.role != UserRole::Admin {
-        return Err(AppError::Authorization("Admin access required".to_string()));
-    }
+    auth_context.require_admin()?;
 
     // Add auth context to request extensions
-    let auth_context = AuthContext { user, claims };
     request.extensions_mut().insert(auth_context);
 
     Ok(next.run(request).await)
@@ -87,4 +188,4 @@ pub fn get_auth_context(request: &Request) -> Option<&AuthContext> {
 pub fn require_auth_context(request: &Request) -> Result<&AuthContext, AppError> {
     get_auth_context(request)
         .ok_or_else(|| AppError::Authentication("Authentication required".to_string()))
-} 
\ No newline at end of file
+}
\ No newline at end of file
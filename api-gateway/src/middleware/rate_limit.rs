@@ -1,63 +1,370 @@
+use async_graphql::parser::{
+    parse_query,
+    types::{ExecutableDocument, Selection, SelectionSet},
+};
 use axum::{
+    body::Body,
     extract::{ConnectInfo, Request},
-    http::StatusCode,
-    middleware::Next,
-    response::Response,
+    http::{HeaderMap, HeaderValue, Method},
+    response::{IntoResponse, Response},
 };
 use governor::{
-    clock::{DefaultClock, QuantaClock},
-    middleware::NoOpMiddleware,
-    state::{InMemoryState, NotKeyed},
+    clock::{Clock, DefaultClock},
+    middleware::StateInformationMiddleware,
+    state::keyed::DefaultKeyedStateStore,
     Quota, RateLimiter,
 };
+use ipnet::IpNet;
+use redis::{aio::ConnectionManager, Script};
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
     num::NonZeroU32,
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{Arc, RwLock as StdRwLock},
+    time::{Duration, Instant},
 };
+use tokio::sync::RwLock;
 use tower::{Layer, Service};
 
-use crate::error::AppError;
+use crate::{
+    auth::hash_api_key,
+    config::{Config, RateLimitKey, RateLimitPolicy},
+    error::AppError,
+    jwt::JwtKeys,
+    models::UserRole,
+};
+
+type LocalRateLimiter = RateLimiter<
+    String,
+    DefaultKeyedStateStore<String>,
+    DefaultClock,
+    StateInformationMiddleware,
+>;
+
+// Give up on Redis quickly so a slow cache never stalls requests
+const REDIS_TIMEOUT: Duration = Duration::from_millis(100);
+const REDIS_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+const LOCAL_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+// How long an API key that authenticated a request keeps its own bucket
+const VERIFIED_API_KEY_TTL: Duration = Duration::from_secs(600);
+// GraphQL bodies larger than this are rejected rather than buffered
+const MAX_GRAPHQL_BODY_BYTES: usize = 1024 * 1024;
+const GRAPHQL_PATH: &str = "/graphql";
+
+/// GCRA over a single key holding the theoretical arrival time (TAT) in ms.
+/// Uses the Redis clock so every gateway replica shares one notion of "now".
+/// Returns {allowed, remaining, retry_after_ms, reset_ms}.
+const GCRA_SCRIPT: &str = r#"
+local emission = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local tat = tonumber(redis.call('GET', KEYS[1])) or now
+if tat < now then
+    tat = now
+end
+
+local new_tat = tat + emission
+local allow_at = new_tat - burst * emission
+
+if now < allow_at then
+    return {0, 0, allow_at - now, tat - now}
+end
+
+redis.call('SET', KEYS[1], new_tat, 'PX', new_tat - now)
+return {1, math.floor((now - allow_at) / emission), 0, new_tat - now}
+"#;
+
+#[derive(Debug, Clone)]
+struct RateLimitDecision {
+    allowed: bool,
+    remaining: u32,
+    reset: Duration,
+    retry_after: Duration,
+}
+
+/// One quota and the requests it applies to. The default limit matches every
+/// request that is not exempt; policies add tighter limits on top of it.
+struct Limit {
+    policy: RateLimitPolicy,
+    local: LocalRateLimiter,
+}
+
+impl Limit {
+    fn new(policy: RateLimitPolicy) -> Self {
+        let requests_per_minute = NonZeroU32::new(policy.requests_per_minute).unwrap_or(NonZeroU32::MIN);
+        let burst_size = NonZeroU32::new(policy.burst_size).unwrap_or(NonZeroU32::MIN);
+        let quota = Quota::per_minute(requests_per_minute).allow_burst(burst_size);
+
+        Self {
+            policy,
+            local: RateLimiter::keyed(quota).with_middleware::<StateInformationMiddleware>(),
+        }
+    }
 
-type SharedRateLimiter = Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock, NoOpMiddleware>>;
-type IpRateLimiters = Arc<Mutex<HashMap<String, SharedRateLimiter>>>;
+    fn matches(&self, path: &str, graphql_fields: &HashSet<String>) -> bool {
+        let route_matches = self.policy.route.as_deref().map(|route| match route.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == route,
+        });
+        let fields_match = (!self.policy.graphql_fields.is_empty())
+            .then(|| self.policy.graphql_fields.iter().any(|f| graphql_fields.contains(f)));
+
+        match (route_matches, fields_match) {
+            (None, None) => true,
+            (route, fields) => route.unwrap_or(false) || fields.unwrap_or(false),
+        }
+    }
+}
+
+/// Digests of API keys that recently authenticated a request. Limits only key
+/// on an API key once it is known to be genuine; until then its requests count
+/// against the client IP, so sending a random key each time does not earn a
+/// fresh bucket, and the lookups such keys cause are limited along with them.
+#[derive(Clone, Default)]
+pub struct VerifiedApiKeys {
+    inner: Arc<StdRwLock<HashMap<String, Instant>>>,
+}
+
+impl VerifiedApiKeys {
+    /// Records a key that was just verified against the database.
+    pub fn insert(&self, key_hash: String) {
+        if let Ok(mut keys) = self.inner.write() {
+            keys.insert(key_hash, Instant::now());
+        }
+    }
+
+    fn contains(&self, key_hash: &str) -> bool {
+        self.inner
+            .read()
+            .is_ok_and(|keys| keys.get(key_hash).is_some_and(|verified_at| verified_at.elapsed() < VERIFIED_API_KEY_TTL))
+    }
+
+    fn retain_recent(&self) {
+        if let Ok(mut keys) = self.inner.write() {
+            keys.retain(|_, verified_at| verified_at.elapsed() < VERIFIED_API_KEY_TTL);
+        }
+    }
+}
+
+/// Who is making a request, as far as can be told without a database lookup.
+struct Caller {
+    ip: String,
+    user_id: Option<String>,
+    role: Option<UserRole>,
+    api_key_hash: Option<String>,
+}
 
+impl Caller {
+    fn bucket(&self, key: RateLimitKey) -> String {
+        let ip = || format!("ip:{}", self.ip);
+        let user = || self.user_id.as_ref().map(|id| format!("user:{}", id));
+        let api_key = || self.api_key_hash.as_ref().map(|hash| format!("api_key:{}", hash));
+
+        match key {
+            RateLimitKey::User => user().or_else(api_key).unwrap_or_else(ip),
+            RateLimitKey::ApiKey => api_key().or_else(user).unwrap_or_else(ip),
+            RateLimitKey::Role => self
+                .role
+                .map(|role| format!("role:{:?}", role))
+                .or_else(api_key)
+                .unwrap_or_else(ip),
+            RateLimitKey::Ip => ip(),
+        }
+    }
+}
+
+/// Token-bucket (GCRA) rate limiting shared across gateway replicas through
+/// Redis. Falls back to a per-process limiter while Redis is unreachable.
 #[derive(Clone)]
 pub struct RateLimitLayer {
-    quota: Quota,
-    ip_limiters: IpRateLimiters,
+    limits: Arc<Vec<Limit>>,
+    exempt_routes: Arc<Vec<String>>,
+    trusted_proxies: Arc<Vec<IpNet>>,
+    jwt_keys: Arc<JwtKeys>,
+    verified_api_keys: VerifiedApiKeys,
+    inspects_graphql: bool,
+    redis: Arc<RwLock<Option<ConnectionManager>>>,
+    script: Arc<Script>,
 }
 
 impl RateLimitLayer {
-    pub fn new() -> Self {
-        Self::with_quota(Quota::per_minute(NonZeroU32::new(60).unwrap()))
+    pub async fn new(config: &Config, jwt_keys: Arc<JwtKeys>, verified_api_keys: VerifiedApiKeys) -> Self {
+        let rate_limit = &config.rate_limit;
+
+        let default_limit = Limit::new(RateLimitPolicy {
+            name: "default".to_string(),
+            route: None,
+            graphql_fields: Vec::new(),
+            key: rate_limit.key,
+            requests_per_minute: rate_limit.requests_per_minute,
+            burst_size: rate_limit.burst_size,
+        });
+
+        let limits: Vec<Limit> = std::iter::once(default_limit)
+            .chain(rate_limit.policies.iter().cloned().map(Limit::new))
+            .collect();
+
+        let layer = Self {
+            inspects_graphql: limits.iter().any(|limit| !limit.policy.graphql_fields.is_empty()),
+            limits: Arc::new(limits),
+            exempt_routes: Arc::new(rate_limit.exempt_routes.clone()),
+            trusted_proxies: Arc::new(rate_limit.trusted_proxies.clone()),
+            jwt_keys,
+            verified_api_keys,
+            redis: Arc::new(RwLock::new(None)),
+            script: Arc::new(Script::new(GCRA_SCRIPT)),
+        };
+
+        layer.spawn_redis_connector(config.redis_url.clone());
+        layer.spawn_local_cleanup();
+        layer
     }
 
-    pub fn with_quota(quota: Quota) -> Self {
-        Self {
-            quota,
-            ip_limiters: Arc::new(Mutex::new(HashMap::new())),
+    // Connects in the background and keeps retrying; once connected the
+    // connection manager reconnects on its own.
+    fn spawn_redis_connector(&self, redis_url: String) {
+        let redis = self.redis.clone();
+
+        tokio::spawn(async move {
+            let client = match redis::Client::open(redis_url) {
+                Ok(client) => client,
+                Err(e) => {
+                    tracing::error!("Invalid Redis URL, rate limiting stays per-process: {}", e);
+                    return;
+                }
+            };
+
+            loop {
+                match ConnectionManager::new(client.clone()).await {
+                    Ok(connection) => {
+                        *redis.write().await = Some(connection);
+                        tracing::info!("Rate limiter connected to Redis");
+                        return;
+                    }
+                    Err(e) => {
+                        tracing::warn!("Rate limiter cannot reach Redis, using in-memory limits: {}", e);
+                        tokio::time::sleep(REDIS_RECONNECT_INTERVAL).await;
+                    }
+                }
+            }
+        });
+    }
+
+    // Drops idle keys so the fallback limiters do not grow without bound
+    fn spawn_local_cleanup(&self) {
+        let limits = Arc::downgrade(&self.limits);
+        let verified_api_keys = self.verified_api_keys.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(LOCAL_CLEANUP_INTERVAL).await;
+                let Some(limits) = limits.upgrade() else { return };
+                for limit in limits.iter() {
+                    limit.local.retain_recent();
+                    limit.local.shrink_to_fit();
+                }
+                verified_api_keys.retain_recent();
+            }
+        });
+    }
+
+    fn is_exempt(&self, path: &str) -> bool {
+        self.exempt_routes.iter().any(|route| match route.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == route,
+        })
+    }
+
+    fn caller(&self, request: &Request) -> Caller {
+        let headers = request.headers();
+
+        // Invalid tokens are treated as anonymous here; authentication rejects them later
+        let claims = headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.jwt_keys.verify(token).ok());
+
+        Caller {
+            ip: request.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|info| client_ip(info.0.ip(), headers, &self.trusted_proxies))
+                .unwrap_or_else(|| "unknown".to_string()),
+            user_id: claims.as_ref().map(|claims| claims.sub.clone()),
+            role: claims.as_ref().map(|claims| claims.role),
+            // Keys that have not been verified yet are limited by client IP
+            api_key_hash: headers
+                .get("x-api-key")
+                .and_then(|value| value.to_str().ok())
+                .map(hash_api_key)
+                .filter(|hash| self.verified_api_keys.contains(hash)),
         }
     }
 
-    pub fn per_minute(limit: u32) -> Self {
-        let quota = Quota::per_minute(NonZeroU32::new(limit).unwrap_or(NonZeroU32::new(1).unwrap()));
-        Self::with_quota(quota)
+    async fn check(&self, limit: &Limit, bucket: &str) -> RateLimitDecision {
+        let connection = self.redis.read().await.clone();
+
+        if let Some(mut connection) = connection {
+            match tokio::time::timeout(REDIS_TIMEOUT, self.check_redis(&mut connection, limit, bucket)).await {
+                Ok(Ok(decision)) => return decision,
+                Ok(Err(e)) => tracing::warn!("Redis rate limit check failed, using in-memory limits: {}", e),
+                Err(_) => tracing::warn!("Redis rate limit check timed out, using in-memory limits"),
+            }
+        }
+
+        check_local(limit, bucket)
     }
 
-    pub fn per_second(limit: u32) -> Self {
-        let quota = Quota::per_second(NonZeroU32::new(limit).unwrap_or(NonZeroU32::new(1).unwrap()));
-        Self::with_quota(quota)
+    async fn check_redis(
+        &self,
+        connection: &mut ConnectionManager,
+        limit: &Limit,
+        bucket: &str,
+    ) -> Result<RateLimitDecision, redis::RedisError> {
+        let emission_ms = 60_000 / u64::from(limit.policy.requests_per_minute.max(1));
+
+        let (allowed, remaining, retry_after_ms, reset_ms): (i64, i64, i64, i64) = self
+            .script
+            .key(format!("ratelimit:{}:{}", limit.policy.name, bucket))
+            .arg(emission_ms.max(1))
+            .arg(limit.policy.burst_size.max(1))
+            .invoke_async(connection)
+            .await?;
+
+        Ok(RateLimitDecision {
+            allowed: allowed == 1,
+            remaining: remaining.max(0) as u32,
+            reset: Duration::from_millis(reset_ms.max(0) as u64),
+            retry_after: Duration::from_millis(retry_after_ms.max(0) as u64),
+        })
     }
+}
+
+fn check_local(limit: &Limit, bucket: &str) -> RateLimitDecision {
+    let period = Duration::from_secs(60) / limit.policy.requests_per_minute.max(1);
+    let burst_size = limit.policy.burst_size.max(1);
 
-    fn get_or_create_limiter(&self, ip: String) -> SharedRateLimiter {
-        let mut limiters = self.ip_limiters.lock().unwrap();
-        
-        limiters.entry(ip).or_insert_with(|| {
-            Arc::new(RateLimiter::direct(self.quota))
-        }).clone()
+    match limit.local.check_key(&bucket.to_string()) {
+        Ok(snapshot) => {
+            let remaining = snapshot.remaining_burst_capacity();
+            RateLimitDecision {
+                allowed: true,
+                remaining,
+                reset: period * burst_size.saturating_sub(remaining),
+                retry_after: Duration::ZERO,
+            }
+        }
+        Err(not_until) => {
+            let retry_after = not_until.wait_time_from(DefaultClock::default().now());
+            RateLimitDecision {
+                allowed: false,
+                remaining: 0,
+                reset: retry_after + period * burst_size,
+                retry_after,
+            }
+        }
     }
 }
 
@@ -82,14 +389,13 @@ impl<S> Service<Request> for RateLimitService<S>
 where
     S: Service<Request, Response = Response> + Clone + Send + 'static,
     S::Future: Send + 'static,
-    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
 {
     type Response = Response;
-    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Error = S::Error;
     type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx).map_err(Into::into)
+        self.inner.poll_ready(cx)
     }
 
     fn call(&mut self, request: Request) -> Self::Future {
@@ -97,59 +403,248 @@ where
         let mut inner = self.inner.clone();
 
         Box::pin(async move {
-            // Extract IP address
-            let ip = extract_ip(&request).unwrap_or_else(|| "unknown".to_string());
-            
-            // Get rate limiter for this IP
-            let limiter = layer.get_or_create_limiter(ip);
-
-            // Check rate limit
-            match limiter.check() {
-                Ok(_) => {
-                    // Request allowed, proceed
-                    inner.call(request).await.map_err(Into::into)
+            let path = request.uri().path().to_string();
+            if layer.is_exempt(&path) {
+                return inner.call(request).await;
+            }
+
+            let caller = layer.caller(&request);
+
+            // GraphQL policies apply per root field, which requires reading the body
+            let (request, graphql_fields) = if layer.inspects_graphql
+                && request.method() == Method::POST
+                && path == GRAPHQL_PATH
+            {
+                match buffer_graphql_fields(request).await {
+                    Ok(buffered) => buffered,
+                    Err(e) => return Ok(e.into_response()),
                 }
-                Err(_) => {
-                    // Rate limit exceeded
-                    let response = AppError::RateLimited.into_response();
-                    Ok(response)
+            } else {
+                (request, HashSet::new())
+            };
+
+            // The most restrictive matching limit decides the outcome and the headers
+            let mut binding: Option<(&Limit, RateLimitDecision)> = None;
+            for limit in layer.limits.iter().filter(|limit| limit.matches(&path, &graphql_fields)) {
+                let decision = layer.check(limit, &caller.bucket(limit.policy.key)).await;
+
+                let more_restrictive = match &binding {
+                    None => true,
+                    Some((_, current)) if current.allowed != decision.allowed => !decision.allowed,
+                    Some((_, current)) if !decision.allowed => decision.retry_after > current.retry_after,
+                    Some((_, current)) => decision.remaining < current.remaining,
+                };
+                if more_restrictive {
+                    binding = Some((limit, decision));
                 }
             }
+
+            let Some((limit, decision)) = binding else {
+                return inner.call(request).await;
+            };
+
+            let mut response = if decision.allowed {
+                inner.call(request).await?
+            } else {
+                tracing::debug!("Rate limit '{}' exceeded by {}", limit.policy.name, caller.bucket(limit.policy.key));
+                AppError::RateLimited.into_response()
+            };
+
+            set_rate_limit_headers(response.headers_mut(), &limit.policy, &decision);
+            Ok(response)
         })
     }
 }
 
-fn extract_ip(request: &Request) -> Option<String> {
-    // First try to get IP from X-Forwarded-For header (for proxies)
-    if let Some(forwarded) = request.headers().get("x-forwarded-for") {
-        if let Ok(forwarded_str) = forwarded.to_str() {
-            if let Some(first_ip) = forwarded_str.split(',').next() {
-                return Some(first_ip.trim().to_string());
+fn set_rate_limit_headers(headers: &mut HeaderMap, policy: &RateLimitPolicy, decision: &RateLimitDecision) {
+    // Header values are rounded up to whole seconds
+    let seconds = |duration: Duration| (duration.as_millis() as u64).div_ceil(1000);
+
+    headers.insert("ratelimit-limit", HeaderValue::from(policy.burst_size));
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(seconds(decision.reset)));
+    if let Ok(value) = HeaderValue::from_str(&format!("{};w=60;burst={}", policy.requests_per_minute, policy.burst_size)) {
+        headers.insert("ratelimit-policy", value);
+    }
+
+    if !decision.allowed {
+        headers.insert("retry-after", HeaderValue::from(seconds(decision.retry_after).max(1)));
+    }
+}
+
+/// Reads a GraphQL POST body, returning the request with the body restored and
+/// the root fields of every operation it contains.
+async fn buffer_graphql_fields(request: Request) -> Result<(Request, HashSet<String>), AppError> {
+    let (parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_GRAPHQL_BODY_BYTES)
+        .await
+        .map_err(|_| AppError::BadRequest("GraphQL request body too large".to_string()))?;
+
+    let mut fields = HashSet::new();
+    if let Ok(payload) = serde_json::from_slice::<serde_json::Value>(&bytes) {
+        // Batched requests are sent as an array of operations
+        let operations = match payload {
+            serde_json::Value::Array(operations) => operations,
+            operation => vec![operation],
+        };
+
+        for query in operations.iter().filter_map(|op| op.get("query").and_then(|q| q.as_str())) {
+            if let Ok(document) = parse_query(query) {
+                for (_, operation) in document.operations.iter() {
+                    collect_root_fields(&document, &operation.node.selection_set.node, &mut HashSet::new(), &mut fields);
+                }
             }
         }
     }
 
-    // Then try X-Real-IP header
-    if let Some(real_ip) = request.headers().get("x-real-ip") {
-        if let Ok(real_ip_str) = real_ip.to_str() {
-            return Some(real_ip_str.to_string());
+    Ok((Request::from_parts(parts, Body::from(bytes)), fields))
+}
+
+// Follows fragments at the root so a policy cannot be bypassed by wrapping a field in one
+fn collect_root_fields(
+    document: &ExecutableDocument,
+    selection_set: &SelectionSet,
+    visited_fragments: &mut HashSet<String>,
+    fields: &mut HashSet<String>,
+) {
+    for selection in &selection_set.items {
+        match &selection.node {
+            Selection::Field(field) => {
+                fields.insert(field.node.name.node.to_string());
+            }
+            Selection::InlineFragment(fragment) => {
+                collect_root_fields(document, &fragment.node.selection_set.node, visited_fragments, fields);
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.node.fragment_name.node.to_string();
+                if !visited_fragments.insert(name.clone()) {
+                    continue;
+                }
+                if let Some(fragment) = document.fragments.get(spread.node.fragment_name.node.as_str()) {
+                    collect_root_fields(document, &fragment.node.selection_set.node, visited_fragments, fields);
+                }
+            }
         }
     }
+}
+
+/// The client address. Forwarding headers are only honoured when the direct
+/// peer is a trusted proxy, and the chain is walked from the right so a client
+/// cannot spoof its address by prepending entries.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> String {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    if !is_trusted(&peer) {
+        return peer.to_string();
+    }
 
-    // Finally, try to get IP from connection info
-    request.extensions()
-        .get::<ConnectInfo<SocketAddr>>()
-        .map(|info| info.0.ip().to_string())
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+
+    if let Some(client) = forwarded.iter().rev().find(|ip| !is_trusted(ip)) {
+        return client.to_string();
+    }
+
+    // Then try X-Real-IP header
+    let real_ip = headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok());
+
+    real_ip.or(forwarded.first().copied()).unwrap_or(peer).to_string()
 }
 
-pub async fn rate_limit_middleware(
-    request: Request,
-    next: Next,
-) -> Result<Response, AppError> {
-    // This is a simple implementation, in production you'd want to use
-    // a more sophisticated rate limiter with Redis backing
-    next.run(request).await.map_err(|e| {
-        tracing::error!("Rate limit middleware error: {:?}", e);
-        AppError::Internal("Rate limit error".to_string())
-    })
-} 
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitKey;
+
+    fn proxies() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn limit(route: Option<&str>, graphql_fields: &[&str]) -> Limit {
+        Limit::new(RateLimitPolicy {
+            name: "test".to_string(),
+            route: route.map(str::to_string),
+            graphql_fields: graphql_fields.iter().map(|field| field.to_string()).collect(),
+            key: RateLimitKey::Ip,
+            requests_per_minute: 60,
+            burst_size: 10,
+        })
+    }
+
+    fn fields(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn root_fields(query: &str) -> HashSet<String> {
+        let document = parse_query(query).unwrap();
+        let mut fields = HashSet::new();
+        for (_, operation) in document.operations.iter() {
+            collect_root_fields(&document, &operation.node.selection_set.node, &mut HashSet::new(), &mut fields);
+        }
+        fields
+    }
+
+    #[test]
+    fn an_untrusted_peer_cannot_spoof_its_address() {
+        let peer: IpAddr = "203.0.113.7".parse().unwrap();
+
+        assert_eq!(client_ip(peer, &forwarded_for("198.51.100.1"), &proxies()), "203.0.113.7");
+    }
+
+    #[test]
+    fn the_forwarded_chain_is_walked_from_the_right_past_trusted_hops() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // The client prepended a fake entry; the first untrusted hop from the right is the real one
+        let headers = forwarded_for("198.51.100.1, 203.0.113.7, 10.0.0.2");
+        assert_eq!(client_ip(peer, &headers, &proxies()), "203.0.113.7");
+
+        // Without forwarding headers the proxy itself is the client
+        assert_eq!(client_ip(peer, &HeaderMap::new(), &proxies()), "10.0.0.1");
+    }
+
+    #[test]
+    fn limits_match_routes_by_path_or_prefix() {
+        assert!(limit(Some("/auth/login"), &[]).matches("/auth/login", &fields(&[])));
+        assert!(!limit(Some("/auth/login"), &[]).matches("/auth/login/other", &fields(&[])));
+        assert!(limit(Some("/auth/*"), &[]).matches("/auth/refresh", &fields(&[])));
+        assert!(!limit(Some("/auth/*"), &[]).matches("/graphql", &fields(&[])));
+
+        // The default limit has neither and applies to everything
+        assert!(limit(None, &[]).matches("/graphql", &fields(&[])));
+    }
+
+    #[test]
+    fn limits_match_graphql_root_fields() {
+        let stats = limit(None, &["inferenceStats"]);
+
+        assert!(stats.matches("/graphql", &fields(&["videoStreams", "inferenceStats"])));
+        assert!(!stats.matches("/graphql", &fields(&["videoStreams"])));
+        assert!(limit(Some("/auth/*"), &["inferenceStats"]).matches("/graphql", &fields(&["inferenceStats"])));
+    }
+
+    #[test]
+    fn root_fields_inside_fragments_are_found() {
+        let query = r#"
+            query { ...Stats ... on Query { videoStreams { id } } }
+            fragment Stats on Query { ...Nested }
+            fragment Nested on Query { inferenceStats(from: "a", to: "b", bucket: HOUR) { count } }
+        "#;
+
+        assert_eq!(root_fields(query), fields(&["inferenceStats", "videoStreams"]));
+    }
+}
@@ -13,6 +13,7 @@ pub struct User {
     #[graphql(skip)]
     pub password_hash: String,
     pub role: UserRole,
+    pub email_verified_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -31,7 +32,16 @@ pub struct CreateUserInput {
     pub email: String,
     #[validate(length(min = 8))]
     pub password: String,
-    pub role: Option<UserRole>,
+}
+
+#[derive(Debug, Deserialize, Validate, InputObject)]
+pub struct InviteUserInput {
+    #[validate(email)]
+    pub email: String,
+    pub role: UserRole,
+    /// Membership role in the active organization; defaults to the one
+    /// matching `role`
+    pub organization_role_id: Option<Uuid>,
 }
 
 #[derive(Debug, Deserialize, Validate, InputObject)]
@@ -43,6 +53,7 @@ pub struct LoginInput {
 
 // Video Stream models
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow, SimpleObject)]
+#[graphql(complex)]
 pub struct VideoStream {
     pub id: Uuid,
     pub name: String,
@@ -50,6 +61,8 @@ pub struct VideoStream {
     pub source_url: Option<String>,
     pub source_type: StreamSourceType,
     pub status: StreamStatus,
+    pub organization_id: Uuid,
+    #[graphql(name = "createdById")]
     pub created_by: Option<Uuid>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
@@ -83,6 +96,7 @@ pub struct CreateVideoStreamInput {
 
 #[derive(Debug, Deserialize, Validate, InputObject)]
 pub struct UpdateVideoStreamInput {
+    #[validate(length(min = 1, max = 255))]
     pub name: Option<String>,
     pub description: Option<String>,
     pub source_url: Option<String>,
@@ -116,6 +130,7 @@ pub struct InferenceModel {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow, SimpleObject)]
+#[graphql(complex)]
 pub struct InferenceResult {
     pub id: Uuid,
     pub stream_id: Uuid,
@@ -129,6 +144,41 @@ pub struct InferenceResult {
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Default, InputObject)]
+pub struct InferenceResultFilter {
+    /// Inclusive
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive
+    pub to: Option<DateTime<Utc>>,
+    /// Results detecting any of these classes
+    pub detected_classes: Option<Vec<String>>,
+    pub min_confidence: Option<f32>,
+    pub max_confidence: Option<f32>,
+    pub model_id: Option<Uuid>,
+    pub min_frame_number: Option<i64>,
+    pub max_frame_number: Option<i64>,
+    /// Results whose bounding box intersects this region
+    pub region: Option<RegionInput>,
+}
+
+/// Rectangle in the coordinates of `bounding_box` (`{x, y, width, height}`)
+#[derive(Debug, Clone, Copy, InputObject)]
+pub struct RegionInput {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Enum)]
+pub enum InferenceResultOrder {
+    #[default]
+    NewestFirst,
+    OldestFirst,
+    HighestConfidence,
+    LowestConfidence,
+}
+
 // Analytics models
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow, SimpleObject)]
 pub struct AnalyticsEvent {
@@ -142,7 +192,54 @@ pub struct AnalyticsEvent {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Enum, Copy, PartialEq, Eq, sqlx::Type)]
+/// Width of the time buckets `inferenceStats` aggregates into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum StatsBucket {
+    Minute,
+    FiveMinutes,
+    FifteenMinutes,
+    Hour,
+    Day,
+    Week,
+}
+
+impl StatsBucket {
+    pub fn seconds(self) -> i64 {
+        match self {
+            StatsBucket::Minute => 60,
+            StatsBucket::FiveMinutes => 5 * 60,
+            StatsBucket::FifteenMinutes => 15 * 60,
+            StatsBucket::Hour => 60 * 60,
+            StatsBucket::Day => 24 * 60 * 60,
+            StatsBucket::Week => 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum StatsGroup {
+    Class,
+    Model,
+}
+
+/// Aggregated inference results of one time bucket, and of one class and/or
+/// model when grouped by them
+#[derive(Debug, Clone, Serialize, FromRow, SimpleObject)]
+pub struct InferenceStats {
+    /// Start of the bucket
+    pub bucket: DateTime<Utc>,
+    /// Set when grouped by `CLASS`
+    pub detected_class: Option<String>,
+    /// Set when grouped by `MODEL`
+    pub model_id: Option<Uuid>,
+    pub detections: i64,
+    pub mean_confidence: f64,
+    pub max_confidence: f64,
+    pub distinct_classes: i64,
+}
+
+// Variants are declared from least to most severe so they can be compared
+#[derive(Debug, Clone, Serialize, Deserialize, Enum, Copy, PartialEq, Eq, PartialOrd, Ord, sqlx::Type)]
 #[sqlx(type_name = "varchar")]
 pub enum EventSeverity {
     Info,
@@ -152,6 +249,7 @@ pub enum EventSeverity {
 
 // Alert models
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow, SimpleObject)]
+#[graphql(complex)]
 pub struct Alert {
     pub id: Uuid,
     pub stream_id: Uuid,
@@ -163,6 +261,7 @@ pub struct Alert {
     pub metadata: Option<serde_json::Value>,
     pub triggered_at: DateTime<Utc>,
     pub acknowledged_at: Option<DateTime<Utc>>,
+    #[graphql(name = "acknowledgedById")]
     pub acknowledged_by: Option<Uuid>,
     pub created_at: DateTime<Utc>,
 }
@@ -182,15 +281,58 @@ pub struct AuthResponse {
     pub refresh_token: String,
     pub user: User,
     pub expires_at: DateTime<Utc>,
+    /// Only set when a login also completed two-factor enrollment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// What `login` answers: tokens, or a challenge for the second factor
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(AuthResponse),
+    TwoFactorRequired { two_factor: TwoFactorChallenge },
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorChallenge {
+    /// Exchanged at `/auth/2fa/verify` together with a code
+    pub challenge_token: String,
+    /// The user's role requires two-factor but it is not set up yet; enroll
+    /// at `/auth/2fa/enroll` first
+    pub enrollment_required: bool,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// An invitation waiting to be accepted
+#[derive(Debug, Serialize, SimpleObject)]
+pub struct Invitation {
+    pub email: String,
+    pub role: UserRole,
+    /// Organization the user joins on accepting, the inviter's active one
+    pub organization_id: Option<Uuid>,
+    pub organization_role_id: Option<Uuid>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, SimpleObject)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    /// `otpauth://` URI to render as a QR code
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub email: String,
     pub role: UserRole,
     pub exp: i64,
     pub iat: i64,
+    /// Identifies this token so it can be denylisted
+    pub jti: Uuid,
+    /// The session family the token was issued for
+    pub sid: Uuid,
 }
 
 // Session models
@@ -198,9 +340,33 @@ pub struct Claims {
 pub struct UserSession {
     pub id: Uuid,
     pub user_id: Uuid,
+    /// Shared by every refresh token descending from the same login
+    pub family_id: Uuid,
     pub token_hash: String,
     pub expires_at: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
+    /// Set once the token has been exchanged for a new one
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    /// The access token issued together with this refresh token
+    pub access_jti: Option<Uuid>,
+    pub access_expires_at: Option<DateTime<Utc>>,
+}
+
+/// A signed-in device, as listed by `mySessions`. Its id is the session family.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, SimpleObject)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    /// Whether this is the session making the request
+    pub current: bool,
+    pub created_at: DateTime<Utc>,
+    /// When the session last refreshed its tokens
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
 }
 
 // API Key models
@@ -208,6 +374,8 @@ pub struct UserSession {
 pub struct ApiKey {
     pub id: Uuid,
     pub user_id: Uuid,
+    /// Keys only work inside the organization they were created in
+    pub organization_id: Uuid,
     pub name: String,
     #[graphql(skip)]
     pub key_hash: String,
@@ -218,6 +386,102 @@ pub struct ApiKey {
     pub created_at: DateTime<Utc>,
 }
 
+impl ApiKey {
+    pub fn permits(&self, permission: &str) -> bool {
+        self.permissions
+            .as_array()
+            .map_or(false, |permissions| permissions.iter().any(|p| p.as_str() == Some(permission)))
+    }
+}
+
+#[derive(Debug, Deserialize, Validate, InputObject)]
+pub struct CreateApiKeyInput {
+    #[validate(length(min = 1, max = 255))]
+    pub name: String,
+    pub permissions: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A newly issued key. The plaintext `key` is only ever returned here.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct CreatedApiKey {
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+// Organization models
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, SimpleObject)]
+pub struct Organization {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Validate, InputObject)]
+pub struct CreateOrganizationInput {
+    #[validate(length(min = 1, max = 255))]
+    pub name: String,
+    #[validate(length(min = 1, max = 100))]
+    pub slug: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, SimpleObject)]
+pub struct OrganizationMember {
+    pub organization_id: Uuid,
+    pub user_id: Uuid,
+    pub email: String,
+    pub role_id: Uuid,
+    pub role: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// Role and permission models
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, SimpleObject)]
+pub struct Role {
+    pub id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub is_system: bool,
+    /// Members must sign in with a second factor
+    pub requires_two_factor: bool,
+    pub permissions: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Validate, InputObject)]
+pub struct CreateRoleInput {
+    #[validate(length(min = 1, max = 100))]
+    pub name: String,
+    pub description: Option<String>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, SimpleObject)]
+pub struct StreamGrant {
+    pub stream_id: Uuid,
+    pub user_id: Uuid,
+    pub permission: String,
+    pub granted_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Real-time session models
+#[derive(Debug, Clone, SimpleObject)]
+pub struct WebSocketSessionInfo {
+    pub id: Uuid,
+    pub user: Option<User>,
+    pub subscriptions: Vec<WebSocketSubscriptionInfo>,
+    pub connected_at: DateTime<Utc>,
+    pub dropped_messages: i64,
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct WebSocketSubscriptionInfo {
+    pub stream_id: Option<Uuid>,
+    pub event_types: Vec<String>,
+}
+
 // Pagination
 #[derive(Debug, InputObject)]
 pub struct PaginationInput {
@@ -239,9 +503,6 @@ pub struct PaginationInfo {
 #[derive(Debug, SimpleObject)]
 #[graphql(concrete(name = "PaginatedUsers", params(User)))]
 #[graphql(concrete(name = "PaginatedVideoStreams", params(VideoStream)))]
-#[graphql(concrete(name = "PaginatedVideoSegments", params(VideoSegment)))]
-#[graphql(concrete(name = "PaginatedInferenceResults", params(InferenceResult)))]
-#[graphql(concrete(name = "PaginatedAlerts", params(Alert)))]
 pub struct PaginatedResponse<T> {
     pub items: Vec<T>,
     pub pagination: PaginationInfo,
@@ -0,0 +1,215 @@
+//! A minimal OpenID Connect provider served by the gateway itself under
+//! `/mock-idp`, so the SSO flow can be exercised locally and in integration
+//! tests without reaching a real identity provider. It signs in configured
+//! users without asking for credentials and must never run in production.
+
+use axum::{
+    extract::{Query, State},
+    response::{Json, Redirect},
+    Form,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use ed25519_dalek::{pkcs8::EncodePrivateKey, SigningKey};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use rand::rngs::OsRng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::{pkce_challenge, random_token};
+use crate::{
+    config::{MockIdpConfig, MockIdpUser},
+    error::{AppError, Result},
+    AppState,
+};
+
+const KEY_ID: &str = "mock-idp";
+
+// Authorization codes are redeemed right after the redirect, as with real providers
+const CODE_TTL_MINUTES: i64 = 5;
+
+struct PendingCode {
+    user: MockIdpUser,
+    issued_at: DateTime<Utc>,
+    client_id: String,
+    redirect_uri: String,
+    nonce: Option<String>,
+    code_challenge: String,
+}
+
+pub struct MockIdp {
+    config: MockIdpConfig,
+    encoding_key: EncodingKey,
+    jwk: Value,
+    codes: Mutex<HashMap<String, PendingCode>>,
+}
+
+impl MockIdp {
+    /// Generates a fresh signing key; tokens from a previous run stop verifying.
+    pub fn new(config: MockIdpConfig) -> Result<Self> {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let der = signing_key
+            .to_pkcs8_der()
+            .map_err(|e| AppError::Internal(format!("Failed to encode mock IdP key: {}", e)))?;
+
+        let jwk = json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "use": "sig",
+            "alg": "EdDSA",
+            "kid": KEY_ID,
+            "x": URL_SAFE_NO_PAD.encode(signing_key.verifying_key().as_bytes()),
+        });
+
+        tracing::warn!("Mock identity provider enabled at {}; do not use in production", config.issuer);
+
+        Ok(Self {
+            config,
+            encoding_key: EncodingKey::from_ed_der(der.as_bytes()),
+            jwk,
+            codes: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+fn mock_idp(state: &AppState) -> Result<&MockIdp> {
+    state.mock_idp
+        .as_deref()
+        .ok_or_else(|| AppError::NotFound("Mock identity provider is disabled".to_string()))
+}
+
+pub async fn discovery(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+) -> Result<Json<Value>> {
+    let issuer = &mock_idp(&state)?.config.issuer;
+
+    Ok(Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "jwks_uri": format!("{}/jwks.json", issuer),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "code_challenge_methods_supported": ["S256"],
+    })))
+}
+
+pub async fn jwks(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+) -> Result<Json<Value>> {
+    Ok(Json(json!({ "keys": [mock_idp(&state)?.jwk] })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizeParams {
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub state: String,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+    pub login_hint: Option<String>,
+}
+
+/// Signs in the user named by `login_hint` (or the first configured user)
+/// and redirects straight back with a code.
+pub async fn authorize(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    Query(params): Query<AuthorizeParams>,
+) -> Result<Redirect> {
+    let mock_idp = mock_idp(&state)?;
+
+    if params.code_challenge_method != "S256" {
+        return Err(AppError::BadRequest("Only S256 code challenges are supported".to_string()));
+    }
+
+    let user = match &params.login_hint {
+        Some(email) => mock_idp.config.users.iter().find(|user| &user.email == email),
+        None => mock_idp.config.users.first(),
+    }
+    .cloned()
+    .ok_or_else(|| AppError::NotFound("Unknown mock user".to_string()))?;
+
+    let code = random_token();
+    let mut codes = mock_idp.codes.lock().await;
+
+    // Codes nobody redeemed would otherwise pile up
+    codes.retain(|_, pending| Utc::now() - pending.issued_at < Duration::minutes(CODE_TTL_MINUTES));
+    codes.insert(code.clone(), PendingCode {
+        user,
+        issued_at: Utc::now(),
+        client_id: params.client_id,
+        redirect_uri: params.redirect_uri.clone(),
+        nonce: params.nonce,
+        code_challenge: params.code_challenge,
+    });
+    drop(codes);
+
+    let mut url = reqwest::Url::parse(&params.redirect_uri)
+        .map_err(|e| AppError::BadRequest(format!("Invalid redirect_uri: {}", e)))?;
+    url.query_pairs_mut()
+        .append_pair("code", &code)
+        .append_pair("state", &params.state);
+
+    Ok(Redirect::to(url.as_str()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenParams {
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: String,
+    pub client_id: String,
+    pub code_verifier: String,
+}
+
+pub async fn token(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    Form(params): Form<TokenParams>,
+) -> Result<Json<Value>> {
+    let mock_idp = mock_idp(&state)?;
+
+    if params.grant_type != "authorization_code" {
+        return Err(AppError::BadRequest("Unsupported grant_type".to_string()));
+    }
+
+    let pending = mock_idp.codes.lock().await.remove(&params.code)
+        .filter(|pending| Utc::now() - pending.issued_at < Duration::minutes(CODE_TTL_MINUTES))
+        .ok_or_else(|| AppError::BadRequest("Invalid authorization code".to_string()))?;
+
+    if pending.client_id != params.client_id
+        || pending.redirect_uri != params.redirect_uri
+        || pending.code_challenge != pkce_challenge(&params.code_verifier)
+    {
+        return Err(AppError::BadRequest("Authorization code does not match the request".to_string()));
+    }
+
+    let now = Utc::now();
+    let claims = json!({
+        "iss": mock_idp.config.issuer,
+        "aud": pending.client_id,
+        // Stable per email, like a real provider's subject
+        "sub": Uuid::new_v5(&Uuid::NAMESPACE_URL, pending.user.email.as_bytes()).to_string(),
+        "email": pending.user.email,
+        "email_verified": true,
+        "groups": pending.user.groups,
+        "nonce": pending.nonce,
+        "iat": now.timestamp(),
+        "exp": (now + Duration::minutes(5)).timestamp(),
+    });
+
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some(KEY_ID.to_string());
+    let id_token = encode(&header, &claims, &mock_idp.encoding_key)?;
+
+    Ok(Json(json!({
+        "access_token": random_token(),
+        "token_type": "Bearer",
+        "expires_in": 300,
+        "id_token": id_token,
+    })))
+}
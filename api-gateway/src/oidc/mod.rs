@@ -0,0 +1,622 @@
+pub mod mock;
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::HeaderMap,
+    response::{Json, Redirect},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::{rngs::OsRng, RngCore};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, net::SocketAddr};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::{
+    auth::{self, SessionClient},
+    config::OidcProviderConfig,
+    error::{AppError, Result},
+    models::{LoginResponse, User, UserRole},
+    two_factor, AppState,
+};
+
+// How long a user has to finish signing in at the provider
+const LOGIN_STATE_TTL_MINUTES: i64 = 10;
+
+// Discovery documents and keys are refetched after this, or sooner when an
+// ID token names a key we do not know yet
+const METADATA_TTL_MINUTES: i64 = 60;
+
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct CachedMetadata {
+    metadata: ProviderMetadata,
+    jwks: JwkSet,
+    fetched_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    nonce: Option<String>,
+    // Holds the configurable groups claim
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+/// Single sign-on through OpenID Connect providers using the authorization
+/// code flow with PKCE. Users are provisioned on their first sign-in.
+pub struct Oidc {
+    http: reqwest::Client,
+    providers: Vec<OidcProviderConfig>,
+    metadata: RwLock<HashMap<String, CachedMetadata>>,
+}
+
+impl Oidc {
+    pub fn new(providers: Vec<OidcProviderConfig>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            providers,
+            metadata: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn provider(&self, name: &str) -> Result<&OidcProviderConfig> {
+        self.providers
+            .iter()
+            .find(|provider| provider.name == name)
+            .ok_or_else(|| AppError::NotFound(format!("Unknown identity provider '{}'", name)))
+    }
+
+    /// Discovery document and keys of a provider, from cache unless stale or `refresh` is set
+    async fn metadata(&self, provider: &OidcProviderConfig, refresh: bool) -> Result<(ProviderMetadata, JwkSet)> {
+        if !refresh {
+            if let Some(cached) = self.metadata.read().await.get(&provider.name) {
+                if Utc::now() - cached.fetched_at < Duration::minutes(METADATA_TTL_MINUTES) {
+                    return Ok((cached.metadata.clone(), cached.jwks.clone()));
+                }
+            }
+        }
+
+        let discovery_url = format!("{}/.well-known/openid-configuration", provider.issuer.trim_end_matches('/'));
+        let metadata: ProviderMetadata = self.http.get(&discovery_url).send().await?.error_for_status()?.json().await?;
+
+        if metadata.issuer != provider.issuer {
+            return Err(AppError::Config(format!(
+                "Identity provider '{}' reports issuer '{}'",
+                provider.name, metadata.issuer
+            )));
+        }
+
+        let jwks: JwkSet = self.http.get(&metadata.jwks_uri).send().await?.error_for_status()?.json().await?;
+
+        self.metadata.write().await.insert(provider.name.clone(), CachedMetadata {
+            metadata: metadata.clone(),
+            jwks: jwks.clone(),
+            fetched_at: Utc::now(),
+        });
+
+        Ok((metadata, jwks))
+    }
+
+    /// Checks the ID token's signature against the provider's JWKS, and its
+    /// issuer, audience, expiry and nonce.
+    async fn validate_id_token(&self, provider: &OidcProviderConfig, id_token: &str, nonce: &str) -> Result<IdTokenClaims> {
+        let header = decode_header(id_token)?;
+
+        // Only asymmetric signatures prove the token came from the provider
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(AppError::Authentication("Unsupported ID token algorithm".to_string()));
+        }
+
+        let kid = header.kid.as_deref()
+            .ok_or_else(|| AppError::Authentication("ID token has no key id".to_string()))?;
+
+        let (_, mut jwks) = self.metadata(provider, false).await?;
+        if jwks.find(kid).is_none() {
+            // The provider may have rotated its keys since we fetched them
+            (_, jwks) = self.metadata(provider, true).await?;
+        }
+
+        let jwk = jwks.find(kid)
+            .ok_or_else(|| AppError::Authentication("ID token signed with an unknown key".to_string()))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&provider.issuer]);
+        validation.set_audience(&[&provider.client_id]);
+
+        let claims = decode::<IdTokenClaims>(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(AppError::Authentication("ID token nonce mismatch".to_string()));
+        }
+
+        Ok(claims)
+    }
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// The most privileged role any of the user's groups maps to, if any does
+fn map_role(provider: &OidcProviderConfig, groups: &[String]) -> Option<UserRole> {
+    let rank = |role: &UserRole| match role {
+        UserRole::Admin => 2,
+        UserRole::User => 1,
+        UserRole::Viewer => 0,
+    };
+
+    groups
+        .iter()
+        .filter_map(|group| provider.role_mapping.get(group))
+        .copied()
+        .max_by_key(rank)
+}
+
+fn groups(provider: &OidcProviderConfig, claims: &IdTokenClaims) -> Vec<String> {
+    match claims.other.get(&provider.groups_claim) {
+        Some(Value::Array(groups)) => groups.iter().filter_map(|group| group.as_str().map(str::to_string)).collect(),
+        Some(Value::String(group)) => vec![group.clone()],
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParams {
+    /// Passed on to the provider to preselect an account
+    pub login_hint: Option<String>,
+}
+
+/// Starts a sign-in by redirecting to the provider's authorization endpoint.
+pub async fn login(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    Path(provider_name): Path<String>,
+    Query(params): Query<LoginParams>,
+) -> Result<Redirect> {
+    let provider = state.oidc.provider(&provider_name)?;
+    let (metadata, _) = state.oidc.metadata(provider, false).await?;
+
+    let login_state = random_token();
+    let code_verifier = random_token();
+    let nonce = random_token();
+
+    sqlx::query(&format!(
+        "DELETE FROM oidc_login_states WHERE created_at < NOW() - INTERVAL '{} minutes'",
+        LOGIN_STATE_TTL_MINUTES
+    ))
+    .execute(state.db.pool())
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO oidc_login_states (state, provider, code_verifier, nonce) VALUES ($1, $2, $3, $4)"
+    )
+    .bind(&login_state)
+    .bind(&provider.name)
+    .bind(&code_verifier)
+    .bind(&nonce)
+    .execute(state.db.pool())
+    .await?;
+
+    let mut url = reqwest::Url::parse(&metadata.authorization_endpoint)
+        .map_err(|e| AppError::Config(format!("Invalid authorization endpoint: {}", e)))?;
+
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", &provider.redirect_uri)
+        .append_pair("scope", &provider.scopes.join(" "))
+        .append_pair("state", &login_state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &pkce_challenge(&code_verifier))
+        .append_pair("code_challenge_method", "S256");
+
+    if let Some(login_hint) = &params.login_hint {
+        url.query_pairs_mut().append_pair("login_hint", login_hint);
+    }
+
+    Ok(Redirect::to(url.as_str()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackParams {
+    pub code: Option<String>,
+    pub state: String,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+/// Completes a sign-in: exchanges the code, validates the ID token, provisions
+/// the user if needed and answers as `/auth/login` does, with tokens or a
+/// two-factor challenge.
+pub async fn callback(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    Path(provider_name): Path<String>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<CallbackParams>,
+) -> Result<Json<LoginResponse>> {
+    let provider = state.oidc.provider(&provider_name)?;
+
+    // Each state is good for one attempt
+    let (code_verifier, nonce): (String, String) = sqlx::query_as(&format!(
+        r#"
+        DELETE FROM oidc_login_states
+        WHERE state = $1 AND provider = $2 AND created_at > NOW() - INTERVAL '{} minutes'
+        RETURNING code_verifier, nonce
+        "#,
+        LOGIN_STATE_TTL_MINUTES
+    ))
+    .bind(&params.state)
+    .bind(&provider.name)
+    .fetch_optional(state.db.pool())
+    .await?
+    .ok_or_else(|| AppError::Authentication("Invalid or expired sign-in state".to_string()))?;
+
+    if let Some(error) = &params.error {
+        return Err(AppError::Authentication(format!(
+            "Identity provider returned '{}': {}",
+            error,
+            params.error_description.as_deref().unwrap_or("no description")
+        )));
+    }
+
+    let code = params.code.as_deref()
+        .ok_or_else(|| AppError::BadRequest("Missing authorization code".to_string()))?;
+
+    let (metadata, _) = state.oidc.metadata(provider, false).await?;
+
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", provider.redirect_uri.as_str()),
+        ("client_id", provider.client_id.as_str()),
+        ("code_verifier", code_verifier.as_str()),
+    ];
+    if let Some(client_secret) = &provider.client_secret {
+        form.push(("client_secret", client_secret.as_str()));
+    }
+
+    let response = state.oidc.http.post(&metadata.token_endpoint).form(&form).send().await?;
+    if !response.status().is_success() {
+        tracing::warn!("Token exchange with '{}' failed with {}", provider.name, response.status());
+        return Err(AppError::Authentication("Authorization code was rejected".to_string()));
+    }
+    let tokens: TokenResponse = response.json().await?;
+
+    let claims = state.oidc.validate_id_token(provider, &tokens.id_token, &nonce).await?;
+    let user = provision_user(&state, provider, &claims).await?;
+
+    // The provider may not ask for a second factor, so users who enabled
+    // two-factor here, or whose role requires it, still have to pass it
+    if let Some(challenge) = two_factor::challenge(&state, &user).await? {
+        return Ok(Json(LoginResponse::TwoFactorRequired { two_factor: challenge }));
+    }
+
+    tracing::info!("User logged in through {}: {}", provider.name, user.email);
+
+    let client = SessionClient::new(&state, peer, &headers);
+    let response = auth::issue_tokens(state.db.pool(), &state, user, Uuid::new_v4(), &client).await?;
+
+    Ok(Json(LoginResponse::Authenticated(response)))
+}
+
+/// Finds the user behind an identity, linking or creating an account on first
+/// sign-in. New accounts get the role their groups map to, or the provider's
+/// default. Existing accounts keep theirs unless a group maps to a role.
+async fn provision_user(state: &AppState, provider: &OidcProviderConfig, claims: &IdTokenClaims) -> Result<User> {
+    let mapped_role = map_role(provider, &groups(provider, claims));
+    let mut tx = state.db.pool().begin().await?;
+
+    let existing: Option<Uuid> = sqlx::query_scalar(
+        "UPDATE user_identities SET last_login_at = NOW() WHERE provider = $1 AND subject = $2 RETURNING user_id"
+    )
+    .bind(&provider.name)
+    .bind(&claims.sub)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user_id = match existing {
+        Some(user_id) => user_id,
+        None => {
+            let email = claims.email.as_deref()
+                .ok_or_else(|| AppError::Authentication("Identity provider did not share an email".to_string()))?;
+
+            let linked: Option<Uuid> = sqlx::query_scalar("SELECT id FROM users WHERE email = $1")
+                .bind(email)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+            // Only a verified email proves the account belongs to the same person
+            let email_verified = provider.trust_email || claims.email_verified == Some(true);
+
+            let user_id = match linked {
+                Some(_) if !email_verified => {
+                    return Err(AppError::Conflict(
+                        "An account with this email exists but the provider did not verify the email".to_string(),
+                    ));
+                }
+                Some(user_id) => user_id,
+                None => create_user(&mut tx, state, provider, email, mapped_role.unwrap_or(provider.default_role)).await?,
+            };
+
+            sqlx::query(
+                "INSERT INTO user_identities (provider, subject, user_id, email, last_login_at) VALUES ($1, $2, $3, $4, NOW())"
+            )
+            .bind(&provider.name)
+            .bind(&claims.sub)
+            .bind(user_id)
+            .bind(email)
+            .execute(&mut *tx)
+            .await?;
+
+            user_id
+        }
+    };
+
+    // An email the provider verified counts as verified here too
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users SET role = COALESCE($1, role), updated_at = NOW(),
+            email_verified_at = CASE WHEN $3 THEN COALESCE(email_verified_at, NOW()) ELSE email_verified_at END
+        WHERE id = $2
+        RETURNING *
+        "#,
+    )
+    .bind(mapped_role)
+    .bind(user_id)
+    .bind(provider.trust_email || claims.email_verified == Some(true))
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(user)
+}
+
+async fn create_user(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    state: &AppState,
+    provider: &OidcProviderConfig,
+    email: &str,
+    role: UserRole,
+) -> Result<Uuid> {
+    // Nobody knows this password, so the account can only sign in through the provider
+    let password_hash = state.password_hasher.hash(&random_token()).await?;
+
+    let user_id: Uuid = sqlx::query_scalar(
+        r#"
+        INSERT INTO users (id, email, password_hash, role, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, NOW(), NOW())
+        RETURNING id
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(email)
+    .bind(&password_hash)
+    .bind(role)
+    .fetch_one(&mut **tx)
+    .await?;
+
+    if let Some(slug) = &provider.organization {
+        sqlx::query(
+            r#"
+            INSERT INTO organization_members (organization_id, user_id, role_id)
+            SELECT o.id, $2, r.id
+            FROM organizations o
+            JOIN roles r ON r.name = CASE $3 WHEN 'admin' THEN 'owner' ELSE $3 END
+            WHERE o.slug = $1
+            "#,
+        )
+        .bind(slug)
+        .bind(user_id)
+        .bind(format!("{:?}", role).to_lowercase())
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    tracing::info!("User provisioned through {}: {}", provider.name, email);
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::{MockIdpConfig, MockIdpUser}, test_support};
+    use reqwest::{redirect::Policy, StatusCode};
+    use sqlx::PgPool;
+    use std::sync::Arc;
+
+    /// Serves the gateway with the mock IdP signing in `users` and a `mock`
+    /// provider pointing at it; returns the base URL.
+    async fn serve(pool: PgPool, users: Vec<MockIdpUser>) -> (AppState, String) {
+        let (base_url, listener) = test_support::listen().await;
+        let mut state = test_support::state(pool).await;
+
+        let issuer = format!("{}/mock-idp", base_url);
+        let mock_idp = MockIdpConfig { issuer: issuer.clone(), users };
+        let provider = OidcProviderConfig {
+            name: "mock".to_string(),
+            issuer,
+            client_id: "gateway".to_string(),
+            client_secret: None,
+            redirect_uri: format!("{}/auth/oidc/mock/callback", base_url),
+            scopes: vec!["openid".to_string(), "email".to_string()],
+            groups_claim: "groups".to_string(),
+            role_mapping: HashMap::from([("coaches".to_string(), UserRole::User)]),
+            default_role: UserRole::Viewer,
+            trust_email: false,
+            organization: Some("default".to_string()),
+        };
+
+        state.oidc = Arc::new(Oidc::new(vec![provider.clone()]));
+        state.mock_idp = Some(Arc::new(mock::MockIdp::new(mock_idp.clone()).unwrap()));
+        state.config.oidc.providers = vec![provider];
+        state.config.oidc.mock_idp = Some(mock_idp);
+
+        test_support::serve(state.clone(), listener).await;
+        (state, base_url)
+    }
+
+    fn mock_user(email: &str, groups: &[&str]) -> MockIdpUser {
+        MockIdpUser {
+            email: email.to_string(),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        }
+    }
+
+    /// Runs the whole redirect chain for `email` and returns the callback's answer.
+    async fn sign_in(base_url: &str, email: &str) -> (StatusCode, Value) {
+        let response = reqwest::get(format!("{}/auth/oidc/mock/login?login_hint={}", base_url, email))
+            .await
+            .unwrap();
+        (response.status(), response.json().await.unwrap())
+    }
+
+    /// Where a redirect points
+    fn redirect_url(response: &reqwest::Response) -> reqwest::Url {
+        let location = response.headers()[reqwest::header::LOCATION].to_str().unwrap();
+        reqwest::Url::parse(location).unwrap()
+    }
+
+    async fn user_role(state: &AppState, email: &str) -> UserRole {
+        sqlx::query_scalar("SELECT role FROM users WHERE email = $1")
+            .bind(email)
+            .fetch_one(state.db.pool())
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn first_sign_in_provisions_a_user_with_the_mapped_role(pool: PgPool) {
+        let (state, base_url) = serve(pool, vec![mock_user("coach@example.com", &["coaches"])]).await;
+
+        let (status, body) = sign_in(&base_url, "coach@example.com").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["access_token"].is_string());
+        assert_eq!(body["user"]["email"], "coach@example.com");
+        assert_eq!(user_role(&state, "coach@example.com").await, UserRole::User);
+
+        let in_default_organization: bool = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM organization_members m
+                JOIN organizations o ON o.id = m.organization_id
+                JOIN users u ON u.id = m.user_id
+                WHERE o.slug = 'default' AND u.email = $1
+            )
+            "#,
+        )
+        .bind("coach@example.com")
+        .fetch_one(state.db.pool())
+        .await
+        .unwrap();
+        assert!(in_default_organization);
+    }
+
+    #[sqlx::test]
+    async fn sign_in_links_an_existing_account_and_keeps_its_role(pool: PgPool) {
+        let (state, base_url) = serve(pool, vec![mock_user("lead@example.com", &[])]).await;
+
+        let user_id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO users (id, email, password_hash, role, created_at, updated_at)
+            VALUES ($1, 'lead@example.com', 'not a hash', $2, NOW(), NOW())
+            RETURNING id
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(UserRole::Admin)
+        .fetch_one(state.db.pool())
+        .await
+        .unwrap();
+
+        for _ in 0..2 {
+            let (status, body) = sign_in(&base_url, "lead@example.com").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["user"]["id"], user_id.to_string());
+        }
+
+        // No group maps to a role, so the provider's default must not demote the account
+        assert_eq!(user_role(&state, "lead@example.com").await, UserRole::Admin);
+
+        let identities: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_identities WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(state.db.pool())
+            .await
+            .unwrap();
+        assert_eq!(identities, 1);
+    }
+
+    #[sqlx::test]
+    async fn callback_rejects_unknown_and_reused_states(pool: PgPool) {
+        let (_, base_url) = serve(pool, vec![mock_user("coach@example.com", &["coaches"])]).await;
+        let client = reqwest::Client::builder().redirect(Policy::none()).build().unwrap();
+
+        let response = client
+            .get(format!("{}/auth/oidc/mock/callback?code=made-up&state=made-up", base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let login = client.get(format!("{}/auth/oidc/mock/login", base_url)).send().await.unwrap();
+        let authorize = client.get(redirect_url(&login)).send().await.unwrap();
+        let callback = redirect_url(&authorize);
+
+        assert_eq!(client.get(callback.clone()).send().await.unwrap().status(), StatusCode::OK);
+        assert_eq!(client.get(callback).send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn callback_fails_when_the_code_verifier_does_not_match(pool: PgPool) {
+        let (state, base_url) = serve(pool, vec![mock_user("coach@example.com", &["coaches"])]).await;
+        let client = reqwest::Client::builder().redirect(Policy::none()).build().unwrap();
+
+        let login = client.get(format!("{}/auth/oidc/mock/login", base_url)).send().await.unwrap();
+        let authorize = client.get(redirect_url(&login)).send().await.unwrap();
+        let callback = redirect_url(&authorize);
+
+        let login_state = callback.query_pairs().find(|(key, _)| key == "state").unwrap().1.into_owned();
+        sqlx::query("UPDATE oidc_login_states SET code_verifier = $2 WHERE state = $1")
+            .bind(&login_state)
+            .bind(random_token())
+            .execute(state.db.pool())
+            .await
+            .unwrap();
+
+        let response = client.get(callback).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE email = 'coach@example.com'")
+            .fetch_one(state.db.pool())
+            .await
+            .unwrap();
+        assert_eq!(users, 0);
+    }
+}
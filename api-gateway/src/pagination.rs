@@ -0,0 +1,412 @@
+use async_graphql::{
+    connection::{Connection, CursorType, Edge},
+    Context, OutputType, SimpleObject,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::{postgres::PgRow, FromRow, PgPool, Postgres, QueryBuilder};
+use uuid::Uuid;
+
+use crate::{
+    error::{AppError, Result},
+    query::{self, Filter},
+    models::{Alert, AnalyticsEvent, InferenceResult, VideoSegment},
+};
+
+/// Value of the sort column a cursor points at
+#[derive(Debug, Clone, Copy)]
+pub enum CursorValue {
+    Time(DateTime<Utc>),
+    Number(f64),
+}
+
+/// Opaque position in a list ordered by `(sort column, id)`. Unlike an
+/// offset it keeps pointing at the same row while new rows arrive.
+#[derive(Debug, Clone)]
+pub struct KeysetCursor {
+    /// Cursors only make sense for the order they were issued in
+    pub column: String,
+    pub value: CursorValue,
+    pub id: Uuid,
+}
+
+impl CursorType for KeysetCursor {
+    type Error = AppError;
+
+    fn decode_cursor(s: &str) -> Result<Self> {
+        let invalid = || AppError::BadRequest("Invalid cursor".to_string());
+
+        let decoded = URL_SAFE_NO_PAD.decode(s).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+
+        let mut parts = decoded.splitn(3, ':');
+        let (Some(column), Some(value), Some(id)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+
+        let value = if let Some(micros) = value.strip_prefix('t') {
+            let micros: i64 = micros.parse().map_err(|_| invalid())?;
+
+            // Postgres keeps microseconds, so the round trip is exact
+            let timestamp = Utc
+                .timestamp_opt(micros.div_euclid(1_000_000), (micros.rem_euclid(1_000_000) * 1_000) as u32)
+                .single()
+                .ok_or_else(invalid)?;
+            CursorValue::Time(timestamp)
+        } else if let Some(number) = value.strip_prefix('n') {
+            CursorValue::Number(number.parse().map_err(|_| invalid())?)
+        } else {
+            return Err(invalid());
+        };
+
+        Ok(Self {
+            column: column.to_string(),
+            value,
+            id: Uuid::parse_str(id).map_err(|_| invalid())?,
+        })
+    }
+
+    fn encode_cursor(&self) -> String {
+        let value = match self.value {
+            CursorValue::Time(timestamp) => format!("t{}", timestamp.timestamp_micros()),
+            CursorValue::Number(number) => format!("n{}", number),
+        };
+
+        URL_SAFE_NO_PAD.encode(format!("{}:{}:{}", self.column, value, self.id))
+    }
+}
+
+#[derive(Debug, SimpleObject)]
+pub struct ConnectionCount {
+    /// Rows matching the filters. Only counted when selected, which is slow
+    /// on long time ranges.
+    pub total_count: Option<i64>,
+}
+
+pub type KeysetConnection<T> = Connection<KeysetCursor, T, ConnectionCount>;
+
+/// Relay connection arguments
+#[derive(Debug, Default)]
+pub struct PageArgs {
+    pub after: Option<String>,
+    pub before: Option<String>,
+    pub first: Option<i32>,
+    pub last: Option<i32>,
+}
+
+/// Rows listed with a `KeysetCursor`; their id breaks ties in the sort order
+pub trait Keyed {
+    fn id(&self) -> Uuid;
+}
+
+impl Keyed for InferenceResult {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+impl Keyed for VideoSegment {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+impl Keyed for AnalyticsEvent {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+impl Keyed for Alert {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+/// Column a list is ordered by, with `id` breaking ties
+pub struct SortKey<T> {
+    pub column: &'static str,
+    pub descending: bool,
+    /// Reads the column's value from a row, for its cursor
+    pub value: fn(&T) -> CursorValue,
+}
+
+pub struct Keyset<T> {
+    pub table: &'static str,
+    pub sort: SortKey<T>,
+    pub default_size: i32,
+    pub max_size: i32,
+}
+
+fn push_bind_value(query: &mut QueryBuilder<'static, Postgres>, value: CursorValue) {
+    match value {
+        CursorValue::Time(timestamp) => query.push_bind(timestamp),
+        CursorValue::Number(number) => query.push_bind(number),
+    };
+}
+
+/// Fetches one page of `keyset` matching `filter`. Pages start from a keyset
+/// condition instead of an offset, so deep pages cost the same as the first.
+pub async fn keyset_connection<T>(
+    ctx: &Context<'_>,
+    pool: &PgPool,
+    keyset: Keyset<T>,
+    filter: &Filter,
+    args: PageArgs,
+) -> Result<KeysetConnection<T>>
+where
+    T: for<'r> FromRow<'r, PgRow> + Keyed + OutputType + Send + Unpin,
+{
+    if args.first.is_some() && args.last.is_some() {
+        return Err(AppError::BadRequest("Pass either first or last, not both".to_string()));
+    }
+
+    let sort = &keyset.sort;
+    let decode = |cursor: &str| -> Result<KeysetCursor> {
+        let cursor = KeysetCursor::decode_cursor(cursor)?;
+        if cursor.column != sort.column {
+            return Err(AppError::BadRequest("Cursor belongs to another sort order".to_string()));
+        }
+        Ok(cursor)
+    };
+    let after = args.after.as_deref().map(decode).transpose()?;
+    let before = args.before.as_deref().map(decode).transpose()?;
+
+    let backward = args.last.is_some();
+    let size = args.last.or(args.first).unwrap_or(keyset.default_size);
+    if size < 0 {
+        return Err(AppError::BadRequest("first and last cannot be negative".to_string()));
+    }
+    let size = size.min(keyset.max_size) as usize;
+
+    let mut page = query::select(keyset.table, filter);
+    let mut connector = if filter.is_empty() { " WHERE " } else { " AND " };
+
+    // Rows after a cursor come later in the sort order
+    let (after_operator, before_operator) = if sort.descending { ("<", ">") } else { (">", "<") };
+    for (cursor, operator) in [(&after, after_operator), (&before, before_operator)] {
+        if let Some(cursor) = cursor {
+            page.push(format!("{}({}, id) {} (", connector, sort.column, operator));
+            push_bind_value(&mut page, cursor.value);
+            page.push(", ").push_bind(cursor.id).push(")");
+            connector = " AND ";
+        }
+    }
+
+    // Paging backward reads the order reversed and flips the rows afterwards
+    let direction = if sort.descending != backward { "DESC" } else { "ASC" };
+    page.push(format!(" ORDER BY {} {}, id {} LIMIT ", sort.column, direction, direction))
+        .push_bind(size as i64 + 1);
+
+    let mut rows: Vec<T> = page.build_query_as().fetch_all(pool).await?;
+
+    // The extra row only tells whether there is more
+    let has_more = rows.len() > size;
+    rows.truncate(size);
+    if backward {
+        rows.reverse();
+    }
+
+    let (has_previous_page, has_next_page) = if backward {
+        (has_more, before.is_some())
+    } else {
+        (after.is_some(), has_more)
+    };
+
+    let total_count = if ctx.look_ahead().field("totalCount").exists() {
+        Some(query::count(keyset.table, filter).build_query_scalar::<i64>().fetch_one(pool).await?)
+    } else {
+        None
+    };
+
+    let mut connection = Connection::with_additional_fields(has_previous_page, has_next_page, ConnectionCount { total_count });
+    connection.edges.extend(rows.into_iter().map(|row| {
+        let cursor = KeysetCursor {
+            column: sort.column.to_string(),
+            value: (sort.value)(&row),
+            id: row.id(),
+        };
+        Edge::new(cursor, row)
+    }));
+
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Schema};
+    use chrono::Duration;
+
+    #[derive(Debug, FromRow, SimpleObject)]
+    struct Item {
+        id: Uuid,
+        timestamp: DateTime<Utc>,
+        confidence: f32,
+    }
+
+    impl Keyed for Item {
+        fn id(&self) -> Uuid {
+            self.id
+        }
+    }
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        /// Newest first, like the stream lists, or by ascending confidence
+        async fn items(
+            &self,
+            ctx: &Context<'_>,
+            #[graphql(default)] by_confidence: bool,
+            after: Option<String>,
+            before: Option<String>,
+            first: Option<i32>,
+            last: Option<i32>,
+        ) -> Result<KeysetConnection<Item>> {
+            let sort = if by_confidence {
+                SortKey { column: "confidence", descending: false, value: |item: &Item| CursorValue::Number(item.confidence as f64) }
+            } else {
+                SortKey { column: "timestamp", descending: true, value: |item: &Item| CursorValue::Time(item.timestamp) }
+            };
+            let keyset = Keyset { table: "items", sort, default_size: 2, max_size: 10 };
+
+            keyset_connection(ctx, ctx.data_unchecked::<PgPool>(), keyset, &Filter::new(), PageArgs { after, before, first, last }).await
+        }
+    }
+
+    struct Page {
+        ids: Vec<String>,
+        has_previous_page: bool,
+        has_next_page: bool,
+        start_cursor: String,
+        end_cursor: String,
+    }
+
+    fn id(n: u128) -> String {
+        Uuid::from_u128(n).to_string()
+    }
+
+    /// Items 1-3 share the older timestamp and 4-5 the newer one, so newest
+    /// first is 5, 4, 3, 2, 1 and only the id tells equal timestamps apart.
+    async fn items(pool: &PgPool) {
+        sqlx::query("CREATE TABLE items (id UUID PRIMARY KEY, timestamp TIMESTAMPTZ NOT NULL, confidence REAL NOT NULL)")
+            .execute(pool)
+            .await
+            .unwrap();
+
+        let older = Utc::now() - Duration::minutes(1);
+        let newer = Utc::now();
+        for (n, timestamp, confidence) in [(1, older, 0.1f32), (2, older, 0.1), (3, older, 0.3), (4, newer, 0.7), (5, newer, 0.1)] {
+            sqlx::query("INSERT INTO items (id, timestamp, confidence) VALUES ($1, $2, $3)")
+                .bind(Uuid::from_u128(n))
+                .bind(timestamp)
+                .bind(confidence)
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn page(pool: &PgPool, args: &str) -> std::result::Result<Page, String> {
+        let schema = Schema::build(Query, EmptyMutation, EmptySubscription).data(pool.clone()).finish();
+        let response = schema
+            .execute(format!(
+                "{{ items({}) {{ edges {{ node {{ id }} }} pageInfo {{ hasPreviousPage hasNextPage startCursor endCursor }} }} }}",
+                args
+            ))
+            .await;
+        if let Some(error) = response.errors.first() {
+            return Err(error.message.clone());
+        }
+
+        let items = &response.data.into_json().unwrap()["items"];
+        let page_info = &items["pageInfo"];
+        Ok(Page {
+            ids: items["edges"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|edge| edge["node"]["id"].as_str().unwrap().to_string())
+                .collect(),
+            has_previous_page: page_info["hasPreviousPage"].as_bool().unwrap(),
+            has_next_page: page_info["hasNextPage"].as_bool().unwrap(),
+            start_cursor: page_info["startCursor"].as_str().map(str::to_string).unwrap_or_default(),
+            end_cursor: page_info["endCursor"].as_str().map(str::to_string).unwrap_or_default(),
+        })
+    }
+
+    #[sqlx::test]
+    async fn paging_forward_visits_rows_with_equal_timestamps_once(pool: PgPool) {
+        items(&pool).await;
+
+        let first = page(&pool, "first: 2").await.unwrap();
+        assert_eq!(first.ids, vec![id(5), id(4)]);
+        assert!(!first.has_previous_page && first.has_next_page);
+
+        let second = page(&pool, &format!("first: 2, after: \"{}\"", first.end_cursor)).await.unwrap();
+        assert_eq!(second.ids, vec![id(3), id(2)]);
+        assert!(second.has_previous_page && second.has_next_page);
+
+        let last = page(&pool, &format!("first: 2, after: \"{}\"", second.end_cursor)).await.unwrap();
+        assert_eq!(last.ids, vec![id(1)]);
+        assert!(last.has_previous_page && !last.has_next_page);
+    }
+
+    #[sqlx::test]
+    async fn paging_backward_visits_rows_with_equal_timestamps_once(pool: PgPool) {
+        items(&pool).await;
+
+        let last = page(&pool, "last: 2").await.unwrap();
+        assert_eq!(last.ids, vec![id(2), id(1)]);
+        assert!(last.has_previous_page && !last.has_next_page);
+
+        let second = page(&pool, &format!("last: 2, before: \"{}\"", last.start_cursor)).await.unwrap();
+        assert_eq!(second.ids, vec![id(4), id(3)]);
+        assert!(second.has_previous_page && second.has_next_page);
+
+        let first = page(&pool, &format!("last: 2, before: \"{}\"", second.start_cursor)).await.unwrap();
+        assert_eq!(first.ids, vec![id(5)]);
+        assert!(!first.has_previous_page && first.has_next_page);
+    }
+
+    #[sqlx::test]
+    async fn a_cursor_from_another_sort_order_is_rejected(pool: PgPool) {
+        items(&pool).await;
+
+        let by_time = page(&pool, "first: 1").await.unwrap();
+        let error = page(&pool, &format!("byConfidence: true, first: 1, after: \"{}\"", by_time.end_cursor))
+            .await
+            .err()
+            .unwrap();
+        assert!(error.contains("Cursor belongs to another sort order"), "{}", error);
+    }
+
+    #[sqlx::test]
+    async fn confidence_cursors_round_trip_exactly(pool: PgPool) {
+        items(&pool).await;
+
+        // 0.1 has no exact REAL, so a cursor that rounded it would skip or repeat the ties
+        let cursor = KeysetCursor { column: "confidence".to_string(), value: CursorValue::Number(0.1f32 as f64), id: Uuid::from_u128(1) };
+        let decoded = KeysetCursor::decode_cursor(&cursor.encode_cursor()).unwrap();
+        assert!(matches!(decoded.value, CursorValue::Number(number) if number == 0.1f32 as f64));
+
+        let mut ids = Vec::new();
+        let mut after = String::new();
+        loop {
+            let args = if after.is_empty() {
+                "byConfidence: true, first: 1".to_string()
+            } else {
+                format!("byConfidence: true, first: 1, after: \"{}\"", after)
+            };
+            let page = page(&pool, &args).await.unwrap();
+            ids.extend(page.ids);
+            if !page.has_next_page {
+                break;
+            }
+            after = page.end_cursor;
+        }
+        assert_eq!(ids, vec![id(1), id(2), id(5), id(3), id(4)]);
+    }
+}
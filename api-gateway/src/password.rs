@@ -0,0 +1,210 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use std::{
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{AuthConfig, PasswordAlgorithm},
+    database::Database,
+    error::{AppError, Result},
+};
+
+const DUMMY_PASSWORD: &str = "not a real password";
+
+#[derive(Debug, Clone, Copy)]
+pub struct Verification {
+    pub valid: bool,
+    /// The hash uses another algorithm or weaker parameters than configured
+    /// and should be replaced while the password is at hand
+    pub needs_rehash: bool,
+}
+
+/// Hashes passwords with the configured algorithm and verifies both bcrypt
+/// and Argon2id hashes, so accounts move to new settings as users log in.
+/// Hashing is slow on purpose and runs on the blocking thread pool.
+pub struct PasswordHasher {
+    algorithm: PasswordAlgorithm,
+    bcrypt_cost: u32,
+    argon2_params: Params,
+    /// Verified against when the user does not exist, so an unknown email
+    /// takes as long as a wrong password. See `calibrate_dummy_hash`.
+    dummy_hash: String,
+}
+
+impl PasswordHasher {
+    pub fn new(config: &AuthConfig) -> Result<Self> {
+        let argon2_params = Params::new(
+            config.argon2_memory_kib,
+            config.argon2_iterations,
+            config.argon2_parallelism,
+            None,
+        )
+        .map_err(|e| AppError::Config(format!("Invalid Argon2 parameters: {}", e)))?;
+
+        let mut hasher = Self {
+            algorithm: config.password_algorithm,
+            bcrypt_cost: config.bcrypt_cost,
+            argon2_params,
+            dummy_hash: String::new(),
+        };
+        hasher.dummy_hash = hasher.hash_blocking(DUMMY_PASSWORD)?;
+
+        Ok(hasher)
+    }
+
+    /// Accounts keep their hash until their user logs in again, so the table
+    /// may hold bcrypt hashes or stronger parameters than configured. The
+    /// dummy hash is replaced by one as slow to verify as the slowest kind
+    /// of hash stored, or an unknown email would answer measurably faster.
+    pub async fn calibrate_dummy_hash(&mut self, db: &Database) -> Result<()> {
+        // One stored hash per algorithm and parameter set
+        let stored: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT ON (scheme) password_hash
+            FROM (
+                SELECT password_hash, COALESCE(
+                    substring(password_hash FROM '^\$argon2[^$]*\$[^$]*\$[^$]*'),
+                    substring(password_hash FROM '^\$2[a-z]?\$[0-9]+')
+                ) AS scheme
+                FROM users
+            ) hashes
+            WHERE scheme IS NOT NULL
+            "#,
+        )
+        .fetch_all(db.pool())
+        .await?;
+
+        let mut slowest = self.verification_time(&self.dummy_hash);
+        for hash in stored {
+            let Some(dummy_hash) = self.dummy_hash_like(&hash) else {
+                continue;
+            };
+
+            let elapsed = self.verification_time(&dummy_hash);
+            if elapsed > slowest {
+                slowest = elapsed;
+                self.dummy_hash = dummy_hash;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hashes the dummy password with the algorithm and parameters of `hash`
+    fn dummy_hash_like(&self, hash: &str) -> Option<String> {
+        if hash.starts_with("$argon2") {
+            let parsed = PasswordHash::new(hash).ok()?;
+            let algorithm = Algorithm::try_from(parsed.algorithm).ok()?;
+            let version = Version::try_from(parsed.version.unwrap_or(Version::V0x13 as u32)).ok()?;
+            let params = Params::try_from(&parsed).ok()?;
+            let salt = SaltString::generate(&mut OsRng);
+
+            return Argon2::new(algorithm, version, params)
+                .hash_password(DUMMY_PASSWORD.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .ok();
+        }
+
+        let cost = hash.split('$').nth(2)?.parse().ok()?;
+        bcrypt::hash(DUMMY_PASSWORD, cost).ok()
+    }
+
+    fn verification_time(&self, hash: &str) -> Duration {
+        let started = Instant::now();
+        let _ = self.verify_blocking("wrong password", hash);
+        started.elapsed()
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.argon2_params.clone())
+    }
+
+    fn hash_blocking(&self, password: &str) -> Result<String> {
+        match self.algorithm {
+            PasswordAlgorithm::Argon2id => {
+                let salt = SaltString::generate(&mut OsRng);
+                self.argon2()
+                    .hash_password(password.as_bytes(), &salt)
+                    .map(|hash| hash.to_string())
+                    .map_err(|e| AppError::Internal(format!("Failed to hash password: {}", e)))
+            }
+            PasswordAlgorithm::Bcrypt => bcrypt::hash(password, self.bcrypt_cost)
+                .map_err(|e| AppError::Internal(format!("Failed to hash password: {}", e))),
+        }
+    }
+
+    fn verify_blocking(&self, password: &str, hash: &str) -> Result<Verification> {
+        if hash.starts_with("$argon2") {
+            let parsed = match PasswordHash::new(hash) {
+                Ok(parsed) => parsed,
+                Err(e) => return self.reject_malformed(password, e),
+            };
+
+            // The parameters are read from the hash itself
+            let valid = Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok();
+
+            let needs_rehash = self.algorithm != PasswordAlgorithm::Argon2id
+                || parsed.algorithm != Algorithm::Argon2id.ident()
+                || Params::try_from(&parsed).map_or(true, |params| {
+                    params.m_cost() < self.argon2_params.m_cost()
+                        || params.t_cost() < self.argon2_params.t_cost()
+                        || params.p_cost() < self.argon2_params.p_cost()
+                });
+
+            return Ok(Verification { valid, needs_rehash });
+        }
+
+        let valid = match bcrypt::verify(password, hash) {
+            Ok(valid) => valid,
+            Err(e) => return self.reject_malformed(password, e),
+        };
+
+        // bcrypt hashes look like $2b$12$..., the cost being the second field
+        let cost: Option<u32> = hash.split('$').nth(2).and_then(|cost| cost.parse().ok());
+        let needs_rehash = self.algorithm != PasswordAlgorithm::Bcrypt
+            || cost.map_or(true, |cost| cost < self.bcrypt_cost);
+
+        Ok(Verification { valid, needs_rehash })
+    }
+
+    /// A stored hash that cannot be verified fails like a wrong password,
+    /// after taking as long as one
+    fn reject_malformed(&self, password: &str, error: impl Display) -> Result<Verification> {
+        tracing::warn!("Invalid password hash: {}", error);
+        self.verify_blocking(password, &self.dummy_hash)?;
+
+        Ok(Verification { valid: false, needs_rehash: false })
+    }
+
+    pub async fn hash(self: &Arc<Self>, password: &str) -> Result<String> {
+        let hasher = self.clone();
+        let password = password.to_string();
+
+        tokio::task::spawn_blocking(move || hasher.hash_blocking(&password))
+            .await
+            .map_err(|e| AppError::Internal(format!("Password hashing task failed: {}", e)))?
+    }
+
+    pub async fn verify(self: &Arc<Self>, password: &str, hash: &str) -> Result<Verification> {
+        let hasher = self.clone();
+        let password = password.to_string();
+        let hash = hash.to_string();
+
+        tokio::task::spawn_blocking(move || hasher.verify_blocking(&password, &hash))
+            .await
+            .map_err(|e| AppError::Internal(format!("Password verification task failed: {}", e)))?
+    }
+
+    /// Spends the time a real verification takes and always fails
+    pub async fn verify_dummy(self: &Arc<Self>, password: &str) -> Result<Verification> {
+        let dummy_hash = self.dummy_hash.clone();
+        let verification = self.verify(password, &dummy_hash).await?;
+
+        Ok(Verification { valid: false, ..verification })
+    }
+}
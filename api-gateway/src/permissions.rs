@@ -0,0 +1,462 @@
+use async_graphql::{Context, ErrorExtensions, Guard};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    database::Database,
+    error::{AppError, Result},
+    middleware::auth::AuthContext,
+    models::User,
+};
+
+pub const STREAMS_READ: &str = "streams:read";
+pub const STREAMS_WRITE: &str = "streams:write";
+pub const STREAMS_SHARE: &str = "streams:share";
+pub const STREAMS_MANAGE: &str = "streams:manage";
+pub const RESULTS_READ: &str = "results:read";
+pub const ALERTS_READ: &str = "alerts:read";
+pub const ALERTS_ACK: &str = "alerts:ack";
+pub const MODELS_MANAGE: &str = "models:manage";
+pub const USERS_MANAGE: &str = "users:manage";
+pub const ROLES_MANAGE: &str = "roles:manage";
+pub const ORG_MANAGE: &str = "org:manage";
+
+/// Every permission known to the gateway. Mirrors the `permissions` table.
+pub const ALL: &[&str] = &[
+    STREAMS_READ,
+    STREAMS_WRITE,
+    STREAMS_SHARE,
+    STREAMS_MANAGE,
+    RESULTS_READ,
+    ALERTS_READ,
+    ALERTS_ACK,
+    MODELS_MANAGE,
+    USERS_MANAGE,
+    ROLES_MANAGE,
+    ORG_MANAGE,
+];
+
+/// Permissions that apply across the whole platform. They only come from a
+/// user's own roles; every other permission comes from their membership role
+/// in the active organization.
+pub const PLATFORM: &[&str] = &[MODELS_MANAGE, USERS_MANAGE, ROLES_MANAGE];
+
+/// Permissions that can be granted on a single stream.
+pub const STREAM_SCOPED: &[&str] = &[STREAMS_READ, STREAMS_WRITE, RESULTS_READ, ALERTS_READ, ALERTS_ACK];
+
+// Held through a role, these only apply to streams the user owns
+const OWNER_ONLY: &[&str] = &[STREAMS_WRITE, STREAMS_SHARE];
+
+pub fn ensure_known(permissions: &[String]) -> Result<()> {
+    match permissions.iter().find(|p| !ALL.contains(&p.as_str())) {
+        Some(unknown) => Err(AppError::Validation(format!("Unknown permission: {}", unknown))),
+        None => Ok(()),
+    }
+}
+
+/// Permissions `user` holds while working in `organization_id`: platform
+/// permissions from their own roles, everything else from their membership.
+pub async fn user_permissions(
+    db: &Database,
+    user: &User,
+    organization_id: Option<Uuid>,
+) -> Result<HashSet<String>> {
+    let permissions: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT DISTINCT rp.permission
+        FROM role_permissions rp
+        JOIN roles r ON r.id = rp.role_id
+        WHERE (
+            (r.name = LOWER($2) OR r.id IN (SELECT role_id FROM user_roles WHERE user_id = $1))
+            AND rp.permission = ANY($4)
+        ) OR (
+            r.id IN (SELECT role_id FROM organization_members WHERE user_id = $1 AND organization_id = $3)
+            AND rp.permission <> ALL($4)
+        )
+        "#,
+    )
+    .bind(user.id)
+    .bind(format!("{:?}", user.role))
+    .bind(organization_id)
+    .bind(PLATFORM)
+    .fetch_all(db.pool())
+    .await?;
+
+    Ok(permissions.into_iter().collect())
+}
+
+/// The organization a request acts in: the requested one if the user is a
+/// member, otherwise the one they joined first.
+pub async fn resolve_organization(
+    db: &Database,
+    user_id: Uuid,
+    requested: Option<Uuid>,
+) -> Result<Option<Uuid>> {
+    let organization_id: Option<Uuid> = sqlx::query_scalar(
+        r#"
+        SELECT organization_id FROM organization_members
+        WHERE user_id = $1 AND ($2::UUID IS NULL OR organization_id = $2)
+        ORDER BY created_at
+        LIMIT 1
+        "#,
+    )
+    .bind(user_id)
+    .bind(requested)
+    .fetch_optional(db.pool())
+    .await?;
+
+    if requested.is_some() && organization_id.is_none() {
+        return Err(AppError::Authorization("Not a member of this organization".to_string()));
+    }
+
+    Ok(organization_id)
+}
+
+/// Permissions `user_id` has been granted on one stream.
+pub async fn stream_grants(db: &Database, user_id: Uuid, stream_id: Uuid) -> Result<HashSet<String>> {
+    let permissions: Vec<String> = sqlx::query_scalar(
+        "SELECT permission FROM stream_grants WHERE stream_id = $1 AND user_id = $2"
+    )
+    .bind(stream_id)
+    .bind(user_id)
+    .fetch_all(db.pool())
+    .await?;
+
+    Ok(permissions.into_iter().collect())
+}
+
+/// Whether the caller may use `permission` on a stream. Ownership matters for
+/// writes; everything else applies to every stream of the active organization.
+///
+/// This is where tenants are kept apart: streams of other organizations are
+/// reported as missing, and everything else is reached through a stream.
+pub async fn has_stream_permission(
+    db: &Database,
+    auth_context: &AuthContext,
+    stream_id: Uuid,
+    permission: &str,
+) -> Result<bool> {
+    let stream: Option<(Uuid, Option<Uuid>)> = sqlx::query_as(
+        "SELECT organization_id, created_by FROM video_streams WHERE id = $1"
+    )
+    .bind(stream_id)
+    .fetch_optional(db.pool())
+    .await?;
+
+    let (_, created_by) = stream
+        .filter(|(organization_id, _)| Some(*organization_id) == auth_context.organization_id)
+        .ok_or_else(|| AppError::NotFound("Video stream not found".to_string()))?;
+
+    let role_permissions = &auth_context.permissions;
+    if role_permissions.contains(STREAMS_MANAGE) {
+        return Ok(true);
+    }
+
+    if role_permissions.contains(permission)
+        && (!OWNER_ONLY.contains(&permission) || created_by == Some(auth_context.user.id))
+    {
+        return Ok(true);
+    }
+
+    Ok(stream_grants(db, auth_context.user.id, stream_id).await?.contains(permission))
+}
+
+/// Checks `permission` on one stream for an authenticated caller. Requests
+/// made with an API key are further limited to the key's own permissions.
+pub async fn require_stream_permission(
+    db: &Database,
+    auth_context: &AuthContext,
+    stream_id: Uuid,
+    permission: &str,
+) -> Result<()> {
+    auth_context.require_key_permission(permission)?;
+
+    if !has_stream_permission(db, auth_context, stream_id, permission).await? {
+        return Err(AppError::Authorization("Permission denied".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Checks that the caller may read `stream_id`. Shared by the GraphQL
+/// resolvers and the WebSocket service so both enforce the same rules.
+pub async fn ensure_stream_visible(db: &Database, auth_context: &AuthContext, stream_id: Uuid) -> Result<()> {
+    auth_context.require_key_permission(STREAMS_READ)?;
+
+    // Streams the caller cannot see are reported as missing so their ids do not leak
+    if !has_stream_permission(db, auth_context, stream_id, STREAMS_READ).await? {
+        return Err(AppError::NotFound("Video stream not found".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Streams of the active organization on which the caller holds `permission`,
+/// through their role or a grant. Queries spanning several streams filter on
+/// this list.
+pub async fn visible_stream_ids(db: &Database, auth_context: &AuthContext, permission: &str) -> Result<Vec<Uuid>> {
+    auth_context.require_key_permission(permission)?;
+
+    let Some(organization_id) = auth_context.organization_id else {
+        return Ok(Vec::new());
+    };
+
+    let all_streams = auth_context.permissions.contains(permission)
+        || auth_context.permissions.contains(STREAMS_MANAGE);
+
+    let stream_ids = sqlx::query_scalar(
+        r#"
+        SELECT id FROM video_streams
+        WHERE organization_id = $1
+          AND ($2 OR id IN (SELECT stream_id FROM stream_grants WHERE user_id = $3 AND permission = $4))
+        "#,
+    )
+    .bind(organization_id)
+    .bind(all_streams)
+    .bind(auth_context.user.id)
+    .bind(permission)
+    .fetch_all(db.pool())
+    .await?;
+
+    Ok(stream_ids)
+}
+
+// How long a subscription trusts its answers about roles and streams
+const STREAM_ACCESS_TTL: Duration = Duration::from_secs(30);
+
+/// Checks `permission` on the stream of each event a long-lived subscription
+/// delivers, since grants, memberships and roles can change while it is open.
+/// Answers are kept for a short while so a busy stream does not cost a query
+/// per event.
+pub struct StreamAccess {
+    db: Database,
+    permission: &'static str,
+    cache: Mutex<StreamAccessCache>,
+}
+
+struct StreamAccessCache {
+    auth_context: AuthContext,
+    refreshed_at: Instant,
+    streams: HashMap<Uuid, bool>,
+}
+
+impl StreamAccess {
+    /// API keys cannot gain permissions, so theirs is checked once here.
+    pub fn new(db: Database, auth_context: AuthContext, permission: &'static str) -> Result<Self> {
+        auth_context.require_key_permission(permission)?;
+
+        Ok(Self {
+            db,
+            permission,
+            cache: Mutex::new(StreamAccessCache {
+                auth_context,
+                refreshed_at: Instant::now(),
+                streams: HashMap::new(),
+            }),
+        })
+    }
+
+    pub async fn permits(&self, stream_id: Uuid) -> bool {
+        let mut cache = self.cache.lock().await;
+
+        if cache.refreshed_at.elapsed() >= STREAM_ACCESS_TTL {
+            let auth_context = &cache.auth_context;
+            match user_permissions(&self.db, &auth_context.user, auth_context.organization_id).await {
+                Ok(permissions) => cache.auth_context.permissions = permissions,
+                Err(e) => {
+                    tracing::warn!("Failed to refresh permissions of {}: {}", cache.auth_context.user.email, e);
+                    return false;
+                }
+            }
+            cache.refreshed_at = Instant::now();
+            cache.streams.clear();
+        }
+
+        if let Some(permitted) = cache.streams.get(&stream_id) {
+            return *permitted;
+        }
+
+        // Streams that were deleted or moved to another organization are reported as missing
+        let permitted = match has_stream_permission(&self.db, &cache.auth_context, stream_id, self.permission).await {
+            Ok(permitted) => permitted,
+            Err(AppError::NotFound(_)) => false,
+            Err(e) => {
+                tracing::warn!("Failed to check access to stream {}: {}", stream_id, e);
+                return false;
+            }
+        };
+
+        cache.streams.insert(stream_id, permitted);
+        permitted
+    }
+}
+
+/// Field guard requiring a permission, e.g.
+/// `#[graphql(guard = "PermissionGuard::new(permissions::MODELS_MANAGE)")]`.
+pub struct PermissionGuard {
+    permission: &'static str,
+}
+
+impl PermissionGuard {
+    pub fn new(permission: &'static str) -> Self {
+        Self { permission }
+    }
+}
+
+impl Guard for PermissionGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        let auth_context = ctx.data_opt::<AuthContext>()
+            .ok_or_else(|| AppError::Authentication("Authentication required".to_string()).extend())?;
+
+        auth_context.require_permission(self.permission).map_err(|e| e.extend())?;
+        Ok(())
+    }
+}
+
+/// Who may call a field. Every field of the schema declares one, e.g.
+/// `#[graphql(guard = "Access::Authenticated")]`; fields behind a
+/// `PermissionGuard` are authenticated through it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Public,
+    Authenticated,
+    /// Platform administrators, as for `admin_auth_middleware`
+    Admin,
+}
+
+impl Guard for Access {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        if *self == Access::Public {
+            return Ok(());
+        }
+
+        let auth_context = ctx.data_opt::<AuthContext>()
+            .ok_or_else(|| AppError::Authentication("Authentication required".to_string()).extend())?;
+
+        if *self == Access::Admin {
+            auth_context.require_admin().map_err(|e| e.extend())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::UserRole, test_support, AppState};
+    use sqlx::PgPool;
+
+    async fn default_organization(state: &AppState) -> Uuid {
+        sqlx::query_scalar("SELECT id FROM organizations WHERE slug = 'default'")
+            .fetch_one(state.db.pool())
+            .await
+            .unwrap()
+    }
+
+    /// Signs in a new member of the default organization with membership role `role`
+    async fn member(state: &AppState, email: &str, role: &str) -> AuthContext {
+        let user = sqlx::query_as::<_, User>(
+            "INSERT INTO users (email, password_hash, role) VALUES ($1, '', $2) RETURNING *"
+        )
+        .bind(email)
+        .bind(UserRole::User)
+        .fetch_one(state.db.pool())
+        .await
+        .unwrap();
+
+        let organization_id = default_organization(state).await;
+        sqlx::query(
+            "INSERT INTO organization_members (organization_id, user_id, role_id) SELECT $1, $2, id FROM roles WHERE name = $3"
+        )
+        .bind(organization_id)
+        .bind(user.id)
+        .bind(role)
+        .execute(state.db.pool())
+        .await
+        .unwrap();
+
+        let permissions = user_permissions(&state.db, &user, Some(organization_id)).await.unwrap();
+        AuthContext { user, claims: None, api_key: None, organization_id: Some(organization_id), permissions }
+    }
+
+    async fn create_stream(state: &AppState, created_by: Uuid) -> Uuid {
+        let stream_id = test_support::create_stream(state).await;
+        sqlx::query("UPDATE video_streams SET created_by = $2 WHERE id = $1")
+            .bind(stream_id)
+            .bind(created_by)
+            .execute(state.db.pool())
+            .await
+            .unwrap();
+        stream_id
+    }
+
+    #[sqlx::test]
+    async fn streams_of_another_organization_are_reported_missing(pool: PgPool) {
+        let state = test_support::state(pool).await;
+        let owner = member(&state, "owner@example.com", "owner").await;
+        let own_stream = create_stream(&state, owner.user.id).await;
+        let other_stream = create_stream(&state, owner.user.id).await;
+
+        sqlx::query(
+            "WITH other AS (INSERT INTO organizations (name, slug) VALUES ('Other', 'other') RETURNING id) \
+             UPDATE video_streams SET organization_id = (SELECT id FROM other) WHERE id = $1"
+        )
+        .bind(other_stream)
+        .execute(state.db.pool())
+        .await
+        .unwrap();
+
+        // Not even streams:manage reaches into another organization
+        assert!(matches!(
+            has_stream_permission(&state.db, &owner, other_stream, STREAMS_READ).await,
+            Err(AppError::NotFound(_))
+        ));
+        assert!(matches!(
+            has_stream_permission(&state.db, &owner, other_stream, STREAMS_MANAGE).await,
+            Err(AppError::NotFound(_))
+        ));
+        assert_eq!(visible_stream_ids(&state.db, &owner, STREAMS_READ).await.unwrap(), vec![own_stream]);
+    }
+
+    #[sqlx::test]
+    async fn owner_only_permissions_apply_to_the_creator(pool: PgPool) {
+        let state = test_support::state(pool).await;
+        let creator = member(&state, "creator@example.com", "user").await;
+        let colleague = member(&state, "colleague@example.com", "user").await;
+        let stream_id = create_stream(&state, creator.user.id).await;
+
+        for permission in OWNER_ONLY {
+            assert!(has_stream_permission(&state.db, &creator, stream_id, permission).await.unwrap());
+            assert!(!has_stream_permission(&state.db, &colleague, stream_id, permission).await.unwrap());
+        }
+        assert!(has_stream_permission(&state.db, &colleague, stream_id, STREAMS_READ).await.unwrap());
+    }
+
+    #[sqlx::test]
+    async fn a_stream_grant_covers_one_permission_on_one_stream(pool: PgPool) {
+        let state = test_support::state(pool).await;
+        let creator = member(&state, "creator@example.com", "user").await;
+        let viewer = member(&state, "viewer@example.com", "viewer").await;
+        let granted = create_stream(&state, creator.user.id).await;
+        let other = create_stream(&state, creator.user.id).await;
+
+        sqlx::query("INSERT INTO stream_grants (stream_id, user_id, permission, granted_by) VALUES ($1, $2, $3, $4)")
+            .bind(granted)
+            .bind(viewer.user.id)
+            .bind(ALERTS_ACK)
+            .bind(creator.user.id)
+            .execute(state.db.pool())
+            .await
+            .unwrap();
+
+        assert!(has_stream_permission(&state.db, &viewer, granted, ALERTS_ACK).await.unwrap());
+        assert!(!has_stream_permission(&state.db, &viewer, granted, STREAMS_WRITE).await.unwrap());
+        assert!(!has_stream_permission(&state.db, &viewer, other, ALERTS_ACK).await.unwrap());
+        assert_eq!(visible_stream_ids(&state.db, &viewer, ALERTS_ACK).await.unwrap(), vec![granted]);
+    }
+}
@@ -0,0 +1,324 @@
+//! Typed SQL builders on top of `sqlx::QueryBuilder`, for queries whose
+//! conditions or columns depend on the request. Column and table names are
+//! written into the SQL as given and must never come from user input; values
+//! are always bound.
+
+use sqlx::{Encode, Postgres, QueryBuilder, Type};
+
+use crate::error::{AppError, Result};
+
+/// Values a builder can bind
+pub trait Bind: for<'q> Encode<'q, Postgres> + Type<Postgres> + Clone + Send + Sync + 'static {}
+
+impl<T> Bind for T where T: for<'q> Encode<'q, Postgres> + Type<Postgres> + Clone + Send + Sync + 'static {}
+
+/// Pushes a piece of SQL with its binds. Fragments are kept rather than
+/// pushed right away so they can go into several queries.
+type Fragment = Box<dyn Fn(&mut QueryBuilder<'static, Postgres>) + Send + Sync>;
+
+/// WHERE conditions, e.g. for a page and its count
+#[derive(Default)]
+pub struct Filter {
+    conditions: Vec<Fragment>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+
+    /// Adds a condition that pushes its own SQL and binds
+    pub fn condition(mut self, condition: impl Fn(&mut QueryBuilder<'static, Postgres>) + Send + Sync + 'static) -> Self {
+        self.conditions.push(Box::new(condition));
+        self
+    }
+
+    fn compare<T: Bind>(self, column: &'static str, operator: &'static str, value: T) -> Self {
+        self.condition(move |query| {
+            query.push(column).push(operator).push_bind(value.clone());
+        })
+    }
+
+    pub fn eq<T: Bind>(self, column: &'static str, value: T) -> Self {
+        self.compare(column, " = ", value)
+    }
+
+    pub fn gte<T: Bind>(self, column: &'static str, value: T) -> Self {
+        self.compare(column, " >= ", value)
+    }
+
+    pub fn lte<T: Bind>(self, column: &'static str, value: T) -> Self {
+        self.compare(column, " <= ", value)
+    }
+
+    pub fn lt<T: Bind>(self, column: &'static str, value: T) -> Self {
+        self.compare(column, " < ", value)
+    }
+
+    /// `column` equals one of `values`
+    pub fn any<T>(self, column: &'static str, values: Vec<T>) -> Self
+    where
+        Vec<T>: Bind,
+    {
+        self.condition(move |query| {
+            query.push(column).push(" = ANY(").push_bind(values.clone()).push(")");
+        })
+    }
+
+    // The _opt variants leave the filter unchanged when no value is given
+
+    pub fn eq_opt<T: Bind>(self, column: &'static str, value: Option<T>) -> Self {
+        match value {
+            Some(value) => self.eq(column, value),
+            None => self,
+        }
+    }
+
+    pub fn gte_opt<T: Bind>(self, column: &'static str, value: Option<T>) -> Self {
+        match value {
+            Some(value) => self.gte(column, value),
+            None => self,
+        }
+    }
+
+    pub fn lte_opt<T: Bind>(self, column: &'static str, value: Option<T>) -> Self {
+        match value {
+            Some(value) => self.lte(column, value),
+            None => self,
+        }
+    }
+
+    pub fn lt_opt<T: Bind>(self, column: &'static str, value: Option<T>) -> Self {
+        match value {
+            Some(value) => self.lt(column, value),
+            None => self,
+        }
+    }
+
+    pub fn any_opt<T>(self, column: &'static str, values: Option<Vec<T>>) -> Self
+    where
+        Vec<T>: Bind,
+    {
+        match values {
+            Some(values) => self.any(column, values),
+            None => self,
+        }
+    }
+
+    /// Pushes ` WHERE ...`, or nothing when there are no conditions
+    pub fn push_where(&self, query: &mut QueryBuilder<'static, Postgres>) {
+        for (i, condition) in self.conditions.iter().enumerate() {
+            query.push(if i == 0 { " WHERE " } else { " AND " });
+            condition(query);
+        }
+    }
+}
+
+/// `SELECT * FROM table` with `filter` applied, ready for ORDER BY or LIMIT
+pub fn select(table: &'static str, filter: &Filter) -> QueryBuilder<'static, Postgres> {
+    let mut query = QueryBuilder::new(format!("SELECT * FROM {}", table));
+    filter.push_where(&mut query);
+    query
+}
+
+pub fn count(table: &'static str, filter: &Filter) -> QueryBuilder<'static, Postgres> {
+    let mut query = QueryBuilder::new(format!("SELECT COUNT(*) FROM {}", table));
+    filter.push_where(&mut query);
+    query
+}
+
+/// Partial update: only the columns that were given are set
+pub struct Update {
+    table: &'static str,
+    assignments: Vec<Fragment>,
+}
+
+impl Update {
+    pub fn new(table: &'static str) -> Self {
+        Self {
+            table,
+            assignments: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assignments.is_empty()
+    }
+
+    pub fn set<T: Bind>(mut self, column: &'static str, value: T) -> Self {
+        self.assignments.push(Box::new(move |query| {
+            query.push(column).push(" = ").push_bind(value.clone());
+        }));
+        self
+    }
+
+    /// Leaves the column unchanged when no value is given
+    pub fn set_opt<T: Bind>(self, column: &'static str, value: Option<T>) -> Self {
+        match value {
+            Some(value) => self.set(column, value),
+            None => self,
+        }
+    }
+
+    /// Sets `column` to a SQL expression such as `NOW()`
+    pub fn set_sql(mut self, column: &'static str, expression: &'static str) -> Self {
+        self.assignments.push(Box::new(move |query| {
+            query.push(column).push(" = ").push(expression);
+        }));
+        self
+    }
+
+    /// `UPDATE ... SET ... WHERE ... RETURNING *`. The filter picks the rows
+    /// and must not be empty, so a missing condition cannot update the whole
+    /// table.
+    pub fn build(&self, filter: &Filter) -> Result<QueryBuilder<'static, Postgres>> {
+        if filter.is_empty() {
+            return Err(AppError::Internal(format!("Refusing to update every row of {}", self.table)));
+        }
+        if self.is_empty() {
+            return Err(AppError::Internal(format!("Update of {} sets no columns", self.table)));
+        }
+
+        let mut query = QueryBuilder::new(format!("UPDATE {} SET ", self.table));
+        for (i, assignment) in self.assignments.iter().enumerate() {
+            if i > 0 {
+                query.push(", ");
+            }
+            assignment(&mut query);
+        }
+        filter.push_where(&mut query);
+        query.push(" RETURNING *");
+        Ok(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use sqlx::{FromRow, PgPool};
+
+    #[derive(Debug, FromRow)]
+    struct Item {
+        id: i32,
+        name: String,
+        kind: String,
+        score: i32,
+        updated_at: Option<DateTime<Utc>>,
+    }
+
+    async fn items(pool: &PgPool) {
+        sqlx::query(
+            "CREATE TABLE items (id INT PRIMARY KEY, name TEXT NOT NULL, kind TEXT NOT NULL, score INT NOT NULL, updated_at TIMESTAMPTZ)"
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+
+        sqlx::query("INSERT INTO items (id, name, kind, score) VALUES (1, 'a', 'x', 10), (2, 'b', 'x', 20), (3, 'c', 'y', 30)")
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn ids(pool: &PgPool, filter: &Filter) -> Vec<i32> {
+        let mut query = select("items", filter);
+        query.push(" ORDER BY id");
+        query.build_query_as::<Item>()
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|item| item.id)
+            .collect()
+    }
+
+    async fn total(pool: &PgPool, filter: &Filter) -> i64 {
+        count("items", filter).build_query_scalar().fetch_one(pool).await.unwrap()
+    }
+
+    #[sqlx::test]
+    async fn an_empty_filter_selects_and_counts_every_row(pool: PgPool) {
+        items(&pool).await;
+
+        assert_eq!(ids(&pool, &Filter::new()).await, vec![1, 2, 3]);
+        assert_eq!(total(&pool, &Filter::new()).await, 3);
+    }
+
+    #[sqlx::test]
+    async fn conditions_are_combined(pool: PgPool) {
+        items(&pool).await;
+
+        let filter = Filter::new().eq("kind", "x".to_string()).gte("score", 15).lte("score", 30).lt("id", 3);
+
+        assert_eq!(ids(&pool, &filter).await, vec![2]);
+        assert_eq!(total(&pool, &filter).await, 1);
+    }
+
+    #[sqlx::test]
+    async fn any_matches_the_listed_values(pool: PgPool) {
+        items(&pool).await;
+
+        assert_eq!(ids(&pool, &Filter::new().any("id", vec![1, 3, 4])).await, vec![1, 3]);
+        assert_eq!(total(&pool, &Filter::new().any("id", Vec::<i32>::new())).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn opt_variants_only_filter_on_given_values(pool: PgPool) {
+        items(&pool).await;
+
+        let unset = Filter::new()
+            .eq_opt("kind", None::<String>)
+            .gte_opt("score", None::<i32>)
+            .lte_opt("score", None::<i32>)
+            .lt_opt("id", None::<i32>)
+            .any_opt("id", None::<Vec<i32>>);
+        assert!(unset.is_empty());
+        assert_eq!(total(&pool, &unset).await, 3);
+
+        let set = Filter::new()
+            .eq_opt("kind", Some("x".to_string()))
+            .gte_opt("score", Some(10))
+            .lte_opt("score", Some(10))
+            .lt_opt("id", Some(2))
+            .any_opt("id", Some(vec![1, 2]));
+        assert_eq!(ids(&pool, &set).await, vec![1]);
+    }
+
+    #[sqlx::test]
+    async fn update_sets_the_given_columns_of_the_filtered_rows(pool: PgPool) {
+        items(&pool).await;
+
+        let updated = Update::new("items")
+            .set("score", 99)
+            .set_opt("name", None::<String>)
+            .set_sql("updated_at", "NOW()")
+            .build(&Filter::new().eq("kind", "x".to_string()))
+            .unwrap()
+            .build_query_as::<Item>()
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+
+        let mut names: Vec<&str> = updated.iter().map(|item| item.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "b"]);
+        assert!(updated.iter().all(|item| item.kind == "x" && item.score == 99 && item.updated_at.is_some()));
+
+        let untouched: i32 = sqlx::query_scalar("SELECT score FROM items WHERE id = 3")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(untouched, 30);
+    }
+
+    #[test]
+    fn update_refuses_an_empty_filter_or_no_columns() {
+        assert!(Update::new("items").set("score", 1).build(&Filter::new()).is_err());
+        assert!(Update::new("items").set_opt("score", None::<i32>).build(&Filter::new().eq("id", 1)).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use chrono::{DateTime, Utc};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+const REDIS_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Access tokens revoked before they expire, keyed on their `jti` claim and
+/// shared across gateway replicas through Redis. Entries expire together with
+/// the token they deny.
+///
+/// This is only a cache of revocations: one made while Redis was unreachable
+/// is missing, so an absent entry proves nothing and the sessions table has
+/// the final say.
+#[derive(Clone, Default)]
+pub struct TokenDenylist {
+    redis: Arc<RwLock<Option<ConnectionManager>>>,
+}
+
+impl TokenDenylist {
+    /// Connects in the background and keeps retrying; until then every
+    /// lookup reports Redis as unavailable.
+    pub fn connect(redis_url: String) -> Self {
+        let denylist = Self::default();
+        let redis = denylist.redis.clone();
+
+        tokio::spawn(async move {
+            let client = match redis::Client::open(redis_url) {
+                Ok(client) => client,
+                Err(e) => {
+                    tracing::error!("Invalid Redis URL, token denylist disabled: {}", e);
+                    return;
+                }
+            };
+
+            loop {
+                match ConnectionManager::new(client.clone()).await {
+                    Ok(connection) => {
+                        *redis.write().await = Some(connection);
+                        tracing::info!("Token denylist connected to Redis");
+                        return;
+                    }
+                    Err(e) => {
+                        tracing::warn!("Token denylist cannot reach Redis: {}", e);
+                        tokio::time::sleep(REDIS_RECONNECT_INTERVAL).await;
+                    }
+                }
+            }
+        });
+
+        denylist
+    }
+
+    fn key(jti: Uuid) -> String {
+        format!("denylist:jti:{}", jti)
+    }
+
+    /// Denies `jti` until `expires_at`, when the token stops working anyway.
+    pub async fn deny(&self, jti: Uuid, expires_at: DateTime<Utc>) {
+        let ttl = (expires_at - Utc::now()).num_seconds();
+        if ttl <= 0 {
+            return;
+        }
+
+        let Some(mut connection) = self.redis.read().await.clone() else {
+            tracing::warn!("Token denylist unavailable, {} is only revoked in the database", jti);
+            return;
+        };
+
+        if let Err(e) = connection.set_ex::<_, _, ()>(Self::key(jti), 1, ttl as u64).await {
+            tracing::warn!("Failed to denylist {}: {}", jti, e);
+        }
+    }
+
+    /// Whether Redis knows `jti` is denied; `false` when it cannot answer.
+    pub async fn contains(&self, jti: Uuid) -> bool {
+        let Some(mut connection) = self.redis.read().await.clone() else {
+            return false;
+        };
+
+        match connection.exists(Self::key(jti)).await {
+            Ok(denied) => denied,
+            Err(e) => {
+                tracing::warn!("Token denylist lookup failed: {}", e);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use sqlx::postgres::PgListener;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::{
+    error::Result,
+    models::{Alert, AnalyticsEvent, InferenceResult, VideoStream},
+    AppState,
+};
+
+// Channel the `alert_changed` trigger notifies with the id of the alert
+const ALERT_CHANNEL: &str = "alert_changed";
+
+const LISTENER_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Live events published by the gateway and consumed by GraphQL subscriptions
+/// and WebSocket sessions.
+#[derive(Debug, Clone)]
+pub enum LiveEvent {
+    InferenceResult(InferenceResult),
+    AnalyticsEvent(AnalyticsEvent),
+    Alert(Alert),
+    StreamStatusChanged(VideoStream),
+}
+
+/// In-process fan-out of live events. Slow subscribers that fall more than
+/// `capacity` events behind skip the missed events instead of blocking publishers.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<LiveEvent>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self { tx }
+    }
+
+    pub fn publish(&self, event: LiveEvent) {
+        // Sending only fails when nobody is subscribed, which is fine
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveEvent> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+/// Publishes alerts as they are raised and as their status changes. Other
+/// services insert alerts straight into the database, so a trigger announces
+/// them and each replica relays them to its own subscribers.
+pub fn spawn_alert_listener(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = relay_alerts(&state).await {
+                tracing::warn!("Alert listener failed, reconnecting: {}", e);
+            }
+            tokio::time::sleep(LISTENER_RETRY_INTERVAL).await;
+        }
+    });
+}
+
+async fn relay_alerts(state: &AppState) -> Result<()> {
+    let mut listener = PgListener::connect_with(state.db.pool()).await?;
+    listener.listen(ALERT_CHANNEL).await?;
+
+    loop {
+        let notification = listener.recv().await?;
+        let Ok(alert_id) = Uuid::parse_str(notification.payload()) else {
+            tracing::warn!("Ignoring alert notification with payload {:?}", notification.payload());
+            continue;
+        };
+
+        let alert = sqlx::query_as::<_, Alert>("SELECT * FROM alerts WHERE id = $1")
+            .bind(alert_id)
+            .fetch_optional(state.db.pool())
+            .await?;
+
+        if let Some(alert) = alert {
+            state.events.publish(LiveEvent::Alert(alert));
+        }
+    }
+}
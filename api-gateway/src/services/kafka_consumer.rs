@@ -0,0 +1,679 @@
+use chrono::{DateTime, Utc};
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    message::{BorrowedMessage, Header, Message, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    Offset, TopicPartitionList,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{Postgres, QueryBuilder};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    path::PathBuf,
+    time::Duration,
+};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::{
+    error::{AppError, Result},
+    models::{AnalyticsEvent, EventSeverity, InferenceResult},
+    services::events::LiveEvent,
+    AppState,
+};
+
+// How long to keep filling a batch after its first message arrives
+const BATCH_LINGER: Duration = Duration::from_millis(100);
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+// Keeps multi-row inserts well below Postgres' bind parameter limit
+const INSERT_CHUNK_ROWS: usize = 1000;
+
+/// A message read from the broker, detached from the client that read it.
+#[derive(Debug, Clone)]
+pub struct SourceMessage {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl SourceMessage {
+    fn from_kafka(message: &BorrowedMessage<'_>) -> Self {
+        Self {
+            topic: message.topic().to_string(),
+            partition: message.partition(),
+            offset: message.offset(),
+            key: message.key().map(<[u8]>::to_vec),
+            payload: message.payload().map(<[u8]>::to_vec).unwrap_or_default(),
+        }
+    }
+
+    /// Stable id derived from the message position, so redelivered messages
+    /// that carry no id of their own are deduplicated on insert.
+    fn derived_id(&self) -> Uuid {
+        let position = format!("{}/{}/{}", self.topic, self.partition, self.offset);
+        Uuid::new_v5(&Uuid::NAMESPACE_OID, position.as_bytes())
+    }
+}
+
+/// Where the ingest consumer reads from. Implemented by Kafka and by a
+/// file-backed stand-in used for local replay and tests.
+pub trait MessageSource: Send {
+    /// Next batch of up to `max` messages, or `None` once the source is exhausted.
+    fn next_batch(&mut self, max: usize) -> impl Future<Output = Result<Option<Vec<SourceMessage>>>> + Send;
+
+    /// Marks every message in the batch as processed.
+    fn commit(&mut self, batch: &[SourceMessage]) -> impl Future<Output = Result<()>> + Send;
+
+    /// Parks a message that can never be processed so it does not block its partition.
+    fn dead_letter(&mut self, message: &SourceMessage, reason: &str) -> impl Future<Output = Result<()>> + Send;
+}
+
+pub struct KafkaSource {
+    consumer: StreamConsumer,
+    producer: FutureProducer,
+    dead_letter_topic: String,
+}
+
+impl KafkaSource {
+    pub fn new(state: &AppState) -> Result<Self> {
+        let config = &state.config.kafka;
+
+        // Offsets are committed manually once a batch has been written
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &state.config.kafka_brokers)
+            .set("group.id", &config.group_id)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()?;
+
+        consumer.subscribe(&[
+            config.inference_results_topic.as_str(),
+            config.analytics_events_topic.as_str(),
+        ])?;
+
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", &state.config.kafka_brokers)
+            .set("message.timeout.ms", "5000")
+            .create()?;
+
+        Ok(Self {
+            consumer,
+            producer,
+            dead_letter_topic: config.dead_letter_topic.clone(),
+        })
+    }
+}
+
+impl MessageSource for KafkaSource {
+    async fn next_batch(&mut self, max: usize) -> Result<Option<Vec<SourceMessage>>> {
+        // Wait for the first message, then gather whatever else arrives shortly after
+        let first = self.consumer.recv().await?;
+        let mut batch = vec![SourceMessage::from_kafka(&first)];
+
+        let deadline = tokio::time::Instant::now() + BATCH_LINGER;
+        while batch.len() < max {
+            match tokio::time::timeout_at(deadline, self.consumer.recv()).await {
+                Ok(Ok(message)) => batch.push(SourceMessage::from_kafka(&message)),
+                Ok(Err(e)) => {
+                    // Keep what was already received, it is not redelivered until a rebalance
+                    tracing::warn!("Kafka receive error while filling batch: {}", e);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+
+        Ok(Some(batch))
+    }
+
+    async fn commit(&mut self, batch: &[SourceMessage]) -> Result<()> {
+        let mut next_offsets: HashMap<(&str, i32), i64> = HashMap::new();
+        for message in batch {
+            let next = next_offsets
+                .entry((message.topic.as_str(), message.partition))
+                .or_insert(message.offset + 1);
+            *next = (*next).max(message.offset + 1);
+        }
+
+        let mut offsets = TopicPartitionList::new();
+        for ((topic, partition), offset) in next_offsets {
+            offsets.add_partition_offset(topic, partition, Offset::Offset(offset))?;
+        }
+
+        self.consumer.commit(&offsets, CommitMode::Async)?;
+        Ok(())
+    }
+
+    async fn dead_letter(&mut self, message: &SourceMessage, reason: &str) -> Result<()> {
+        let headers = OwnedHeaders::new()
+            .insert(Header { key: "source-topic", value: Some(message.topic.as_str()) })
+            .insert(Header { key: "error", value: Some(reason) });
+
+        let mut record = FutureRecord::<Vec<u8>, Vec<u8>>::to(&self.dead_letter_topic)
+            .payload(&message.payload)
+            .headers(headers);
+        if let Some(key) = &message.key {
+            record = record.key(key);
+        }
+
+        self.producer
+            .send(record, Duration::from_secs(5))
+            .await
+            .map_err(|(e, _)| e)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FileRecord {
+    topic: String,
+    payload: Value,
+}
+
+/// Replays messages from a JSON-lines file with one `{"topic": ..., "payload": {...}}`
+/// record per line. Dead letters are appended to `<file>.dead-letter`.
+pub struct FileSource {
+    messages: VecDeque<SourceMessage>,
+    dead_letter_path: PathBuf,
+}
+
+impl FileSource {
+    pub async fn open(path: &str) -> Result<Self> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| AppError::Config(format!("Failed to read Kafka source file {}: {}", path, e)))?;
+
+        let messages = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(offset, line)| match serde_json::from_str::<FileRecord>(line) {
+                Ok(record) => SourceMessage {
+                    topic: record.topic,
+                    partition: 0,
+                    offset: offset as i64,
+                    key: None,
+                    payload: record.payload.to_string().into_bytes(),
+                },
+                // Unreadable lines are kept so they end up in the dead-letter file
+                Err(_) => SourceMessage {
+                    topic: String::new(),
+                    partition: 0,
+                    offset: offset as i64,
+                    key: None,
+                    payload: line.as_bytes().to_vec(),
+                },
+            })
+            .collect();
+
+        Ok(Self {
+            messages,
+            dead_letter_path: PathBuf::from(format!("{}.dead-letter", path)),
+        })
+    }
+}
+
+impl MessageSource for FileSource {
+    async fn next_batch(&mut self, max: usize) -> Result<Option<Vec<SourceMessage>>> {
+        if self.messages.is_empty() {
+            return Ok(None);
+        }
+
+        let count = max.min(self.messages.len());
+        Ok(Some(self.messages.drain(..count).collect()))
+    }
+
+    async fn commit(&mut self, batch: &[SourceMessage]) -> Result<()> {
+        if let Some(last) = batch.last() {
+            tracing::debug!("Committed file source up to line {}", last.offset + 1);
+        }
+        Ok(())
+    }
+
+    async fn dead_letter(&mut self, message: &SourceMessage, reason: &str) -> Result<()> {
+        let record = json!({
+            "topic": message.topic,
+            "offset": message.offset,
+            "error": reason,
+            "payload": String::from_utf8_lossy(&message.payload),
+        });
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.dead_letter_path)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to open dead-letter file: {}", e)))?;
+
+        file.write_all(format!("{}\n", record).as_bytes())
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to write dead-letter file: {}", e)))?;
+
+        Ok(())
+    }
+}
+
+/// Detection published by the inference service.
+#[derive(Debug, Deserialize)]
+struct DetectionMessage {
+    id: Option<Uuid>,
+    stream_id: Uuid,
+    model_id: Option<Uuid>,
+    timestamp: DateTime<Utc>,
+    frame_number: i64,
+    confidence: f32,
+    bounding_box: Option<Value>,
+    detected_class: Option<String>,
+    metadata: Option<Value>,
+}
+
+/// Event published by the analytics engine.
+#[derive(Debug, Deserialize)]
+struct EventMessage {
+    id: Option<Uuid>,
+    stream_id: Uuid,
+    event_type: String,
+    event_data: Value,
+    timestamp: DateTime<Utc>,
+    severity: Option<EventSeverity>,
+}
+
+enum Decoded {
+    InferenceResult(InferenceResult),
+    AnalyticsEvent(AnalyticsEvent),
+}
+
+/// Reads detections and analytics events, writes them to the hypertables and
+/// fans them out to live subscribers. Offsets are committed only after a batch
+/// has been written, so delivery is at-least-once; inserts ignore duplicates.
+/// Messages that cannot be decoded, and rows the database rejects for good,
+/// are dead-lettered so they never hold up their partition.
+pub struct IngestConsumer<S> {
+    source: S,
+    state: AppState,
+}
+
+impl<S: MessageSource> IngestConsumer<S> {
+    pub fn new(source: S, state: AppState) -> Self {
+        Self { source, state }
+    }
+
+    pub async fn run(mut self) {
+        let batch_size = self.state.config.kafka.batch_size;
+
+        loop {
+            let batch = match self.source.next_batch(batch_size).await {
+                Ok(Some(batch)) => batch,
+                Ok(None) => {
+                    tracing::info!("Ingest source exhausted, stopping consumer");
+                    return;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to read from ingest source: {}", e);
+                    tokio::time::sleep(INITIAL_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            self.process_batch(batch).await;
+        }
+    }
+
+    async fn process_batch(&mut self, batch: Vec<SourceMessage>) {
+        let mut rows = Vec::new();
+
+        for message in &batch {
+            match self.decode(message) {
+                Ok(row) => rows.push((message, row)),
+                Err(reason) => self.dead_letter(message, &reason).await,
+            }
+        }
+
+        let written = self.write(rows).await;
+
+        tracing::debug!("Ingested {} rows from a batch of {} messages", written.len(), batch.len());
+
+        fan_out(&self.state, written);
+
+        if let Err(e) = self.source.commit(&batch).await {
+            tracing::warn!("Failed to commit ingest offsets, batch may be redelivered: {}", e);
+        }
+    }
+
+    /// Writes the rows in one transaction and returns those that were stored.
+    /// Transient failures are retried until they clear. When the database
+    /// rejects the batch for good, one bad row fails the whole transaction, so
+    /// the rows are written one at a time and only the rejected ones are
+    /// dead-lettered.
+    async fn write(&mut self, rows: Vec<(&SourceMessage, Decoded)>) -> Vec<Decoded> {
+        let mut delay = INITIAL_RETRY_DELAY;
+        loop {
+            let batch: Vec<&Decoded> = rows.iter().map(|(_, row)| row).collect();
+            match write_rows(&self.state, &batch).await {
+                Ok(()) => return rows.into_iter().map(|(_, row)| row).collect(),
+                Err(e) if is_permanent(&e) => {
+                    tracing::warn!("Ingest batch rejected, writing its rows one at a time: {}", e);
+                    break;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to write ingest batch, retrying: {}", e);
+                    back_off(&mut delay).await;
+                }
+            }
+        }
+
+        let mut written = Vec::with_capacity(rows.len());
+        for (message, row) in rows {
+            let mut delay = INITIAL_RETRY_DELAY;
+            loop {
+                match write_rows(&self.state, &[&row]).await {
+                    Ok(()) => {
+                        written.push(row);
+                        break;
+                    }
+                    Err(e) if is_permanent(&e) => {
+                        self.dead_letter(message, &format!("Rejected by the database: {}", e)).await;
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to write ingest row, retrying: {}", e);
+                        back_off(&mut delay).await;
+                    }
+                }
+            }
+        }
+
+        written
+    }
+
+    /// Parks a message, retrying until it is; skipping a message that could
+    /// not be parked would lose it.
+    async fn dead_letter(&mut self, message: &SourceMessage, reason: &str) {
+        tracing::warn!(
+            "Dead-lettering message {}/{}/{}: {}",
+            message.topic, message.partition, message.offset, reason
+        );
+
+        let mut delay = INITIAL_RETRY_DELAY;
+        while let Err(e) = self.source.dead_letter(message, reason).await {
+            tracing::error!("Failed to dead-letter message: {}", e);
+            back_off(&mut delay).await;
+        }
+    }
+
+    fn decode(&self, message: &SourceMessage) -> std::result::Result<Decoded, String> {
+        let config = &self.state.config.kafka;
+
+        if message.topic == config.inference_results_topic {
+            let detection: DetectionMessage = serde_json::from_slice(&message.payload)
+                .map_err(|e| format!("Invalid detection payload: {}", e))?;
+
+            if !(0.0..=1.0).contains(&detection.confidence) {
+                return Err(format!("Confidence {} out of range", detection.confidence));
+            }
+
+            Ok(Decoded::InferenceResult(InferenceResult {
+                id: detection.id.unwrap_or_else(|| message.derived_id()),
+                stream_id: detection.stream_id,
+                model_id: detection.model_id,
+                timestamp: detection.timestamp,
+                frame_number: detection.frame_number,
+                confidence: detection.confidence,
+                bounding_box: detection.bounding_box,
+                detected_class: detection.detected_class,
+                metadata: detection.metadata,
+                created_at: Utc::now(),
+            }))
+        } else if message.topic == config.analytics_events_topic {
+            let event: EventMessage = serde_json::from_slice(&message.payload)
+                .map_err(|e| format!("Invalid analytics event payload: {}", e))?;
+
+            Ok(Decoded::AnalyticsEvent(AnalyticsEvent {
+                id: event.id.unwrap_or_else(|| message.derived_id()),
+                stream_id: event.stream_id,
+                event_type: event.event_type,
+                event_data: event.event_data,
+                timestamp: event.timestamp,
+                severity: event.severity.unwrap_or(EventSeverity::Info),
+                processed: false,
+                created_at: Utc::now(),
+            }))
+        } else {
+            Err(format!("Unexpected topic '{}'", message.topic))
+        }
+    }
+}
+
+// Sleeps for `delay` and doubles it, up to a limit, for the next attempt
+async fn back_off(delay: &mut Duration) {
+    tokio::time::sleep(*delay).await;
+    *delay = (*delay * 2).min(MAX_RETRY_DELAY);
+}
+
+/// Whether the database rejected a write for good. Integrity constraint
+/// violations (SQLSTATE class 23, e.g. a detection for a stream that does not
+/// exist) and data exceptions (class 22, e.g. a class name too long for its
+/// column) fail the same way on every attempt, unlike lost connections or
+/// timeouts.
+fn is_permanent(error: &AppError) -> bool {
+    match error {
+        AppError::Database(sqlx::Error::Database(e)) => {
+            e.code().is_some_and(|code| code.starts_with("23") || code.starts_with("22"))
+        }
+        _ => false,
+    }
+}
+
+async fn write_rows(state: &AppState, rows: &[&Decoded]) -> Result<()> {
+    let results: Vec<&InferenceResult> = rows
+        .iter()
+        .filter_map(|row| match row {
+            Decoded::InferenceResult(result) => Some(result),
+            Decoded::AnalyticsEvent(_) => None,
+        })
+        .collect();
+    let events: Vec<&AnalyticsEvent> = rows
+        .iter()
+        .filter_map(|row| match row {
+            Decoded::AnalyticsEvent(event) => Some(event),
+            Decoded::InferenceResult(_) => None,
+        })
+        .collect();
+
+    let mut tx = state.db.pool().begin().await?;
+
+    for chunk in results.chunks(INSERT_CHUNK_ROWS) {
+        let mut query = QueryBuilder::<Postgres>::new(
+            "INSERT INTO inference_results (id, stream_id, model_id, timestamp, frame_number, confidence, bounding_box, detected_class, metadata, created_at) ",
+        );
+        query.push_values(chunk, |mut row, result| {
+            row.push_bind(result.id)
+                .push_bind(result.stream_id)
+                .push_bind(result.model_id)
+                .push_bind(result.timestamp)
+                .push_bind(result.frame_number)
+                .push_bind(result.confidence)
+                .push_bind(result.bounding_box.clone())
+                .push_bind(result.detected_class.clone())
+                .push_bind(result.metadata.clone())
+                .push_bind(result.created_at);
+        });
+        // The key is (id, timestamp), both taken from the message, so a
+        // redelivery still conflicts with the row written the first time
+        query.push(" ON CONFLICT DO NOTHING");
+        query.build().execute(&mut *tx).await?;
+    }
+
+    for chunk in events.chunks(INSERT_CHUNK_ROWS) {
+        let mut query = QueryBuilder::<Postgres>::new(
+            "INSERT INTO analytics_events (id, stream_id, event_type, event_data, timestamp, severity, processed, created_at) ",
+        );
+        query.push_values(chunk, |mut row, event| {
+            row.push_bind(event.id)
+                .push_bind(event.stream_id)
+                .push_bind(event.event_type.clone())
+                .push_bind(event.event_data.clone())
+                .push_bind(event.timestamp)
+                .push_bind(event.severity)
+                .push_bind(event.processed)
+                .push_bind(event.created_at);
+        });
+        query.push(" ON CONFLICT DO NOTHING");
+        query.build().execute(&mut *tx).await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+// Live subscribers, GraphQL and WebSocket alike, read from the event bus
+fn fan_out(state: &AppState, rows: Vec<Decoded>) {
+    for row in rows {
+        match row {
+            Decoded::InferenceResult(result) => state.events.publish(LiveEvent::InferenceResult(result)),
+            Decoded::AnalyticsEvent(event) => state.events.publish(LiveEvent::AnalyticsEvent(event)),
+        }
+    }
+}
+
+/// Starts the ingest consumer in the background, reading from the configured
+/// source file when one is set and from Kafka otherwise.
+pub async fn spawn(state: AppState) -> Result<()> {
+    match state.config.kafka.source_file.clone() {
+        Some(path) => {
+            let source = FileSource::open(&path).await?;
+            tracing::info!("Ingest consumer replaying {}", path);
+            tokio::spawn(IngestConsumer::new(source, state).run());
+        }
+        None => {
+            let source = KafkaSource::new(&state)?;
+            tracing::info!(
+                "Ingest consumer subscribed to {} and {}",
+                state.config.kafka.inference_results_topic,
+                state.config.kafka.analytics_events_topic
+            );
+            tokio::spawn(IngestConsumer::new(source, state).run());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use sqlx::PgPool;
+
+    fn detection(state: &AppState, payload: Value) -> String {
+        json!({ "topic": state.config.kafka.inference_results_topic, "payload": payload }).to_string()
+    }
+
+    fn analytics_event(state: &AppState, payload: Value) -> String {
+        json!({ "topic": state.config.kafka.analytics_events_topic, "payload": payload }).to_string()
+    }
+
+    /// Replays `lines` through the file source to the end and returns what
+    /// was dead-lettered.
+    async fn ingest(state: &AppState, lines: &[String]) -> Vec<Value> {
+        let path = std::env::temp_dir().join(format!("ingest-{}.jsonl", Uuid::new_v4()));
+        let path = path.to_str().unwrap().to_string();
+        tokio::fs::write(&path, lines.join("\n")).await.unwrap();
+
+        let source = FileSource::open(&path).await.unwrap();
+        IngestConsumer::new(source, state.clone()).run().await;
+
+        let dead_letter_path = format!("{}.dead-letter", path);
+        let dead_letters = tokio::fs::read_to_string(&dead_letter_path).await.unwrap_or_default();
+        let _ = tokio::fs::remove_file(&path).await;
+        let _ = tokio::fs::remove_file(&dead_letter_path).await;
+
+        dead_letters.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    async fn count(state: &AppState, table: &str) -> i64 {
+        sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table))
+            .fetch_one(state.db.pool())
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn malformed_messages_are_dead_lettered(pool: PgPool) {
+        let state = test_support::state(pool).await;
+        let stream_id = test_support::create_stream(&state).await;
+
+        let dead_letters = ingest(&state, &[
+            "not json".to_string(),
+            detection(&state, json!({ "stream_id": "not a uuid" })),
+            detection(&state, json!({
+                "stream_id": stream_id, "timestamp": Utc::now(), "frame_number": 1, "confidence": 1.5,
+            })),
+            detection(&state, json!({
+                "stream_id": stream_id, "timestamp": Utc::now(), "frame_number": 2, "confidence": 0.9,
+            })),
+        ]).await;
+
+        assert_eq!(dead_letters.len(), 3);
+        assert!(dead_letters[0]["error"].as_str().unwrap().starts_with("Unexpected topic"));
+        assert!(dead_letters[1]["error"].as_str().unwrap().starts_with("Invalid detection payload"));
+        assert!(dead_letters[2]["error"].as_str().unwrap().contains("out of range"));
+        assert_eq!(count(&state, "inference_results").await, 1);
+    }
+
+    #[sqlx::test]
+    async fn redelivered_messages_are_written_once(pool: PgPool) {
+        let state = test_support::state(pool).await;
+        let stream_id = test_support::create_stream(&state).await;
+
+        let result = detection(&state, json!({
+            "id": Uuid::new_v4(), "stream_id": stream_id, "timestamp": Utc::now(), "frame_number": 1, "confidence": 0.9,
+        }));
+        let event = analytics_event(&state, json!({
+            "id": Uuid::new_v4(), "stream_id": stream_id, "event_type": "person_detected", "event_data": {}, "timestamp": Utc::now(),
+        }));
+
+        let dead_letters = ingest(&state, &[result.clone(), event.clone(), result, event]).await;
+
+        assert!(dead_letters.is_empty());
+        assert_eq!(count(&state, "inference_results").await, 1);
+        assert_eq!(count(&state, "analytics_events").await, 1);
+    }
+
+    #[sqlx::test]
+    async fn rows_for_unknown_streams_are_dead_lettered_without_blocking_the_batch(pool: PgPool) {
+        let state = test_support::state(pool).await;
+        let stream_id = test_support::create_stream(&state).await;
+
+        let dead_letters = ingest(&state, &[
+            detection(&state, json!({
+                "stream_id": stream_id, "timestamp": Utc::now(), "frame_number": 1, "confidence": 0.9,
+            })),
+            detection(&state, json!({
+                "stream_id": Uuid::new_v4(), "timestamp": Utc::now(), "frame_number": 1, "confidence": 0.9,
+            })),
+            analytics_event(&state, json!({
+                "stream_id": stream_id, "event_type": "person_detected", "event_data": {}, "timestamp": Utc::now(),
+            })),
+        ]).await;
+
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0]["offset"], 1);
+        assert!(dead_letters[0]["error"].as_str().unwrap().starts_with("Rejected by the database"));
+        assert_eq!(count(&state, "inference_results").await, 1);
+        assert_eq!(count(&state, "analytics_events").await, 1);
+    }
+
+    #[test]
+    fn only_constraint_violations_and_bad_data_are_permanent() {
+        assert!(!is_permanent(&AppError::Database(sqlx::Error::PoolTimedOut)));
+        assert!(!is_permanent(&AppError::Internal("connection reset".to_string())));
+    }
+}
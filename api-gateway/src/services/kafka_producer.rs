@@ -0,0 +1,203 @@
+use rdkafka::{
+    config::ClientConfig,
+    message::{Header, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Postgres, Transaction};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::{
+    error::Result,
+    models::{StreamStatus, VideoStream},
+    AppState,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RELAY_BATCH_SIZE: i64 = 100;
+// Arbitrary key for the advisory lock that keeps a single relay active across replicas
+const RELAY_LOCK_KEY: i64 = 0x6f7574626f78;
+
+/// Commands telling the ingest and inference services about stream lifecycle changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum StreamCommand {
+    StreamCreated { stream: VideoStream },
+    StreamStarted { stream: VideoStream },
+    StreamStopped { stream_id: Uuid },
+    StreamDeleted { stream_id: Uuid },
+}
+
+impl StreamCommand {
+    /// Command implied by an update, if the stream was started or stopped.
+    pub fn for_status_change(before: &VideoStream, after: &VideoStream) -> Option<Self> {
+        match (before.status, after.status) {
+            (StreamStatus::Active, StreamStatus::Active) => None,
+            (_, StreamStatus::Active) => Some(StreamCommand::StreamStarted { stream: after.clone() }),
+            (StreamStatus::Active, _) => Some(StreamCommand::StreamStopped { stream_id: after.id }),
+            _ => None,
+        }
+    }
+
+    fn stream_id(&self) -> Uuid {
+        match self {
+            StreamCommand::StreamCreated { stream } | StreamCommand::StreamStarted { stream } => stream.id,
+            StreamCommand::StreamStopped { stream_id } | StreamCommand::StreamDeleted { stream_id } => *stream_id,
+        }
+    }
+
+    fn event_type(&self) -> &'static str {
+        match self {
+            StreamCommand::StreamCreated { .. } => "StreamCreated",
+            StreamCommand::StreamStarted { .. } => "StreamStarted",
+            StreamCommand::StreamStopped { .. } => "StreamStopped",
+            StreamCommand::StreamDeleted { .. } => "StreamDeleted",
+        }
+    }
+}
+
+/// Records a stream command in the outbox as part of the caller's transaction,
+/// so it is published if and only if the transaction commits.
+pub async fn enqueue_stream_command(
+    tx: &mut Transaction<'_, Postgres>,
+    state: &AppState,
+    command: &StreamCommand,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO outbox_events (id, topic, aggregate_id, event_type, payload, created_at)
+        VALUES ($1, $2, $3, $4, $5, NOW())
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(&state.config.kafka.stream_commands_topic)
+    .bind(command.stream_id())
+    .bind(command.event_type())
+    .bind(serde_json::to_value(command)?)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, FromRow)]
+struct OutboxEvent {
+    id: Uuid,
+    topic: String,
+    aggregate_id: Uuid,
+    payload: serde_json::Value,
+}
+
+/// Publishes committed outbox rows to Kafka in insertion order. Delivery is
+/// at-least-once; consumers can deduplicate on the `event-id` header.
+struct OutboxRelay {
+    state: AppState,
+    producer: FutureProducer,
+}
+
+impl OutboxRelay {
+    async fn run(self) {
+        loop {
+            match self.publish_pending().await {
+                Ok(0) => {
+                    if let Err(e) = self.prune_published().await {
+                        tracing::warn!("Failed to prune outbox: {}", e);
+                    }
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+                Ok(published) => tracing::debug!("Relayed {} outbox events", published),
+                Err(e) => {
+                    tracing::error!("Outbox relay failed: {}", e);
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn publish_pending(&self) -> Result<usize> {
+        let mut tx = self.state.db.pool().begin().await?;
+
+        let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1)")
+            .bind(RELAY_LOCK_KEY)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        // Another replica is relaying
+        if !locked {
+            return Ok(0);
+        }
+
+        let events = sqlx::query_as::<_, OutboxEvent>(
+            r#"
+            SELECT id, topic, aggregate_id, payload FROM outbox_events
+            WHERE published_at IS NULL
+            ORDER BY created_at, id
+            LIMIT $1
+            "#,
+        )
+        .bind(RELAY_BATCH_SIZE)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut published = 0;
+        for event in &events {
+            let key = event.aggregate_id.to_string();
+            let payload = event.payload.to_string();
+            let event_id = event.id.to_string();
+
+            let record = FutureRecord::to(&event.topic)
+                .key(&key)
+                .payload(&payload)
+                .headers(OwnedHeaders::new().insert(Header {
+                    key: "event-id",
+                    value: Some(event_id.as_str()),
+                }));
+
+            match self.producer.send(record, Duration::from_secs(10)).await {
+                Ok(_) => {
+                    sqlx::query("UPDATE outbox_events SET published_at = NOW() WHERE id = $1")
+                        .bind(event.id)
+                        .execute(&mut *tx)
+                        .await?;
+                    published += 1;
+                }
+                Err((e, _)) => {
+                    sqlx::query(
+                        "UPDATE outbox_events SET attempts = attempts + 1, last_error = $2 WHERE id = $1",
+                    )
+                    .bind(event.id)
+                    .bind(e.to_string())
+                    .execute(&mut *tx)
+                    .await?;
+
+                    // Stop here so later commands for the same stream are not published first
+                    tracing::warn!("Failed to publish outbox event {}: {}", event.id, e);
+                    break;
+                }
+            }
+        }
+
+        tx.commit().await?;
+        Ok(published)
+    }
+
+    async fn prune_published(&self) -> Result<()> {
+        sqlx::query("DELETE FROM outbox_events WHERE published_at < NOW() - INTERVAL '7 days'")
+            .execute(self.state.db.pool())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Starts the background task that relays the outbox to Kafka.
+pub fn spawn_outbox_relay(state: AppState) -> Result<()> {
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", &state.config.kafka_brokers)
+        .set("enable.idempotence", "true")
+        .set("message.timeout.ms", "10000")
+        .create()?;
+
+    tokio::spawn(OutboxRelay { state, producer }.run());
+    Ok(())
+}
@@ -1 +1,6 @@
-pub mod websocket; 
\ No newline at end of file
+pub mod denylist;
+pub mod events;
+pub mod kafka_consumer;
+pub mod kafka_producer;
+pub mod mailer;
+pub mod websocket;
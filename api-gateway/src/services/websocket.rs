@@ -1,17 +1,156 @@
-use axum::extract::ws::{Message, WebSocket};
+use axum::{
+    extract::ws::{CloseFrame, Message, WebSocket},
+    http::HeaderMap,
+};
+use chrono::{DateTime, TimeZone, Utc};
 use futures_util::{sink::SinkExt, stream::StreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::{broadcast, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::Duration;
+use tokio::sync::{broadcast, broadcast::error::RecvError, Mutex, RwLock};
 use uuid::Uuid;
 
 use crate::{
+    auth::{get_user_from_api_key, get_user_from_token, is_revoked},
     error::AppError,
-    models::{Alert, AnalyticsEvent, InferenceResult, StreamStatus, User},
+    middleware::auth::{build_auth_context, AuthContext, ORGANIZATION_HEADER},
+    models::{Alert, AnalyticsEvent, InferenceResult, StreamStatus},
+    permissions::{self, ensure_stream_visible, require_stream_permission, StreamAccess},
+    services::events::LiveEvent,
     AppState,
 };
 
+/// Close code sent when the credential a session was opened with expires.
+pub const CLOSE_TOKEN_EXPIRED: u16 = 4001;
+
+/// Close code sent when the credential was revoked: a logout, a password
+/// reset, an ended session family or a deactivated API key.
+pub const CLOSE_CREDENTIAL_REVOKED: u16 = 4003;
+
+// How often open sessions check that their credential was not revoked
+const REVOCATION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Subprotocols that carry credentials for clients that cannot set headers on
+/// the handshake. The credential follows its marker, e.g. `["bearer", "<jwt>"]`.
+pub const AUTH_PROTOCOLS: [&str; 2] = ["bearer", "api-key"];
+
+#[derive(Debug, Deserialize)]
+pub struct WebSocketAuthParams {
+    pub token: Option<String>,
+    pub api_key: Option<String>,
+    pub organization_id: Option<Uuid>,
+}
+
+/// Who a connection was authenticated as, and when its credential expires.
+#[derive(Clone)]
+pub struct WebSocketIdentity {
+    pub auth: AuthContext,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+enum Credential {
+    Bearer(String),
+    ApiKey(String),
+}
+
+fn extract_credential(params: &WebSocketAuthParams, headers: &HeaderMap) -> Option<Credential> {
+    if let Some(token) = &params.token {
+        return Some(Credential::Bearer(token.clone()));
+    }
+
+    if let Some(key) = &params.api_key {
+        return Some(Credential::ApiKey(key.clone()));
+    }
+
+    let protocols: Vec<&str> = headers
+        .get_all("sec-websocket-protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    protocols.windows(2).find_map(|pair| match pair[0] {
+        "bearer" => Some(Credential::Bearer(pair[1].to_string())),
+        "api-key" => Some(Credential::ApiKey(pair[1].to_string())),
+        _ => None,
+    })
+}
+
+pub async fn authenticate_handshake(
+    state: &AppState,
+    params: &WebSocketAuthParams,
+    headers: &HeaderMap,
+) -> Result<WebSocketIdentity, AppError> {
+    let credential = extract_credential(params, headers)
+        .ok_or_else(|| AppError::Authentication("Missing credentials".to_string()))?;
+
+    let requested_organization = match params.organization_id {
+        Some(organization_id) => Some(organization_id),
+        None => headers
+            .get(ORGANIZATION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| Uuid::parse_str(value).map_err(|_| AppError::BadRequest("Invalid organization id".to_string())))
+            .transpose()?,
+    };
+
+    match credential {
+        Credential::Bearer(token) => {
+            let (user, claims) = get_user_from_token(&token, state).await?;
+
+            let expires_at = Utc.timestamp_opt(claims.exp, 0).single();
+
+            Ok(WebSocketIdentity {
+                auth: build_auth_context(state, user, Some(claims), None, requested_organization).await?,
+                expires_at,
+            })
+        }
+        Credential::ApiKey(key) => {
+            let (user, api_key) = get_user_from_api_key(&key, state).await?;
+
+            let expires_at = api_key.expires_at;
+
+            Ok(WebSocketIdentity {
+                auth: build_auth_context(state, user, None, Some(api_key), requested_organization).await?,
+                expires_at,
+            })
+        }
+    }
+}
+
+/// Whether the credential a session was opened with has been revoked since
+async fn is_credential_revoked(state: &AppState, auth_context: &AuthContext) -> Result<bool, AppError> {
+    if let Some(claims) = &auth_context.claims {
+        return is_revoked(state, claims.jti).await;
+    }
+
+    let Some(api_key) = &auth_context.api_key else {
+        return Ok(false);
+    };
+
+    let active: Option<bool> = sqlx::query_scalar("SELECT is_active FROM api_keys WHERE id = $1")
+        .bind(api_key.id)
+        .fetch_optional(state.db.pool())
+        .await?;
+
+    Ok(active != Some(true))
+}
+
+// Resolves once the deadline has passed; never resolves without one
+async fn sleep_until(deadline: Option<DateTime<Utc>>) {
+    match deadline {
+        Some(deadline) => {
+            let remaining = (deadline - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(remaining).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum WebSocketMessage {
@@ -39,42 +178,258 @@ pub enum WebSocketMessage {
     NewAnalyticsEvent {
         event: AnalyticsEvent,
     },
+    Subscribed {
+        stream_id: Option<Uuid>,
+        event_types: Vec<String>,
+    },
+    Unsubscribed {
+        stream_id: Option<Uuid>,
+    },
     Pong,
     Error {
         message: String,
     },
 }
 
+impl From<LiveEvent> for WebSocketMessage {
+    fn from(event: LiveEvent) -> Self {
+        match event {
+            LiveEvent::InferenceResult(result) => WebSocketMessage::NewInferenceResult { result },
+            LiveEvent::AnalyticsEvent(event) => WebSocketMessage::NewAnalyticsEvent { event },
+            LiveEvent::Alert(alert) => WebSocketMessage::NewAlert { alert },
+            LiveEvent::StreamStatusChanged(stream) => WebSocketMessage::StreamStatusUpdate {
+                stream_id: stream.id,
+                status: stream.status,
+            },
+        }
+    }
+}
+
+impl WebSocketMessage {
+    /// Stream and event type of a server -> client event, used to match it
+    /// against session subscriptions. `None` for control messages.
+    fn event_key(&self) -> Option<(Uuid, &str)> {
+        match self {
+            WebSocketMessage::StreamStatusUpdate { stream_id, .. } => Some((*stream_id, "stream_status")),
+            WebSocketMessage::NewInferenceResult { result } => Some((result.stream_id, "inference_result")),
+            WebSocketMessage::NewAlert { alert } => Some((alert.stream_id, "alert")),
+            WebSocketMessage::NewAnalyticsEvent { event } => Some((event.stream_id, "analytics_event")),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct WebSocketSession {
     pub user_id: Uuid,
-    pub subscriptions: Vec<WebSocketSubscription>,
+    /// Shared by the session's own tasks, which match every event against it
+    /// without going through the registry
+    pub subscriptions: Arc<RwLock<Vec<WebSocketSubscription>>>,
+    pub tx: broadcast::Sender<WebSocketMessage>,
+    pub connected_at: DateTime<Utc>,
+    /// Messages the client was too slow to receive and that were skipped
+    pub dropped_messages: Arc<AtomicU64>,
+}
+
+impl WebSocketSession {
+    pub fn new(user_id: Uuid, tx: broadcast::Sender<WebSocketMessage>) -> Self {
+        Self {
+            user_id,
+            subscriptions: Arc::default(),
+            tx,
+            connected_at: Utc::now(),
+            dropped_messages: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Adds a subscription, replacing any existing one for the same stream.
+    pub async fn subscribe(&self, subscription: WebSocketSubscription) {
+        let mut subscriptions = self.subscriptions.write().await;
+        subscriptions.retain(|s| s.stream_id != subscription.stream_id);
+        subscriptions.push(subscription);
+    }
+
+    /// Removes the subscription for `stream_id`; `None` removes the all-streams subscription.
+    pub async fn unsubscribe(&self, stream_id: Option<Uuid>) {
+        self.subscriptions.write().await.retain(|s| s.stream_id != stream_id);
+    }
+
+    /// Whether an outbound event should be delivered to this session.
+    pub async fn wants(&self, message: &WebSocketMessage) -> bool {
+        self.subscriptions.read().await.iter().any(|s| s.matches(message))
+    }
 }
 
+/// A session's interest in events. `stream_id: None` matches every stream
+/// and an empty `event_types` matches every event type; whether the session
+/// may see an event is checked as it is delivered.
 #[derive(Debug, Clone)]
 pub struct WebSocketSubscription {
     pub stream_id: Option<Uuid>,
     pub event_types: Vec<String>,
 }
 
-pub type WebSocketSessions = Arc<Mutex<HashMap<Uuid, broadcast::Sender<WebSocketMessage>>>>;
+impl WebSocketSubscription {
+    pub fn matches(&self, message: &WebSocketMessage) -> bool {
+        let Some((stream_id, event_type)) = message.event_key() else {
+            return false;
+        };
+
+        if self.stream_id.is_some_and(|id| id != stream_id) {
+            return false;
+        }
+
+        if self.event_types.is_empty() {
+            return true;
+        }
+
+        // Analytics events can also be selected by their own type, e.g. "person_detected"
+        let analytics_type = match message {
+            WebSocketMessage::NewAnalyticsEvent { event } => Some(event.event_type.as_str()),
+            _ => None,
+        };
+
+        self.event_types
+            .iter()
+            .any(|t| t == event_type || Some(t.as_str()) == analytics_type)
+    }
+}
+
+/// Registry of connected sessions, shared through `AppState`. Sessions register
+/// on connect and leave on disconnect; events are matched against the
+/// session's own subscriptions handle, not through the registry.
+#[derive(Clone, Default)]
+pub struct WebSocketSessions {
+    inner: Arc<Mutex<HashMap<Uuid, WebSocketSession>>>,
+}
+
+impl WebSocketSessions {
+    pub async fn register(&self, session_id: Uuid, session: WebSocketSession) {
+        self.inner.lock().await.insert(session_id, session);
+    }
+
+    pub async fn unregister(&self, session_id: Uuid) {
+        self.inner.lock().await.remove(&session_id);
+    }
+
+    /// Snapshot of every connected session, oldest first.
+    pub async fn list(&self) -> Vec<(Uuid, WebSocketSession)> {
+        let mut sessions: Vec<_> = self
+            .inner
+            .lock()
+            .await
+            .iter()
+            .map(|(id, session)| (*id, session.clone()))
+            .collect();
+        sessions.sort_by_key(|(_, session)| session.connected_at);
+        sessions
+    }
+}
 
-pub async fn handle_socket(socket: WebSocket, state: AppState) {
+pub async fn handle_socket(socket: WebSocket, state: AppState, identity: WebSocketIdentity) {
     let session_id = Uuid::new_v4();
     let (mut sender, mut receiver) = socket.split();
     
     // Create a broadcast channel for this session
     let (tx, mut rx) = broadcast::channel::<WebSocketMessage>(100);
+    let auth_context = identity.auth;
+    let session = WebSocketSession::new(auth_context.user.id, tx.clone());
+    let dropped_messages = session.dropped_messages.clone();
+    state.ws_sessions.register(session_id, session.clone()).await;
     
-    // Store the session (in a real implementation, you'd want to associate this with a user)
-    // For now, we'll use a simple approach
-    
-    tracing::info!("WebSocket session started: {}", session_id);
+    tracing::info!("WebSocket session started: {} for {}", session_id, auth_context.user.email);
+
+    // Spawn a task to handle outgoing messages, closing the socket once the
+    // credential expires or is revoked
+    let outgoing_state = state.clone();
+    let outgoing_auth = auth_context.clone();
+    let outgoing_session = session.clone();
+    let mut outgoing_task = tokio::spawn(async move {
+        let expiry = sleep_until(identity.expires_at);
+        tokio::pin!(expiry);
+
+        // Live events come from the same bus as GraphQL subscriptions. API
+        // keys get no access check for permissions they were not issued with.
+        let mut events = outgoing_state.events.subscribe();
+        let access: HashMap<&'static str, StreamAccess> = EVENT_PERMISSIONS
+            .iter()
+            .filter_map(|&permission| {
+                StreamAccess::new(outgoing_state.db.clone(), outgoing_auth.clone(), permission)
+                    .ok()
+                    .map(|access| (permission, access))
+            })
+            .collect();
+
+        let mut revocation_check = tokio::time::interval(REVOCATION_CHECK_INTERVAL);
+        revocation_check.tick().await;
+
+        loop {
+            let msg = tokio::select! {
+                _ = &mut expiry => {
+                    tracing::info!("Credential expired for WebSocket session: {}", session_id);
+                    let _ = sender
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CLOSE_TOKEN_EXPIRED,
+                            reason: "Token expired".into(),
+                        })))
+                        .await;
+                    break;
+                }
+                _ = revocation_check.tick() => {
+                    match is_credential_revoked(&outgoing_state, &outgoing_auth).await {
+                        Ok(false) => continue,
+                        Ok(true) => {
+                            tracing::info!("Credential revoked for WebSocket session: {}", session_id);
+                            let _ = sender
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: CLOSE_CREDENTIAL_REVOKED,
+                                    reason: "Credential revoked".into(),
+                                })))
+                                .await;
+                            break;
+                        }
+                        Err(e) => {
+                            tracing::warn!("Failed to check WebSocket session {} for revocation: {}", session_id, e);
+                            continue;
+                        }
+                    }
+                }
+                msg = rx.recv() => match msg {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(skipped)) => {
+                        dropped_messages.fetch_add(skipped, Ordering::Relaxed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        let msg = WebSocketMessage::from(event);
+                        let Some((stream_id, event_type)) = msg.event_key() else {
+                            continue;
+                        };
+
+                        if !outgoing_session.wants(&msg).await {
+                            continue;
+                        }
+
+                        let Some(access) = access.get(event_permission(event_type)) else {
+                            continue;
+                        };
+                        if !access.permits(stream_id).await {
+                            continue;
+                        }
+
+                        msg
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        dropped_messages.fetch_add(skipped, Ordering::Relaxed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            };
 
-    // Spawn a task to handle outgoing messages
-    let tx_clone = tx.clone();
-    let outgoing_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
             let json_msg = match serde_json::to_string(&msg) {
                 Ok(json) => json,
                 Err(e) => {
@@ -90,16 +445,17 @@ pub async fn handle_socket(socket: WebSocket, state: AppState) {
     });
 
     // Handle incoming messages
-    let incoming_task = tokio::spawn(async move {
+    let registry = state.ws_sessions.clone();
+    let mut incoming_task = tokio::spawn(async move {
         while let Some(msg) = receiver.next().await {
             match msg {
                 Ok(Message::Text(text)) => {
-                    if let Err(e) = handle_text_message(&text, &tx_clone, &state).await {
+                    if let Err(e) = handle_text_message(&text, &session, &tx, &auth_context, &state).await {
                         tracing::error!("Error handling WebSocket message: {}", e);
                         let error_msg = WebSocketMessage::Error {
                             message: "Failed to process message".to_string(),
                         };
-                        let _ = tx_clone.send(error_msg);
+                        let _ = tx.send(error_msg);
                     }
                 }
                 Ok(Message::Close(_)) => {
@@ -124,22 +480,27 @@ pub async fn handle_socket(socket: WebSocket, state: AppState) {
         }
     });
 
-    // Wait for either task to complete
+    // Wait for either task to complete, then tear down the other one
     tokio::select! {
-        _ = outgoing_task => {
+        _ = &mut outgoing_task => {
             tracing::info!("Outgoing task completed for session: {}", session_id);
         }
-        _ = incoming_task => {
+        _ = &mut incoming_task => {
             tracing::info!("Incoming task completed for session: {}", session_id);
         }
     }
+    outgoing_task.abort();
+    incoming_task.abort();
 
+    registry.unregister(session_id).await;
     tracing::info!("WebSocket session ended: {}", session_id);
 }
 
 async fn handle_text_message(
     text: &str,
+    session: &WebSocketSession,
     tx: &broadcast::Sender<WebSocketMessage>,
+    auth_context: &AuthContext,
     state: &AppState,
 ) -> Result<(), AppError> {
     let message: WebSocketMessage = serde_json::from_str(text)
@@ -147,19 +508,54 @@ async fn handle_text_message(
 
     match message {
         WebSocketMessage::Subscribe { stream_id, event_types } => {
+            if let Some(stream_id) = stream_id {
+                if let Err(e) = ensure_stream_visible(&state.db, auth_context, stream_id).await {
+                    tracing::warn!("Rejected subscription by {} to stream {}: {}", auth_context.user.email, stream_id, e);
+                    let _ = tx.send(WebSocketMessage::Error {
+                        message: format!("Cannot subscribe to stream {}", stream_id),
+                    });
+                    return Ok(());
+                }
+            }
+
+            // Every event type listed needs its permission, on the stream when
+            // one is given. Without a list the session gets whichever event
+            // types it may see; either way each event is checked on delivery.
+            let required: HashSet<&'static str> = event_types.iter().map(|t| event_permission(t)).collect();
+            for permission in required {
+                let permitted = match stream_id {
+                    Some(stream_id) => require_stream_permission(&state.db, auth_context, stream_id, permission).await,
+                    None => auth_context.require_key_permission(permission),
+                };
+
+                match permitted {
+                    Ok(()) => {}
+                    Err(AppError::Authorization(_)) => {
+                        let _ = tx.send(WebSocketMessage::Error {
+                            message: format!("Missing the '{}' permission", permission),
+                        });
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
             tracing::info!("Client subscribed to stream {:?} for events: {:?}", stream_id, event_types);
-            
-            // In a real implementation, you'd store this subscription
-            // and use it to filter which messages to send to this client
-            
-            // Send confirmation (optional)
-            // let _ = tx.send(WebSocketMessage::Pong);
+
+            session
+                .subscribe(WebSocketSubscription {
+                    stream_id,
+                    event_types: event_types.clone(),
+                })
+                .await;
+            let _ = tx.send(WebSocketMessage::Subscribed { stream_id, event_types });
         }
         
         WebSocketMessage::Unsubscribe { stream_id } => {
             tracing::info!("Client unsubscribed from stream {:?}", stream_id);
-            
-            // Remove subscription in a real implementation
+
+            session.unsubscribe(stream_id).await;
+            let _ = tx.send(WebSocketMessage::Unsubscribed { stream_id });
         }
         
         WebSocketMessage::Ping => {
@@ -174,59 +570,15 @@ async fn handle_text_message(
     Ok(())
 }
 
-// These functions would be called from other services to broadcast updates
-pub async fn broadcast_stream_status_update(
-    sessions: &WebSocketSessions,
-    stream_id: Uuid,
-    status: StreamStatus,
-) {
-    let message = WebSocketMessage::StreamStatusUpdate { stream_id, status };
-    broadcast_to_all_sessions(sessions, message).await;
-}
-
-pub async fn broadcast_new_inference_result(
-    sessions: &WebSocketSessions,
-    result: InferenceResult,
-) {
-    let message = WebSocketMessage::NewInferenceResult { result };
-    broadcast_to_all_sessions(sessions, message).await;
-}
-
-pub async fn broadcast_new_alert(
-    sessions: &WebSocketSessions,
-    alert: Alert,
-) {
-    let message = WebSocketMessage::NewAlert { alert };
-    broadcast_to_all_sessions(sessions, message).await;
-}
+// Permissions covering every event type the socket delivers
+const EVENT_PERMISSIONS: [&str; 3] = [permissions::STREAMS_READ, permissions::RESULTS_READ, permissions::ALERTS_READ];
 
-pub async fn broadcast_new_analytics_event(
-    sessions: &WebSocketSessions,
-    event: AnalyticsEvent,
-) {
-    let message = WebSocketMessage::NewAnalyticsEvent { event };
-    broadcast_to_all_sessions(sessions, message).await;
-}
-
-async fn broadcast_to_all_sessions(
-    sessions: &WebSocketSessions,
-    message: WebSocketMessage,
-) {
-    let sessions = sessions.lock().await;
-    for (session_id, tx) in sessions.iter() {
-        if let Err(e) = tx.send(message.clone()) {
-            tracing::warn!("Failed to send message to session {}: {}", session_id, e);
-        }
+// Permission needed on a stream to receive an event type from it. Anything
+// else is an analytics event type such as "person_detected".
+fn event_permission(event_type: &str) -> &'static str {
+    match event_type {
+        "stream_status" => permissions::STREAMS_READ,
+        "alert" => permissions::ALERTS_READ,
+        _ => permissions::RESULTS_READ,
     }
 }
-
-// Helper function to broadcast to specific stream subscribers
-pub async fn broadcast_to_stream_subscribers(
-    sessions: &WebSocketSessions,
-    stream_id: Uuid,
-    message: WebSocketMessage,
-) {
-    // In a real implementation, you'd filter sessions based on their subscriptions
-    // For now, we'll broadcast to all sessions
-    broadcast_to_all_sessions(sessions, message).await;
-} 
\ No newline at end of file
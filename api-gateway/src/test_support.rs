@@ -0,0 +1,69 @@
+//! Application state for tests that run against the database `#[sqlx::test]`
+//! creates for them.
+
+use sqlx::PgPool;
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    config::Config,
+    database::Database,
+    jwt::JwtKeys,
+    middleware::rate_limit::VerifiedApiKeys,
+    models::{StreamSourceType, StreamStatus},
+    oidc::Oidc,
+    password::PasswordHasher,
+    services::{denylist::TokenDenylist, events::EventBus, mailer, websocket::WebSocketSessions},
+    AppState,
+};
+
+/// State with the default configuration and no Redis, so revocation checks
+/// fall back to the database. Tests adjust the fields they care about.
+pub async fn state(pool: PgPool) -> AppState {
+    let config = Config::load().await.expect("default configuration is valid");
+
+    AppState {
+        db: Database::from_pool(pool),
+        events: EventBus::default(),
+        ws_sessions: WebSocketSessions::default(),
+        denylist: TokenDenylist::default(),
+        jwt_keys: Arc::new(JwtKeys::load(&config).expect("signing keys load")),
+        verified_api_keys: VerifiedApiKeys::default(),
+        oidc: Arc::new(Oidc::new(config.oidc.providers.clone())),
+        mock_idp: None,
+        mailer: mailer::from_config(&config.mail).expect("mailer is configured"),
+        password_hasher: Arc::new(PasswordHasher::new(&config.auth).expect("password hasher is configured")),
+        config,
+    }
+}
+
+/// Creates a stream in the organization every migrated database starts with.
+pub async fn create_stream(state: &AppState) -> uuid::Uuid {
+    sqlx::query_scalar(
+        r#"
+        INSERT INTO video_streams (organization_id, name, source_type, status)
+        SELECT id, 'Test stream', $1, $2 FROM organizations WHERE slug = 'default'
+        RETURNING id
+        "#,
+    )
+    .bind(StreamSourceType::Rtmp)
+    .bind(StreamStatus::Inactive)
+    .fetch_one(state.db.pool())
+    .await
+    .expect("stream is created")
+}
+
+/// Binds a free local port and returns its base URL with the listener, for
+/// state that needs to know its own address before `serve` runs.
+pub async fn listen() -> (String, tokio::net::TcpListener) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("local port is free");
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    (base_url, listener)
+}
+
+/// Serves the full router in the background.
+pub async fn serve(state: AppState, listener: tokio::net::TcpListener) {
+    let app = crate::create_app(state).await.expect("router builds");
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+    });
+}
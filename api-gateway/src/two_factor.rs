@@ -0,0 +1,520 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+    response::Json,
+};
+use chrono::{Duration, Utc};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::{Postgres, Transaction};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::{
+    auth::{self, SessionClient},
+    error::{AppError, Result},
+    login_throttle,
+    models::{AuthResponse, TotpEnrollment, TwoFactorChallenge, User},
+    AppState,
+};
+
+// RFC 6238 defaults, which is what authenticator apps expect
+const TOTP_DIGITS: u32 = 6;
+const TOTP_STEP_SECONDS: i64 = 30;
+
+// Accepts codes from the previous and next time step to tolerate clock drift
+const TOTP_ALLOWED_DRIFT: i64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+
+// A six digit code can be guessed; a challenge allows only a few tries, and
+// `login_throttle` locks the user out after repeated failures across challenges
+const CHALLENGE_MAX_ATTEMPTS: i32 = 5;
+
+const NONCE_LEN: usize = 12;
+
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// The URI authenticator apps read from a QR code
+pub fn otpauth_uri(secret: &str, account: &str, issuer: &str) -> String {
+    let mut url = reqwest::Url::parse("otpauth://totp/").expect("static URL is valid");
+    url.set_path(&format!("{}:{}", issuer, account));
+    url.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &TOTP_DIGITS.to_string())
+        .append_pair("period", &TOTP_STEP_SECONDS.to_string());
+    url.to_string()
+}
+
+fn totp_code(secret: &[u8], step: i64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // Dynamic truncation (RFC 4226, section 5.3)
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+/// The time step `code` is valid for, if any
+fn totp_step(secret: &str, code: &str) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: u32 = code.parse().ok()?;
+    let current = Utc::now().timestamp() / TOTP_STEP_SECONDS;
+
+    (current - TOTP_ALLOWED_DRIFT..=current + TOTP_ALLOWED_DRIFT).find(|step| totp_code(&secret, *step) == code)
+}
+
+fn cipher(state: &AppState) -> Aes256Gcm {
+    let key = state.config.auth.totp_encryption_key.as_deref().unwrap_or(&state.config.jwt_secret);
+    let key = Sha256::new().chain_update(b"totp-secret:").chain_update(key.as_bytes()).finalize();
+    Aes256Gcm::new(&key)
+}
+
+/// Encrypts a secret for storage. The user id is bound in as associated data,
+/// so a ciphertext copied to another user's row does not decrypt.
+fn encrypt_secret(state: &AppState, user_id: Uuid, secret: &str) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = cipher(state)
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: secret.as_bytes(), aad: user_id.as_bytes() })
+        .map_err(|_| AppError::Internal("Failed to encrypt TOTP secret".to_string()))?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn decrypt_secret(state: &AppState, user_id: Uuid, stored: &[u8]) -> Result<String> {
+    if stored.len() < NONCE_LEN {
+        return Err(AppError::Internal("Stored TOTP secret is truncated".to_string()));
+    }
+
+    let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+    let secret = cipher(state)
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: user_id.as_bytes() })
+        .map_err(|_| AppError::Internal("Failed to decrypt TOTP secret; was TOTP_ENCRYPTION_KEY changed?".to_string()))?;
+
+    String::from_utf8(secret).map_err(|_| AppError::Internal("Stored TOTP secret is not valid UTF-8".to_string()))
+}
+
+/// Encrypts secrets stored in plaintext before they were encrypted. Runs on
+/// startup, after the migrations.
+pub async fn encrypt_plaintext_secrets(state: &AppState) -> Result<()> {
+    let plaintext: Vec<(Uuid, String)> = sqlx::query_as(
+        "SELECT user_id, secret FROM user_totp WHERE secret IS NOT NULL"
+    )
+    .fetch_all(state.db.pool())
+    .await?;
+
+    for (user_id, secret) in &plaintext {
+        sqlx::query("UPDATE user_totp SET secret_ciphertext = $2, secret = NULL WHERE user_id = $1 AND secret = $3")
+            .bind(user_id)
+            .bind(encrypt_secret(state, *user_id, secret)?)
+            .bind(secret)
+            .execute(state.db.pool())
+            .await?;
+    }
+
+    if !plaintext.is_empty() {
+        tracing::info!("Encrypted {} stored TOTP secret(s)", plaintext.len());
+    }
+
+    Ok(())
+}
+
+fn is_totp_code(code: &str) -> bool {
+    code.len() == TOTP_DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Recovery codes carry 80 random bits, so like API keys a plain digest is
+/// enough to store them.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars().filter(|c| *c != '-').flat_map(char::to_uppercase).collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 10];
+    OsRng.fill_bytes(&mut bytes);
+    let code = BASE32_NOPAD.encode(&bytes);
+    format!("{}-{}-{}-{}", &code[0..4], &code[4..8], &code[8..12], &code[12..16])
+}
+
+/// Replaces a user's recovery codes. The plaintext codes are only returned here.
+pub async fn replace_recovery_codes(tx: &mut Transaction<'_, Postgres>, user_id: Uuid) -> Result<Vec<String>> {
+    sqlx::query("DELETE FROM user_recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    sqlx::query("INSERT INTO user_recovery_codes (user_id, code_hash) SELECT $1, UNNEST($2::TEXT[])")
+        .bind(user_id)
+        .bind(&hashes)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(codes)
+}
+
+/// Starts (or restarts) enrollment with a new secret. It has no effect on
+/// sign-in until confirmed with a first code.
+pub async fn enroll(state: &AppState, user: &User) -> Result<TotpEnrollment> {
+    let secret = generate_secret();
+
+    // A confirmed secret is only replaced by disabling two-factor first
+    let result = sqlx::query(
+        r#"
+        INSERT INTO user_totp (user_id, secret_ciphertext) VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET
+            secret_ciphertext = EXCLUDED.secret_ciphertext, created_at = NOW(), last_used_step = NULL
+        WHERE user_totp.confirmed_at IS NULL
+        "#,
+    )
+    .bind(user.id)
+    .bind(encrypt_secret(state, user.id, &secret)?)
+    .execute(state.db.pool())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::Conflict("Two-factor authentication is already enabled".to_string()));
+    }
+
+    Ok(TotpEnrollment {
+        otpauth_uri: otpauth_uri(&secret, &user.email, &state.config.auth.totp_issuer),
+        secret,
+    })
+}
+
+/// Confirms a pending enrollment with a first code and returns the recovery codes.
+pub async fn confirm(state: &AppState, user_id: Uuid, code: &str) -> Result<Vec<String>> {
+    login_throttle::check_two_factor(state, user_id).await?;
+
+    let mut tx = state.db.pool().begin().await?;
+
+    let stored: Vec<u8> = sqlx::query_scalar(
+        "SELECT secret_ciphertext FROM user_totp WHERE user_id = $1 AND confirmed_at IS NULL FOR UPDATE"
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound("No pending two-factor enrollment".to_string()))?;
+
+    let Some(step) = totp_step(&decrypt_secret(state, user_id, &stored)?, code) else {
+        login_throttle::record_two_factor_failure(state, user_id).await?;
+        return Err(AppError::Validation("Invalid authentication code".to_string()));
+    };
+
+    sqlx::query("UPDATE user_totp SET confirmed_at = NOW(), last_used_step = $2 WHERE user_id = $1")
+        .bind(user_id)
+        .bind(step)
+        .execute(&mut *tx)
+        .await?;
+
+    let recovery_codes = replace_recovery_codes(&mut tx, user_id).await?;
+    tx.commit().await?;
+
+    login_throttle::record_two_factor_success(state, user_id).await?;
+
+    Ok(recovery_codes)
+}
+
+/// Checks a code from the authenticator app, or an unused recovery code, and
+/// consumes it so it cannot be replayed. Wrong codes count towards the user's
+/// two-factor lockout.
+pub async fn verify_code(state: &AppState, user_id: Uuid, code: &str) -> Result<bool> {
+    login_throttle::check_two_factor(state, user_id).await?;
+
+    let verified = consume_code(state, user_id, code.trim()).await?;
+    if verified {
+        login_throttle::record_two_factor_success(state, user_id).await?;
+    } else {
+        login_throttle::record_two_factor_failure(state, user_id).await?;
+    }
+
+    Ok(verified)
+}
+
+async fn consume_code(state: &AppState, user_id: Uuid, code: &str) -> Result<bool> {
+    if is_totp_code(code) {
+        let stored: Option<Vec<u8>> = sqlx::query_scalar(
+            "SELECT secret_ciphertext FROM user_totp WHERE user_id = $1 AND confirmed_at IS NOT NULL"
+        )
+        .bind(user_id)
+        .fetch_optional(state.db.pool())
+        .await?;
+
+        let Some(stored) = stored else {
+            return Ok(false);
+        };
+
+        let Some(step) = totp_step(&decrypt_secret(state, user_id, &stored)?, code) else {
+            return Ok(false);
+        };
+
+        let result = sqlx::query(
+            "UPDATE user_totp SET last_used_step = $2 WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)"
+        )
+        .bind(user_id)
+        .bind(step)
+        .execute(state.db.pool())
+        .await?;
+
+        return Ok(result.rows_affected() > 0);
+    }
+
+    let result = sqlx::query(
+        "UPDATE user_recovery_codes SET used_at = NOW() WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL"
+    )
+    .bind(user_id)
+    .bind(hash_recovery_code(code))
+    .execute(state.db.pool())
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Disables two-factor authentication, unless a role of the user requires it.
+pub async fn disable(state: &AppState, user: &User) -> Result<()> {
+    if is_required(state, user).await? {
+        return Err(AppError::Authorization("Your role requires two-factor authentication".to_string()));
+    }
+
+    sqlx::query("DELETE FROM user_totp WHERE user_id = $1")
+        .bind(user.id)
+        .execute(state.db.pool())
+        .await?;
+
+    sqlx::query("DELETE FROM user_recovery_codes WHERE user_id = $1")
+        .bind(user.id)
+        .execute(state.db.pool())
+        .await?;
+
+    Ok(())
+}
+
+/// Whether the user's built-in role or an assigned role requires a second factor
+async fn is_required(state: &AppState, user: &User) -> Result<bool> {
+    let required = sqlx::query_scalar(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM roles
+            WHERE requires_two_factor
+              AND (name = LOWER($2) OR id IN (SELECT role_id FROM user_roles WHERE user_id = $1))
+        )
+        "#,
+    )
+    .bind(user.id)
+    .bind(format!("{:?}", user.role))
+    .fetch_one(state.db.pool())
+    .await?;
+
+    Ok(required)
+}
+
+/// Called by `login` once the password checks out. Users with two-factor
+/// enabled, or whose role requires it, get a challenge instead of tokens.
+pub async fn challenge(state: &AppState, user: &User) -> Result<Option<TwoFactorChallenge>> {
+    let enabled: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM user_totp WHERE user_id = $1 AND confirmed_at IS NOT NULL)"
+    )
+    .bind(user.id)
+    .fetch_one(state.db.pool())
+    .await?;
+
+    let enrollment_required = !enabled && is_required(state, user).await?;
+    if !enabled && !enrollment_required {
+        return Ok(None);
+    }
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let challenge_token = hex::encode(bytes);
+    let expires_at = Utc::now() + Duration::minutes(CHALLENGE_TTL_MINUTES);
+
+    sqlx::query(
+        "INSERT INTO two_factor_challenges (token_hash, user_id, expires_at) VALUES ($1, $2, $3)"
+    )
+    .bind(hex::encode(Sha256::digest(challenge_token.as_bytes())))
+    .bind(user.id)
+    .bind(expires_at)
+    .execute(state.db.pool())
+    .await?;
+
+    Ok(Some(TwoFactorChallenge {
+        challenge_token,
+        enrollment_required,
+        expires_at,
+    }))
+}
+
+/// Counts an attempt against a challenge and returns its user while it is
+/// still valid.
+async fn use_challenge(state: &AppState, challenge_token: &str) -> Result<User> {
+    sqlx::query("DELETE FROM two_factor_challenges WHERE expires_at <= NOW()")
+        .execute(state.db.pool())
+        .await?;
+
+    sqlx::query_as::<_, User>(
+        r#"
+        WITH challenge AS (
+            UPDATE two_factor_challenges SET attempts = attempts + 1
+            WHERE token_hash = $1 AND expires_at > NOW() AND attempts < $2
+            RETURNING user_id
+        )
+        SELECT u.* FROM users u JOIN challenge c ON c.user_id = u.id
+        "#,
+    )
+    .bind(hex::encode(Sha256::digest(challenge_token.as_bytes())))
+    .bind(CHALLENGE_MAX_ATTEMPTS)
+    .fetch_optional(state.db.pool())
+    .await?
+    .ok_or_else(|| AppError::Authentication("Invalid or expired challenge".to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChallengeInput {
+    pub challenge_token: String,
+}
+
+/// Starts enrollment for a user whose role requires two-factor but who has
+/// not set it up yet, before they can sign in.
+pub async fn enroll_with_challenge(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    Json(input): Json<ChallengeInput>,
+) -> Result<Json<TotpEnrollment>> {
+    let user = use_challenge(&state, &input.challenge_token).await?;
+
+    Ok(Json(enroll(&state, &user).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyInput {
+    pub challenge_token: String,
+    /// A code from the authenticator app or a recovery code
+    pub code: String,
+}
+
+/// Completes a login that was answered with a two-factor challenge. For a
+/// pending enrollment the code confirms it and the response carries the new
+/// recovery codes.
+pub async fn verify(
+    State((state, _)): State<(AppState, crate::graphql::Schema)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(input): Json<VerifyInput>,
+) -> Result<Json<AuthResponse>> {
+    let user = use_challenge(&state, &input.challenge_token).await?;
+
+    let pending_enrollment: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM user_totp WHERE user_id = $1 AND confirmed_at IS NULL)"
+    )
+    .bind(user.id)
+    .fetch_one(state.db.pool())
+    .await?;
+
+    let recovery_codes = if pending_enrollment {
+        Some(confirm(&state, user.id, &input.code).await?)
+    } else if verify_code(&state, user.id, &input.code).await? {
+        None
+    } else {
+        return Err(AppError::Authentication("Invalid authentication code".to_string()));
+    };
+
+    sqlx::query("DELETE FROM two_factor_challenges WHERE token_hash = $1")
+        .bind(hex::encode(Sha256::digest(input.challenge_token.as_bytes())))
+        .execute(state.db.pool())
+        .await?;
+
+    tracing::info!("User logged in with two-factor authentication: {}", user.email);
+
+    let client = SessionClient::new(&state, peer, &headers);
+    let mut response = auth::issue_tokens(state.db.pool(), &state, user, Uuid::new_v4(), &client).await?;
+    response.recovery_codes = recovery_codes;
+
+    Ok(Json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use sqlx::PgPool;
+
+    // The SHA-1 secret of RFC 6238, Appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn code_at(step: i64) -> String {
+        format!("{:06}", totp_code(RFC_SECRET, step))
+    }
+
+    #[test]
+    fn totp_code_matches_the_rfc_6238_vectors() {
+        // The RFC lists eight digits; six-digit codes are their last six
+        for (time, code) in [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ] {
+            assert_eq!(totp_code(RFC_SECRET, time / TOTP_STEP_SECONDS), code % 1_000_000, "T = {}", time);
+        }
+    }
+
+    #[test]
+    fn totp_step_accepts_codes_within_the_drift_window_only() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        let current = Utc::now().timestamp() / TOTP_STEP_SECONDS;
+
+        assert_eq!(totp_step(&secret, &code_at(current)), Some(current));
+        assert_eq!(totp_step(&secret, &code_at(current + 1)), Some(current + 1));
+        assert_eq!(totp_step(&secret, &code_at(current - 5)), None);
+        assert_eq!(totp_step(&secret, "not a code"), None);
+    }
+
+    #[sqlx::test]
+    async fn consume_code_rejects_a_second_use_of_the_same_step(pool: PgPool) {
+        let state = test_support::state(pool).await;
+        let user_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO users (email, password_hash, role) VALUES ('totp@example.com', '', $1) RETURNING id"
+        )
+        .bind(crate::models::UserRole::User)
+        .fetch_one(state.db.pool())
+        .await
+        .unwrap();
+
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        sqlx::query("INSERT INTO user_totp (user_id, secret_ciphertext, confirmed_at) VALUES ($1, $2, NOW())")
+            .bind(user_id)
+            .bind(encrypt_secret(&state, user_id, &secret).unwrap())
+            .execute(state.db.pool())
+            .await
+            .unwrap();
+
+        let code = code_at(Utc::now().timestamp() / TOTP_STEP_SECONDS);
+        assert!(consume_code(&state, user_id, &code).await.unwrap());
+        assert!(!consume_code(&state, user_id, &code).await.unwrap());
+    }
+}